@@ -4,14 +4,116 @@ pub const LOG_PATTERN: &str = "[{d(%d/%m/%Y %H:%M:%S%.6f %Z)}] {h({l})} {m}{n}";
 pub const LOG_DEBUG_PATTERN: &str =
   "[{d(%d/%m/%Y %H:%M:%S%.6f %Z)}] from {f}:{L}{n}{h({l})} {m}{n}";
 
-// BOOTSTRAP CONSTANTS
-// *TODO: move to config file
-pub const BOOTSTRAP_ADDRESS: &str = "/ip4/3.19.56.240/tcp";
-pub const BOOTNODES: &[&str] = &[
-  "12D3KooWERHN2kX14rZBbCkKnLKdDzbQfFjA8NUTvHANSmsqbacA",
-  "12D3KooWDfVV2caaXhXPsZti1wyZPtBj7kckpQ62oSCS3vxJuzyY",
-  "12D3KooWMDoD3xyLF7g4N3a2krrBhW4gBuJ9TZaJ2vUVA5rmfFXt",
-  "12D3KooWACdDu7PiwBBukn58ZSjmMKucbB1KvuYPGStzihqSkJVs",
+// NETWORK CONFIG DEFAULTS
+// Only used when no network config file is given.
+pub const DEFAULT_BOOTNODES: &[(&str, u8)] = &[
+  (
+    "/ip4/3.19.56.240/tcp/4003/p2p/12D3KooWERHN2kX14rZBbCkKnLKdDzbQfFjA8NUTvHANSmsqbacA",
+    89,
+  ),
+  (
+    "/ip4/3.19.56.240/tcp/4043/p2p/12D3KooWDfVV2caaXhXPsZti1wyZPtBj7kckpQ62oSCS3vxJuzyY",
+    134,
+  ),
+  (
+    "/ip4/3.19.56.240/tcp/4344/p2p/12D3KooWMDoD3xyLF7g4N3a2krrBhW4gBuJ9TZaJ2vUVA5rmfFXt",
+    189,
+  ),
+  (
+    "/ip4/3.19.56.240/tcp/4443/p2p/12D3KooWACdDu7PiwBBukn58ZSjmMKucbB1KvuYPGStzihqSkJVs",
+    234,
+  ),
 ];
-pub const KEY_SEEDS: &[u8] = &[89, 134, 189, 234];
-pub const PORTS: &[u16] = &[4003, 4043, 4344, 4443];
+pub const DEFAULT_PEER_LISTEN_ADDR: &str = "/ip4/0.0.0.0/tcp/0";
+#[cfg(feature = "quic")]
+pub const DEFAULT_PEER_QUIC_LISTEN_ADDR: &str = "/ip4/0.0.0.0/udp/0/quic-v1";
+pub const DEFAULT_IDENTIFY_PROTOCOL_VERSION: &str = "/TODO/0.0.1";
+pub const DEFAULT_GOSSIPSUB_HEARTBEAT_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_GOSSIPSUB_IDLE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_GOSSIPSUB_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+pub const DEFAULT_GOSSIPSUB_MAX_MESSAGES_PER_MINUTE: u32 = 60;
+pub const DEFAULT_GOSSIPSUB_MAX_CLOCK_SKEW_SECS: u64 = 60;
+pub const DEFAULT_GOSSIPSUB_MAX_MESSAGE_AGE_SECS: u64 = 10 * 60;
+pub const DEFAULT_KADEMLIA_QUERY_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_KADEMLIA_CONNECTION_IDLE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_KADEMLIA_RECORD_TTL_SECS: u64 = 120;
+pub const DEFAULT_KADEMLIA_PROVIDER_RECORD_TTL_SECS: u64 = 120;
+pub const DEFAULT_KADEMLIA_BOOTSTRAP_INTERVAL_SECS: u64 = 3 * 60;
+pub const DEFAULT_KADEMLIA_BOOTSTRAP_RETRY_SECS: u64 = 5;
+pub const DEFAULT_KADEMLIA_NICKNAME_TTL_SECS: u64 = 60 * 60;
+pub const DEFAULT_SUPERVISOR_MAX_RESTARTS: usize = 5;
+pub const DEFAULT_SUPERVISOR_BACKOFF_SECS: u64 = 1;
+pub const DEFAULT_SUPERVISOR_BACKOFF_MULTIPLIER: f64 = 2.0;
+pub const DEFAULT_AUTONAT_BOOT_DELAY_SECS: u64 = 5;
+pub const DEFAULT_AUTONAT_REFRESH_INTERVAL_SECS: u64 = 15 * 60;
+pub const DEFAULT_MAILBOX_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+pub const DEFAULT_MAILBOX_MAX_MESSAGES_PER_PEER: usize = 100;
+pub const DEFAULT_MAILBOX_MAX_BYTES_PER_PEER: usize = 1024 * 1024;
+pub const DEFAULT_MAILBOX_MAX_MESSAGES_PER_SENDER: usize = 100;
+pub const DEFAULT_MAILBOX_MAX_BYTES_PER_SENDER: usize = 1024 * 1024;
+pub const DEFAULT_MAILBOX_MAX_MESSAGES: usize = 10_000;
+pub const DEFAULT_MAILBOX_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_MAILBOX_EXPIRE_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_STARTUP_LISTEN_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_STARTUP_BOOTNODE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_RELAY_RESERVATIONS: usize = 2;
+pub const DEFAULT_PRESENCE_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_PRESENCE_AWAY_AFTER_SECS: u64 = 5 * 60;
+pub const DEFAULT_PRESENCE_OFFLINE_AFTER_SECS: u64 = 90;
+
+// PROTOCOL CONSTANTS
+pub const DIRECT_MESSAGE_PROTOCOL: &str = "/chat-app/dm/2.0.0";
+pub const DIRECT_MESSAGE_MAX_SIZE: usize = 64 * 1024;
+pub const SYNC_PROTOCOL: &str = "/chat-app/sync/1.0.0";
+pub const SYNC_MAX_SIZE: usize = 4 * 1024 * 1024;
+/// Most messages a peer hands out per sync request, keeping the latest ones.
+pub const SYNC_MAX_MESSAGES: usize = 500;
+pub const MAILBOX_PROTOCOL: &str = "/chat-app/mailbox/1.0.0";
+pub const MAILBOX_MAX_SIZE: usize = 4 * 1024 * 1024;
+/// Advertised through Identify by nodes running a circuit relay.
+pub const RELAY_HOP_PROTOCOL: &str = "/libp2p/circuit/relay/0.2.0/hop";
+
+// DHT CONSTANTS
+pub const NICKNAME_KEY_PREFIX: &str = "/chat-app/nick/";
+pub const NICKNAME_MAX_LEN: usize = 32;
+/// How far ahead of the local clock a claim may be dated, to allow for clock skew.
+pub const NICKNAME_CLOCK_SKEW_SECS: u64 = 60;
+/// Oldest claim time accepted, long-held claims are dated forward when renewed.
+pub const NICKNAME_CLAIM_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+/// Members of a room provide the key made of this prefix and the room name.
+pub const ROOM_KEY_PREFIX: &str = "/chat-app/room/";
+
+// PRESENCE CONSTANTS
+/// Heartbeats of a room are published on the topic made of this prefix and the room name.
+pub const PRESENCE_TOPIC_PREFIX: &str = "/chat-app/presence/";
+
+// CRYPTO CONSTANTS
+pub const DIRECT_KEY_INFO: &str = "chat-app direct message key v1";
+/// Room keys kept after a rotation, so in-flight messages still decrypt.
+pub const ROOM_KEYS_KEPT: usize = 2;
+/// How long an invite to a private room waits to be accepted.
+pub const INVITE_TTL_SECS: u64 = 24 * 60 * 60;
+/// Pending invites kept from a single owner, and from all of them.
+pub const INVITES_PER_OWNER_MAX: usize = 16;
+pub const INVITES_MAX: usize = 256;
+
+// KEYFILE CONSTANTS
+/// Keyfile used when neither `--keyfile` nor `--key-seed` is given, under the user config directory.
+pub const DEFAULT_KEYFILE: &str = "chat-app/identity.key";
+
+// ACTOR CONSTANTS
+pub const PEER_MONITOR: &str = "peer-monitor";
+
+// CHAT CONSTANTS
+pub const DEFAULT_ROOM: &str = "chat";
+pub const CHAT_VIEW_MAX_MESSAGES: usize = 100;
+/// Stored messages shown when joining a room.
+pub const HISTORY_REPLAY_MESSAGES: usize = 20;
+/// Longest span `/history` accepts, about ten years.
+pub const HISTORY_MAX_MINUTES: i64 = 10 * 365 * 24 * 60;
+/// Ids of received messages remembered to drop duplicates, on top of the chat view and history.
+pub const SEEN_MESSAGES_MAX: usize = 10_000;
+pub const ENVELOPE_VERSION: u8 = 2;
+pub const ENVELOPE_MIN_VERSION: u8 = 1;
+/// Version given to plain-text payloads from peers that predate the envelope.
+pub const ENVELOPE_LEGACY_VERSION: u8 = 0;
@@ -3,22 +3,28 @@ mod modules;
 
 pub use crate::modules::*;
 use crate::{
-  constants::{BOOTNODES, KEY_SEEDS, PORTS},
+  config::NetworkConfig,
   peer::{mode::PeerMode, BootstrapBuilder, PeerBuilder},
+  supervisor::PeerSupervisor,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bastion::prelude::*;
 use clap::Parser;
-use log::debug;
+use libp2p::PeerId;
+use log::{debug, warn};
 use logger::FileLoggerSettingBuilder;
 use modules::traits::peer::TBuilder;
-use opts::Opts;
+use opts::{Command, Opts};
 
 #[tokio::main]
 async fn main() -> Result<()> {
   let opts = Opts::parse();
 
+  if let Some(command) = &opts.command {
+    return run_command(command);
+  }
+
   Bastion::init();
   Bastion::start();
 
@@ -40,35 +46,83 @@ async fn main() -> Result<()> {
   debug!("{file_logger_builder:?}");
   debug!("{opts:?}");
 
+  let config = match opts.config.as_deref() {
+    Some(path) => NetworkConfig::load(path)?,
+    None => NetworkConfig::default(),
+  };
+  debug!("{config:?}");
+
   let peer_buidler = match opts.peer_mode {
     PeerMode::Peer => {
-      let builder = match opts.key_seed {
-        Some(seed) => PeerBuilder::default().local_key_with_seed(seed),
-        None => PeerBuilder::default().local_key(),
-      };
-      Vec::from([builder.boxed()])
+      let builder = match (opts.keyfile.as_deref(), opts.key_seed) {
+        (Some(path), _) => PeerBuilder::default().local_key_from_file(path)?,
+        (None, Some(seed)) => PeerBuilder::default().local_key_with_seed(seed),
+        // Without a stable identity the DHT store of the previous run would be left behind.
+        (None, None) => match keyfile::default_path() {
+          Some(path) => PeerBuilder::default().local_key_from_file(&path)?,
+          None => {
+            warn!("No config directory to keep the identity in, using a temporary one");
+            PeerBuilder::default().local_key()
+          }
+        },
+      }
+      .config(config.clone())
+      .history(opts.history.clone())
+      .dht_store(opts.dht_store.clone());
+      Vec::from([(builder.boxed(), config.bootnodes().to_vec())])
     }
     PeerMode::Bootstrap => {
+      let bootnodes = config.bootnodes();
+      if opts.number_of_boot_node > bootnodes.len() {
+        bail!(
+          "{} boot nodes requested, but the network config only lists {}",
+          opts.number_of_boot_node,
+          bootnodes.len()
+        );
+      }
+
       let mut builders = Vec::new();
 
-      for idx in 0..opts.number_of_boot_node {
-        builders.push(
-          BootstrapBuilder::default()
-            .local_key_with_seed(KEY_SEEDS[idx])
-            .port(PORTS[idx])
+      for (idx, bootnode) in bootnodes[..opts.number_of_boot_node].iter().enumerate() {
+        let builder = match (bootnode.keyfile(), bootnode.key_seed()) {
+          (Some(path), _) => {
+            // Bootstrap identities are provisioned ahead of time with `keygen`, never created here.
+            let peer_id = PeerId::from(keyfile::load(path)?.public());
+            if &peer_id != bootnode.peer_id() {
+              bail!(
+                "bootnodes[{idx}]: keyfile {} holds peer id {peer_id}, but the address names {}",
+                path.display(),
+                bootnode.peer_id()
+              );
+            }
+            BootstrapBuilder::default().local_key_from_file(path)?
+          }
+          (None, Some(seed)) => BootstrapBuilder::default().local_key_with_seed(seed),
+          (None, None) => {
+            bail!("bootnodes[{idx}] needs a `keyfile` or `key_seed` to run in bootstrap mode")
+          }
+        };
+        builders.push((
+          builder
+            .port(bootnode.port())
+            .listen_addrs(bootnode.listen_addrs())
+            .external_addrs(bootnode.external_addrs())
+            .ws_port(bootnode.ws_port())
+            .config(config.clone())
+            .dht_store(opts.dht_store.clone())
             .boxed(),
-        )
+          // Each bootstrap node only knows about the ones started before it.
+          bootnodes[..idx].to_vec(),
+        ))
       }
 
       builders
     }
   };
 
-  for (idx, builder) in peer_buidler.iter().enumerate() {
-    let mut peer = builder.build().await?;
-    spawn!(async move {
-      peer.run(&BOOTNODES[..idx]).await.expect("peer run failed");
-    });
+  let supervisor = PeerSupervisor::new(config.supervisor())?;
+  for (idx, (builder, bootnodes)) in peer_buidler.into_iter().enumerate() {
+    supervisor.spawn(format!("peer-{idx}"), builder, bootnodes)?;
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
   }
 
@@ -76,3 +130,17 @@ async fn main() -> Result<()> {
 
   Ok(())
 }
+
+fn run_command(command: &Command) -> Result<()> {
+  match command {
+    Command::Keygen { out, force } => {
+      let keypair = keyfile::create(out, *force)?;
+      println!("{}", PeerId::from(keypair.public()));
+    }
+    Command::ShowPeerId { keyfile: path } => {
+      let keypair = keyfile::load(path)?;
+      println!("{}", PeerId::from(keypair.public()));
+    }
+  }
+  Ok(())
+}
@@ -1,5 +1,12 @@
+pub mod chat;
+pub mod config;
+pub mod console;
+pub mod crypto;
+pub mod envelope;
 pub mod helper;
+pub mod keyfile;
 pub mod logger;
 pub mod opts;
 pub mod peer;
+pub mod supervisor;
 pub mod traits;
@@ -0,0 +1,17 @@
+mod aggregate;
+mod commands;
+mod events;
+mod history;
+mod message;
+mod queries;
+mod service;
+mod store;
+
+pub use aggregate::*;
+pub use commands::*;
+pub use events::*;
+pub use history::*;
+pub use message::*;
+pub use queries::*;
+pub use service::*;
+pub use store::*;
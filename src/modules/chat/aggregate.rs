@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use async_trait::async_trait;
+use cqrs_es::Aggregate;
+use serde::{Deserialize, Serialize};
+
+use super::commands::ChatRoomCommand;
+use super::events::ChatRoomEvent;
+
+/// A chat room, rebuilt from its event log. The aggregate id is the room name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatRoom {
+  members: HashSet<String>,
+  message_count: u64,
+}
+
+#[derive(Debug)]
+pub enum ChatRoomError {
+  AlreadyMember(String),
+  NotMember(String),
+  EmptyMessage,
+}
+
+impl Display for ChatRoomError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ChatRoomError::AlreadyMember(member) => write!(f, "{member} is already in the room"),
+      ChatRoomError::NotMember(member) => write!(f, "{member} is not in the room"),
+      ChatRoomError::EmptyMessage => write!(f, "message is empty"),
+    }
+  }
+}
+
+impl std::error::Error for ChatRoomError {}
+
+#[async_trait]
+impl Aggregate for ChatRoom {
+  type Command = ChatRoomCommand;
+  type Event = ChatRoomEvent;
+  type Error = ChatRoomError;
+  type Services = ();
+
+  fn aggregate_type() -> String {
+    "chat_room".to_owned()
+  }
+
+  async fn handle(
+    &self,
+    command: Self::Command,
+    _: &Self::Services,
+  ) -> Result<Vec<Self::Event>, Self::Error> {
+    match command {
+      ChatRoomCommand::JoinRoom { member } => {
+        if self.members.contains(&member) {
+          return Err(ChatRoomError::AlreadyMember(member));
+        }
+        Ok(vec![ChatRoomEvent::MemberJoined { member }])
+      }
+      ChatRoomCommand::LeaveRoom { member } => {
+        if !self.members.contains(&member) {
+          return Err(ChatRoomError::NotMember(member));
+        }
+        Ok(vec![ChatRoomEvent::MemberLeft { member }])
+      }
+      ChatRoomCommand::SendMessage(message) => {
+        if message.content.trim().is_empty() {
+          return Err(ChatRoomError::EmptyMessage);
+        }
+        if !self.members.contains(&message.sender) {
+          return Err(ChatRoomError::NotMember(message.sender));
+        }
+        Ok(vec![ChatRoomEvent::MessagePosted {
+          message,
+          local: true,
+        }])
+      }
+      ChatRoomCommand::ReceiveMessage(message) => {
+        // Remote peers don't announce themselves, so their first message counts as joining.
+        let mut events = Vec::new();
+        if !self.members.contains(&message.sender) {
+          events.push(ChatRoomEvent::MemberJoined {
+            member: message.sender.clone(),
+          });
+        }
+        events.push(ChatRoomEvent::MessagePosted {
+          message,
+          local: false,
+        });
+        Ok(events)
+      }
+    }
+  }
+
+  fn apply(&mut self, event: Self::Event) {
+    match event {
+      ChatRoomEvent::MemberJoined { member } => {
+        self.members.insert(member);
+      }
+      ChatRoomEvent::MemberLeft { member } => {
+        self.members.remove(&member);
+      }
+      ChatRoomEvent::MessagePosted { .. } => {
+        self.message_count += 1;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::Utc;
+  use uuid::Uuid;
+
+  use super::*;
+  use crate::chat::ChatMessage;
+  use crate::envelope::TEXT_PLAIN;
+
+  fn message(sender: &str, content: &str) -> ChatMessage {
+    ChatMessage {
+      id: Uuid::new_v4(),
+      sender: sender.to_owned(),
+      nickname: None,
+      content: content.to_owned(),
+      content_type: TEXT_PLAIN.to_owned(),
+      body: None,
+      reply_to: None,
+      sent_at: Utc::now(),
+      encrypted: false,
+      signature: None,
+    }
+  }
+
+  fn room_with(members: &[&str]) -> ChatRoom {
+    let mut room = ChatRoom::default();
+    for member in members {
+      room.apply(ChatRoomEvent::MemberJoined {
+        member: member.to_string(),
+      });
+    }
+    room
+  }
+
+  #[tokio::test]
+  async fn members_join_and_leave_once() {
+    let room = room_with(&["alice"]);
+    let join = |member: &str| ChatRoomCommand::JoinRoom {
+      member: member.to_owned(),
+    };
+    assert!(matches!(
+      room.handle(join("alice"), &()).await,
+      Err(ChatRoomError::AlreadyMember(_))
+    ));
+    assert_eq!(
+      room.handle(join("bob"), &()).await.unwrap(),
+      vec![ChatRoomEvent::MemberJoined {
+        member: "bob".to_owned()
+      }]
+    );
+    let leave = ChatRoomCommand::LeaveRoom {
+      member: "bob".to_owned(),
+    };
+    assert!(matches!(
+      room.handle(leave, &()).await,
+      Err(ChatRoomError::NotMember(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn sent_messages_need_a_member_and_content() {
+    let room = room_with(&["alice"]);
+    let send = |sender: &str, content: &str| ChatRoomCommand::SendMessage(message(sender, content));
+    assert!(matches!(
+      room.handle(send("alice", "  "), &()).await,
+      Err(ChatRoomError::EmptyMessage)
+    ));
+    assert!(matches!(
+      room.handle(send("bob", "hi"), &()).await,
+      Err(ChatRoomError::NotMember(_))
+    ));
+    let events = room.handle(send("alice", "hi"), &()).await.unwrap();
+    assert!(matches!(
+      events.as_slice(),
+      [ChatRoomEvent::MessagePosted { local: true, .. }]
+    ));
+  }
+
+  #[tokio::test]
+  async fn first_received_message_joins_its_sender() {
+    let mut room = room_with(&[]);
+    let received = ChatRoomCommand::ReceiveMessage(message("bob", "hi"));
+    let events = room.handle(received.clone(), &()).await.unwrap();
+    assert!(matches!(
+      events.as_slice(),
+      [
+        ChatRoomEvent::MemberJoined { .. },
+        ChatRoomEvent::MessagePosted { local: false, .. }
+      ]
+    ));
+    for event in events {
+      room.apply(event);
+    }
+    assert_eq!(room.message_count, 1);
+    assert_eq!(room.handle(received, &()).await.unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+use super::message::ChatMessage;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChatRoomCommand {
+  /// A member joins the room.
+  JoinRoom { member: String },
+  /// A member leaves the room.
+  LeaveRoom { member: String },
+  /// The local node posts a message typed on its console.
+  SendMessage(ChatMessage),
+  /// A message arrived from another peer over gossipsub.
+  ReceiveMessage(ChatMessage),
+}
@@ -0,0 +1,34 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+
+use super::message::ChatMessage;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ChatRoomEvent {
+  MemberJoined {
+    member: String,
+  },
+  MemberLeft {
+    member: String,
+  },
+  MessagePosted {
+    message: ChatMessage,
+    /// Whether the message was written on this node.
+    local: bool,
+  },
+}
+
+impl DomainEvent for ChatRoomEvent {
+  fn event_type(&self) -> String {
+    match self {
+      ChatRoomEvent::MemberJoined { .. } => "MemberJoined",
+      ChatRoomEvent::MemberLeft { .. } => "MemberLeft",
+      ChatRoomEvent::MessagePosted { .. } => "MessagePosted",
+    }
+    .to_owned()
+  }
+
+  fn event_version(&self) -> String {
+    "2.0".to_owned()
+  }
+}
@@ -0,0 +1,265 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use cqrs_es::{EventEnvelope, Query};
+use log::error;
+use uuid::Uuid;
+
+use super::aggregate::ChatRoom;
+use super::events::ChatRoomEvent;
+use super::queries::PostedMessage;
+
+const ROOM_TREE_PREFIX: &str = "room/";
+const ID_TREE: &str = "ids";
+const ROOMS_TREE: &str = "rooms";
+
+/// On-disk log of sent and received messages, with a tree per room.
+///
+/// Messages are keyed by send time then id, so a room is read back in order and time ranges are
+/// plain key ranges. The `ids` tree maps `room/id` to that key, so a message gossiped twice is
+/// only stored once. The `rooms` tree holds the rooms we're in, rejoined on startup.
+#[derive(Debug, Clone)]
+pub struct HistoryStore {
+  db: sled::Db,
+}
+
+impl HistoryStore {
+  pub fn open(path: &Path) -> Result<Self> {
+    let db = sled::open(path)
+      .with_context(|| format!("failed to open message history {}", path.display()))?;
+    Ok(Self { db })
+  }
+
+  pub fn record(&self, room: &str, posted: &PostedMessage) -> Result<()> {
+    if self.contains(room, &posted.message.id)? {
+      return Ok(());
+    }
+    let key = message_key(posted.message.sent_at, &posted.message.id);
+    self
+      .room_tree(room)?
+      .insert(key, serde_cbor::to_vec(posted)?)?;
+    self
+      .db
+      .open_tree(ID_TREE)?
+      .insert(id_key(room, &posted.message.id), &key[..])?;
+    Ok(())
+  }
+
+  pub fn contains(&self, room: &str, id: &Uuid) -> Result<bool> {
+    Ok(self.db.open_tree(ID_TREE)?.contains_key(id_key(room, id))?)
+  }
+
+  /// The `count` most recent messages of `room`, oldest first.
+  pub fn last(&self, room: &str, count: usize) -> Result<Vec<PostedMessage>> {
+    let mut messages = self
+      .room_tree(room)?
+      .iter()
+      .rev()
+      .take(count)
+      .map(|entry| decode(&entry?.1))
+      .collect::<Result<Vec<_>>>()?;
+    messages.reverse();
+    Ok(messages)
+  }
+
+  /// Messages of `room` sent from `from` (inclusive) to `to` (exclusive), oldest first.
+  pub fn range(
+    &self,
+    room: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+  ) -> Result<Vec<PostedMessage>> {
+    let start = message_key(from, &Uuid::nil());
+    let end = message_key(to, &Uuid::nil());
+    self
+      .room_tree(room)?
+      .range(start..end)
+      .map(|entry| decode(&entry?.1))
+      .collect()
+  }
+
+  /// Messages of `room` sent from `from` on, oldest first.
+  pub fn since(&self, room: &str, from: DateTime<Utc>) -> Result<Vec<PostedMessage>> {
+    self
+      .room_tree(room)?
+      .range(message_key(from, &Uuid::nil())..)
+      .map(|entry| decode(&entry?.1))
+      .collect()
+  }
+
+  /// Remembers that we're in `room`, to rejoin it on startup.
+  pub fn save_room(&self, room: &str) -> Result<()> {
+    self.db.open_tree(ROOMS_TREE)?.insert(room, &[])?;
+    Ok(())
+  }
+
+  pub fn forget_room(&self, room: &str) -> Result<()> {
+    self.db.open_tree(ROOMS_TREE)?.remove(room)?;
+    Ok(())
+  }
+
+  /// Rooms we were in when last running.
+  pub fn rooms(&self) -> Result<Vec<String>> {
+    self
+      .db
+      .open_tree(ROOMS_TREE)?
+      .iter()
+      .keys()
+      .map(|key| String::from_utf8(key?.to_vec()).context("corrupt room name in history"))
+      .collect()
+  }
+
+  fn room_tree(&self, room: &str) -> Result<sled::Tree> {
+    Ok(self.db.open_tree(format!("{ROOM_TREE_PREFIX}{room}"))?)
+  }
+}
+
+#[async_trait]
+impl Query<ChatRoom> for HistoryStore {
+  async fn dispatch(&self, room: &str, events: &[EventEnvelope<ChatRoom>]) {
+    for event in events {
+      if let ChatRoomEvent::MessagePosted { message, local } = &event.payload {
+        let posted = PostedMessage {
+          message: message.clone(),
+          local: *local,
+        };
+        if let Err(e) = self.record(room, &posted) {
+          error!("Failed to store message {} in {room}: {e:?}", message.id);
+        }
+      }
+    }
+  }
+}
+
+/// Big-endian millis with the sign bit flipped, so keys sort by time, followed by the message id.
+fn message_key(sent_at: DateTime<Utc>, id: &Uuid) -> [u8; 24] {
+  let millis = (sent_at.timestamp_millis() as u64) ^ (1 << 63);
+  let mut key = [0u8; 24];
+  key[..8].copy_from_slice(&millis.to_be_bytes());
+  key[8..].copy_from_slice(id.as_bytes());
+  key
+}
+
+fn id_key(room: &str, id: &Uuid) -> Vec<u8> {
+  [room.as_bytes(), b"/", id.as_bytes()].concat()
+}
+
+fn decode(value: &[u8]) -> Result<PostedMessage> {
+  serde_cbor::from_slice(value).context("corrupt message in history")
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{Duration, TimeZone};
+
+  use super::*;
+  use crate::chat::ChatMessage;
+  use crate::envelope::TEXT_PLAIN;
+
+  fn store() -> HistoryStore {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    HistoryStore { db }
+  }
+
+  fn posted(sent_at: DateTime<Utc>, content: &str) -> PostedMessage {
+    PostedMessage {
+      message: ChatMessage {
+        id: Uuid::new_v4(),
+        sender: "peer".to_owned(),
+        nickname: None,
+        content: content.to_owned(),
+        content_type: TEXT_PLAIN.to_owned(),
+        body: None,
+        reply_to: None,
+        sent_at,
+        encrypted: false,
+        signature: None,
+      },
+      local: false,
+    }
+  }
+
+  fn contents(messages: &[PostedMessage]) -> Vec<&str> {
+    messages
+      .iter()
+      .map(|posted| posted.message.content.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn keys_sort_by_time_including_before_the_epoch() {
+    let before = Utc.timestamp_millis_opt(-1).unwrap();
+    let epoch = Utc.timestamp_millis_opt(0).unwrap();
+    let later = Utc.timestamp_millis_opt(1).unwrap();
+    assert!(message_key(before, &Uuid::from_bytes([0xff; 16])) < message_key(epoch, &Uuid::nil()));
+    assert!(message_key(epoch, &Uuid::from_bytes([0xff; 16])) < message_key(later, &Uuid::nil()));
+  }
+
+  #[test]
+  fn reads_rooms_back_in_order() {
+    let history = store();
+    let now = Utc::now();
+    for (minutes, content) in [(2, "third"), (0, "first"), (1, "second")] {
+      history
+        .record("chat", &posted(now + Duration::minutes(minutes), content))
+        .unwrap();
+    }
+    history.record("other", &posted(now, "elsewhere")).unwrap();
+
+    assert_eq!(
+      contents(&history.last("chat", 10).unwrap()),
+      ["first", "second", "third"]
+    );
+    assert_eq!(
+      contents(&history.last("chat", 2).unwrap()),
+      ["second", "third"]
+    );
+    assert_eq!(contents(&history.last("other", 10).unwrap()), ["elsewhere"]);
+  }
+
+  #[test]
+  fn time_ranges_include_their_start_only() {
+    let history = store();
+    let now = Utc::now();
+    for minutes in 0..4 {
+      let content = minutes.to_string();
+      history
+        .record("chat", &posted(now + Duration::minutes(minutes), &content))
+        .unwrap();
+    }
+
+    let range = history
+      .range(
+        "chat",
+        now + Duration::minutes(1),
+        now + Duration::minutes(3),
+      )
+      .unwrap();
+    assert_eq!(contents(&range), ["1", "2"]);
+    let since = history.since("chat", now + Duration::minutes(2)).unwrap();
+    assert_eq!(contents(&since), ["2", "3"]);
+  }
+
+  #[test]
+  fn stores_a_message_once() {
+    let history = store();
+    let message = posted(Utc::now(), "hello");
+    history.record("chat", &message).unwrap();
+    history.record("chat", &message).unwrap();
+    assert!(history.contains("chat", &message.message.id).unwrap());
+    assert!(!history.contains("other", &message.message.id).unwrap());
+    assert_eq!(history.last("chat", 10).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn remembers_joined_rooms() {
+    let history = store();
+    history.save_room("chat").unwrap();
+    history.save_room("rust").unwrap();
+    history.save_room("rust").unwrap();
+    history.forget_room("chat").unwrap();
+    assert_eq!(history.rooms().unwrap(), ["rust"]);
+  }
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use uuid::Uuid;
+
+use crate::envelope::{Envelope, TEXT_PLAIN};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+  pub id: Uuid,
+  /// Peer id of the author.
+  pub sender: String,
+  pub nickname: Option<String>,
+  /// The body as text, or a short description of it for other content types.
+  pub content: String,
+  #[serde(default = "text_plain")]
+  pub content_type: String,
+  /// Original body when `content` doesn't hold it as is, kept to rebuild the envelope.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub body: Option<ByteBuf>,
+  pub reply_to: Option<Uuid>,
+  pub sent_at: DateTime<Utc>,
+  /// Whether the message reached us encrypted.
+  #[serde(default)]
+  pub encrypted: bool,
+  /// Signature of the author over the envelope, kept to pass the message on when syncing.
+  #[serde(default)]
+  pub signature: Option<ByteBuf>,
+}
+
+impl ChatMessage {
+  /// Builds a message from a decrypted `envelope`, that was `encrypted` on the wire.
+  pub fn from_envelope(sender: String, envelope: &Envelope, encrypted: bool) -> Self {
+    let content = envelope.display_body();
+    let body = (content.as_bytes() != envelope.body.as_slice()).then(|| envelope.body.clone());
+    Self {
+      id: envelope.id,
+      sender,
+      nickname: envelope.nickname.clone(),
+      content,
+      content_type: envelope.content_type.clone(),
+      body,
+      reply_to: envelope.reply_to,
+      sent_at: envelope.timestamp,
+      encrypted,
+      signature: envelope.signature.clone(),
+    }
+  }
+
+  /// Name to show for the author: the nickname if any, the peer id otherwise.
+  pub fn author(&self) -> &str {
+    self.nickname.as_deref().unwrap_or(&self.sender)
+  }
+
+  /// Rebuilds the unencrypted envelope this message was posted with, signed if it was.
+  pub fn to_envelope(&self) -> Envelope {
+    let mut envelope = Envelope::text(self.content.clone(), self.nickname.clone(), self.reply_to);
+    envelope.id = self.id;
+    envelope.timestamp = self.sent_at;
+    envelope.content_type = self.content_type.clone();
+    if let Some(body) = &self.body {
+      envelope.body = body.clone();
+    }
+    if let Some(signature) = &self.signature {
+      envelope.sender = Some(self.sender.clone());
+      envelope.signature = Some(signature.clone());
+    }
+    envelope
+  }
+
+  /// Marker shown next to messages that were encrypted on the wire.
+  pub fn lock(&self) -> &'static str {
+    match self.encrypted {
+      true => "[encrypted] ",
+      false => "",
+    }
+  }
+}
+
+fn text_plain() -> String {
+  TEXT_PLAIN.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use libp2p::identity::Keypair;
+  use libp2p::PeerId;
+
+  use super::*;
+
+  #[test]
+  fn rebuilds_the_envelope_it_was_posted_with() {
+    let keypair = Keypair::generate_ed25519();
+    let sender = PeerId::from(keypair.public()).to_string();
+    let text = Envelope::text("hello".to_owned(), Some("alice".to_owned()), None);
+    let mut image = Envelope::text(String::new(), None, Some(text.id));
+    image.content_type = "image/png".to_owned();
+    image.body = ByteBuf::from(vec![0x89, b'P', b'N', b'G']);
+
+    for envelope in [text, image] {
+      let envelope = envelope.sign(&keypair, "chat").unwrap();
+      let message = ChatMessage::from_envelope(sender.clone(), &envelope, false);
+      let rebuilt = message.to_envelope();
+      assert_eq!(rebuilt, envelope);
+      assert!(rebuilt.verify("chat").is_ok());
+    }
+  }
+}
@@ -0,0 +1,98 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::CHAT_VIEW_MAX_MESSAGES;
+
+use super::aggregate::ChatRoom;
+use super::events::ChatRoomEvent;
+use super::message::ChatMessage;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostedMessage {
+  pub message: ChatMessage,
+  pub local: bool,
+}
+
+/// Read model of a room: who is in it and its most recent messages.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RoomView {
+  pub members: BTreeSet<String>,
+  pub messages: Vec<PostedMessage>,
+}
+
+impl View<ChatRoom> for RoomView {
+  fn update(&mut self, event: &EventEnvelope<ChatRoom>) {
+    match &event.payload {
+      ChatRoomEvent::MemberJoined { member } => {
+        self.members.insert(member.clone());
+      }
+      ChatRoomEvent::MemberLeft { member } => {
+        self.members.remove(member);
+      }
+      ChatRoomEvent::MessagePosted { message, local } => {
+        self.messages.push(PostedMessage {
+          message: message.clone(),
+          local: *local,
+        });
+        if self.messages.len() > CHAT_VIEW_MAX_MESSAGES {
+          let overflow = self.messages.len() - CHAT_VIEW_MAX_MESSAGES;
+          self.messages.drain(..overflow);
+        }
+      }
+    }
+  }
+}
+
+/// Keeps a `RoomView` per room in memory.
+#[derive(Debug, Default, Clone)]
+pub struct RoomViewQuery {
+  views: Arc<RwLock<HashMap<String, RoomView>>>,
+}
+
+impl RoomViewQuery {
+  pub fn load(&self, room: &str) -> Option<RoomView> {
+    self.views.read().unwrap().get(room).cloned()
+  }
+}
+
+#[async_trait]
+impl Query<ChatRoom> for RoomViewQuery {
+  async fn dispatch(&self, room: &str, events: &[EventEnvelope<ChatRoom>]) {
+    let mut views = self.views.write().unwrap();
+    let view = views.entry(room.to_owned()).or_default();
+    for event in events {
+      view.update(event);
+    }
+  }
+}
+
+/// Prints committed chat activity to the console.
+#[derive(Debug, Default)]
+pub struct LoggingQuery;
+
+#[async_trait]
+impl Query<ChatRoom> for LoggingQuery {
+  async fn dispatch(&self, room: &str, events: &[EventEnvelope<ChatRoom>]) {
+    for event in events {
+      match &event.payload {
+        ChatRoomEvent::MemberJoined { member } => info!("[{room}] {member} joined"),
+        ChatRoomEvent::MemberLeft { member } => info!("[{room}] {member} left"),
+        ChatRoomEvent::MessagePosted {
+          message,
+          local: false,
+        } => info!(
+          "[{room}] {}Received: '{}' from {}",
+          message.lock(),
+          message.content,
+          message.author()
+        ),
+        ChatRoomEvent::MessagePosted { .. } => {}
+      }
+    }
+  }
+}
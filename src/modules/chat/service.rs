@@ -0,0 +1,45 @@
+use cqrs_es::{AggregateError, CqrsFramework, Query};
+
+use super::aggregate::{ChatRoom, ChatRoomError};
+use super::commands::ChatRoomCommand;
+use super::history::HistoryStore;
+use super::queries::{LoggingQuery, RoomView, RoomViewQuery};
+use super::store::SnapshotStore;
+
+/// Entry point for chat commands and views, backed by an event store.
+pub struct ChatService {
+  cqrs: CqrsFramework<ChatRoom, SnapshotStore<ChatRoom>>,
+  rooms: RoomViewQuery,
+}
+
+impl Default for ChatService {
+  fn default() -> Self {
+    Self::new(None)
+  }
+}
+
+impl ChatService {
+  /// Creates a service that also records messages in `history`, when given.
+  pub fn new(history: Option<HistoryStore>) -> Self {
+    let rooms = RoomViewQuery::default();
+    let mut queries: Vec<Box<dyn Query<ChatRoom>>> =
+      vec![Box::new(LoggingQuery), Box::new(rooms.clone())];
+    if let Some(history) = history {
+      queries.push(Box::new(history));
+    }
+    let cqrs = CqrsFramework::new(SnapshotStore::default(), queries, ());
+    Self { cqrs, rooms }
+  }
+
+  pub async fn execute(
+    &self,
+    room: &str,
+    command: ChatRoomCommand,
+  ) -> Result<(), AggregateError<ChatRoomError>> {
+    self.cqrs.execute(room, command).await
+  }
+
+  pub fn room(&self, room: &str) -> Option<RoomView> {
+    self.rooms.load(room)
+  }
+}
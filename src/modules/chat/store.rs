@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateContext, AggregateError, EventEnvelope, EventStore};
+
+/// In-memory event store keeping the full event log of each aggregate next to a snapshot of it.
+///
+/// The log stays the source of truth, the snapshot only spares replaying every event each time
+/// an aggregate handles a command.
+pub struct SnapshotStore<A: Aggregate> {
+  aggregates: Arc<RwLock<HashMap<String, Snapshot<A>>>>,
+}
+
+struct Snapshot<A: Aggregate> {
+  aggregate: A,
+  sequence: usize,
+  /// Every event committed so far, oldest first.
+  events: Vec<EventEnvelope<A>>,
+}
+
+impl<A: Aggregate> Default for SnapshotStore<A> {
+  fn default() -> Self {
+    Self {
+      aggregates: Default::default(),
+    }
+  }
+}
+
+/// An aggregate as loaded from its snapshot.
+pub struct SnapshotContext<A: Aggregate> {
+  aggregate_id: String,
+  aggregate: A,
+  sequence: usize,
+}
+
+impl<A: Aggregate> AggregateContext<A> for SnapshotContext<A> {
+  fn aggregate(&self) -> &A {
+    &self.aggregate
+  }
+}
+
+#[async_trait]
+impl<A: Aggregate + Clone> EventStore<A> for SnapshotStore<A> {
+  type AC = SnapshotContext<A>;
+
+  /// Every event of the aggregate, oldest first.
+  async fn load_events(
+    &self,
+    aggregate_id: &str,
+  ) -> Result<Vec<EventEnvelope<A>>, AggregateError<A::Error>> {
+    let aggregates = self.aggregates.read().unwrap();
+    Ok(
+      aggregates
+        .get(aggregate_id)
+        .map(|snapshot| snapshot.events.clone())
+        .unwrap_or_default(),
+    )
+  }
+
+  async fn load_aggregate(
+    &self,
+    aggregate_id: &str,
+  ) -> Result<SnapshotContext<A>, AggregateError<A::Error>> {
+    let aggregates = self.aggregates.read().unwrap();
+    let (aggregate, sequence) = match aggregates.get(aggregate_id) {
+      Some(snapshot) => (snapshot.aggregate.clone(), snapshot.sequence),
+      None => (A::default(), 0),
+    };
+    Ok(SnapshotContext {
+      aggregate_id: aggregate_id.to_owned(),
+      aggregate,
+      sequence,
+    })
+  }
+
+  async fn commit(
+    &self,
+    events: Vec<A::Event>,
+    context: SnapshotContext<A>,
+    metadata: HashMap<String, String>,
+  ) -> Result<Vec<EventEnvelope<A>>, AggregateError<A::Error>> {
+    let SnapshotContext {
+      aggregate_id,
+      mut aggregate,
+      sequence,
+    } = context;
+    let mut aggregates = self.aggregates.write().unwrap();
+    let snapshot = aggregates
+      .entry(aggregate_id.clone())
+      .or_insert_with(|| Snapshot {
+        aggregate: A::default(),
+        sequence: 0,
+        events: Vec::new(),
+      });
+    if snapshot.sequence != sequence {
+      return Err(AggregateError::AggregateConflict);
+    }
+
+    let envelopes: Vec<EventEnvelope<A>> = events
+      .into_iter()
+      .enumerate()
+      .map(|(offset, payload)| EventEnvelope {
+        aggregate_id: aggregate_id.clone(),
+        sequence: sequence + offset + 1,
+        payload,
+        metadata: metadata.clone(),
+      })
+      .collect();
+    for envelope in &envelopes {
+      aggregate.apply(envelope.payload.clone());
+      snapshot.events.push(envelope.clone());
+    }
+    snapshot.sequence = sequence + envelopes.len();
+    snapshot.aggregate = aggregate;
+    Ok(envelopes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chat::{ChatRoom, ChatRoomCommand, ChatRoomEvent};
+
+  #[tokio::test]
+  async fn rooms_are_rebuilt_from_the_full_event_log() {
+    let store = SnapshotStore::<ChatRoom>::default();
+    let joined = |member: &str| ChatRoomEvent::MemberJoined {
+      member: member.to_owned(),
+    };
+    let context = store.load_aggregate("chat").await.unwrap();
+    store
+      .commit(vec![joined("alice")], context, HashMap::new())
+      .await
+      .unwrap();
+    for member in 0..500 {
+      let context = store.load_aggregate("chat").await.unwrap();
+      store
+        .commit(vec![joined(&member.to_string())], context, HashMap::new())
+        .await
+        .unwrap();
+    }
+
+    let events = store.load_events("chat").await.unwrap();
+    assert_eq!(events.len(), 501);
+    assert!(events
+      .iter()
+      .enumerate()
+      .all(|(offset, event)| event.sequence == offset + 1));
+
+    // Replaying the log gives the same room as the snapshot, first member included.
+    let mut room = ChatRoom::default();
+    for event in events {
+      room.apply(event.payload);
+    }
+    let leave = ChatRoomCommand::LeaveRoom {
+      member: "alice".to_owned(),
+    };
+    assert!(room.handle(leave, &()).await.is_ok());
+    assert!(store.load_events("lobby").await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn stale_contexts_conflict() {
+    let store = SnapshotStore::<ChatRoom>::default();
+    let joined = ChatRoomEvent::MemberJoined {
+      member: "alice".to_owned(),
+    };
+    let stale = store.load_aggregate("chat").await.unwrap();
+    let context = store.load_aggregate("chat").await.unwrap();
+    store
+      .commit(vec![joined.clone()], context, HashMap::new())
+      .await
+      .unwrap();
+    assert!(matches!(
+      store.commit(vec![joined], stale, HashMap::new()).await,
+      Err(AggregateError::AggregateConflict)
+    ));
+  }
+}
@@ -0,0 +1,1041 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+
+use crate::constants::{
+  DEFAULT_AUTONAT_BOOT_DELAY_SECS, DEFAULT_AUTONAT_REFRESH_INTERVAL_SECS, DEFAULT_BOOTNODES,
+  DEFAULT_GOSSIPSUB_HEARTBEAT_INTERVAL_SECS, DEFAULT_GOSSIPSUB_IDLE_TIMEOUT_SECS,
+  DEFAULT_GOSSIPSUB_MAX_CLOCK_SKEW_SECS, DEFAULT_GOSSIPSUB_MAX_MESSAGES_PER_MINUTE,
+  DEFAULT_GOSSIPSUB_MAX_MESSAGE_AGE_SECS, DEFAULT_GOSSIPSUB_MAX_MESSAGE_SIZE,
+  DEFAULT_IDENTIFY_PROTOCOL_VERSION, DEFAULT_KADEMLIA_BOOTSTRAP_INTERVAL_SECS,
+  DEFAULT_KADEMLIA_BOOTSTRAP_RETRY_SECS, DEFAULT_KADEMLIA_CONNECTION_IDLE_TIMEOUT_SECS,
+  DEFAULT_KADEMLIA_NICKNAME_TTL_SECS, DEFAULT_KADEMLIA_PROVIDER_RECORD_TTL_SECS,
+  DEFAULT_KADEMLIA_QUERY_TIMEOUT_SECS, DEFAULT_KADEMLIA_RECORD_TTL_SECS,
+  DEFAULT_MAILBOX_EXPIRE_INTERVAL_SECS, DEFAULT_MAILBOX_MAX_BYTES,
+  DEFAULT_MAILBOX_MAX_BYTES_PER_PEER, DEFAULT_MAILBOX_MAX_BYTES_PER_SENDER,
+  DEFAULT_MAILBOX_MAX_MESSAGES, DEFAULT_MAILBOX_MAX_MESSAGES_PER_PEER,
+  DEFAULT_MAILBOX_MAX_MESSAGES_PER_SENDER, DEFAULT_MAILBOX_TTL_SECS, DEFAULT_PEER_LISTEN_ADDR,
+  DEFAULT_PRESENCE_AWAY_AFTER_SECS, DEFAULT_PRESENCE_HEARTBEAT_INTERVAL_SECS,
+  DEFAULT_PRESENCE_OFFLINE_AFTER_SECS, DEFAULT_RELAY_RESERVATIONS,
+  DEFAULT_STARTUP_BOOTNODE_TIMEOUT_SECS, DEFAULT_STARTUP_LISTEN_TIMEOUT_SECS,
+  DEFAULT_SUPERVISOR_BACKOFF_MULTIPLIER, DEFAULT_SUPERVISOR_BACKOFF_SECS,
+  DEFAULT_SUPERVISOR_MAX_RESTARTS, NICKNAME_CLAIM_MAX_AGE_SECS,
+};
+
+#[cfg(feature = "quic")]
+use crate::constants::DEFAULT_PEER_QUIC_LISTEN_ADDR;
+
+use super::helper::generate_ed25519;
+
+/// Network configuration, loaded from a TOML file or built from the compiled-in defaults.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+  bootnodes: Vec<BootNode>,
+  listen_addrs: Vec<Multiaddr>,
+  /// Addresses announced to other peers on top of the observed ones, e.g. a forwarded port.
+  external_addrs: Vec<Multiaddr>,
+  gossipsub: GossipsubSettings,
+  kademlia: KademliaSettings,
+  identify: IdentifySettings,
+  supervisor: SupervisorSettings,
+  mailbox: MailboxSettings,
+  autonat: AutonatSettings,
+  startup: StartupSettings,
+  relay: RelaySettings,
+  presence: PresenceSettings,
+}
+
+impl Default for NetworkConfig {
+  fn default() -> Self {
+    let bootnodes = DEFAULT_BOOTNODES
+      .iter()
+      .map(|(address, seed)| BootNode::parse(address, Some(*seed), None))
+      .collect::<Result<Vec<_>>>()
+      .expect("compiled-in bootnodes are valid");
+
+    Self {
+      bootnodes,
+      listen_addrs: default_listen_addrs(),
+      external_addrs: Vec::new(),
+      gossipsub: Default::default(),
+      kademlia: Default::default(),
+      identify: Default::default(),
+      supervisor: Default::default(),
+      mailbox: Default::default(),
+      autonat: Default::default(),
+      startup: Default::default(),
+      relay: Default::default(),
+      presence: Default::default(),
+    }
+  }
+}
+
+impl NetworkConfig {
+  pub fn load(path: &Path) -> Result<Self> {
+    let content = fs::read_to_string(path)
+      .with_context(|| format!("failed to read network config {}", path.display()))?;
+    Self::from_toml(&content).with_context(|| format!("invalid network config {}", path.display()))
+  }
+
+  pub fn from_toml(content: &str) -> Result<Self> {
+    let raw: RawNetworkConfig = toml::from_str(content)?;
+
+    if raw.bootnodes.is_empty() {
+      bail!("at least one entry in `bootnodes` is required");
+    }
+
+    let mut bootnodes = Vec::with_capacity(raw.bootnodes.len());
+    let mut seen = HashSet::new();
+    for (idx, node) in raw.bootnodes.iter().enumerate() {
+      let mut bootnode = BootNode::parse(&node.address, node.key_seed, node.keyfile.clone())
+        .with_context(|| format!("bootnodes[{idx}]"))?;
+      if node.ws_port.is_some() && node.ws_port == Some(bootnode.port()) {
+        bail!("bootnodes[{idx}]: ws_port must differ from the tcp port");
+      }
+      bootnode.ws_port = node.ws_port;
+      bootnode.listen_addrs = parse_addrs(&node.listen_addrs)
+        .with_context(|| format!("bootnodes[{idx}].listen_addrs"))?;
+      bootnode.external_addrs = parse_addrs(&node.external_addrs)
+        .with_context(|| format!("bootnodes[{idx}].external_addrs"))?;
+      if !seen.insert(*bootnode.peer_id()) {
+        bail!(
+          "bootnodes[{idx}]: peer id {} is listed more than once",
+          bootnode.peer_id()
+        );
+      }
+      bootnodes.push(bootnode);
+    }
+
+    let listen_addrs = match raw.peer.listen_addrs.is_empty() {
+      true => default_listen_addrs(),
+      false => parse_addrs(&raw.peer.listen_addrs).context("peer.listen_addrs")?,
+    };
+    let external_addrs = parse_addrs(&raw.peer.external_addrs).context("peer.external_addrs")?;
+
+    raw.gossipsub.validate().context("gossipsub")?;
+    raw.kademlia.validate().context("kademlia")?;
+    raw.identify.validate().context("identify")?;
+    raw.supervisor.validate().context("supervisor")?;
+    raw.mailbox.validate().context("mailbox")?;
+    raw.autonat.validate().context("autonat")?;
+    raw.startup.validate().context("startup")?;
+    raw.relay.validate().context("relay")?;
+    raw.presence.validate().context("presence")?;
+
+    Ok(Self {
+      bootnodes,
+      listen_addrs,
+      external_addrs,
+      gossipsub: raw.gossipsub,
+      kademlia: raw.kademlia,
+      identify: raw.identify,
+      supervisor: raw.supervisor,
+      mailbox: raw.mailbox,
+      autonat: raw.autonat,
+      startup: raw.startup,
+      relay: raw.relay,
+      presence: raw.presence,
+    })
+  }
+
+  pub fn bootnodes(&self) -> &[BootNode] {
+    &self.bootnodes
+  }
+
+  pub fn listen_addrs(&self) -> &[Multiaddr] {
+    &self.listen_addrs
+  }
+
+  pub fn external_addrs(&self) -> &[Multiaddr] {
+    &self.external_addrs
+  }
+
+  pub fn gossipsub(&self) -> &GossipsubSettings {
+    &self.gossipsub
+  }
+
+  pub fn kademlia(&self) -> &KademliaSettings {
+    &self.kademlia
+  }
+
+  pub fn identify(&self) -> &IdentifySettings {
+    &self.identify
+  }
+
+  pub fn supervisor(&self) -> &SupervisorSettings {
+    &self.supervisor
+  }
+
+  pub fn mailbox(&self) -> &MailboxSettings {
+    &self.mailbox
+  }
+
+  pub fn autonat(&self) -> &AutonatSettings {
+    &self.autonat
+  }
+
+  pub fn startup(&self) -> &StartupSettings {
+    &self.startup
+  }
+
+  pub fn relay(&self) -> &RelaySettings {
+    &self.relay
+  }
+
+  pub fn presence(&self) -> &PresenceSettings {
+    &self.presence
+  }
+}
+
+fn parse_addrs(addrs: &[String]) -> Result<Vec<Multiaddr>> {
+  addrs
+    .iter()
+    .enumerate()
+    .map(|(idx, addr)| {
+      addr
+        .parse::<Multiaddr>()
+        .with_context(|| format!("[{idx}]: `{addr}` is not a valid multiaddr"))
+    })
+    .collect()
+}
+
+/// TCP, plus QUIC when built with the `quic` feature.
+fn default_listen_addrs() -> Vec<Multiaddr> {
+  vec![
+    DEFAULT_PEER_LISTEN_ADDR.parse().unwrap(),
+    #[cfg(feature = "quic")]
+    DEFAULT_PEER_QUIC_LISTEN_ADDR.parse().unwrap(),
+  ]
+}
+
+#[derive(Debug, Clone)]
+pub struct BootNode {
+  peer_id: PeerId,
+  address: Multiaddr,
+  key_seed: Option<u8>,
+  keyfile: Option<PathBuf>,
+  ws_port: Option<u16>,
+  listen_addrs: Vec<Multiaddr>,
+  external_addrs: Vec<Multiaddr>,
+}
+
+impl BootNode {
+  fn parse(address: &str, key_seed: Option<u8>, keyfile: Option<PathBuf>) -> Result<Self> {
+    let mut addr = address
+      .parse::<Multiaddr>()
+      .with_context(|| format!("`{address}` is not a valid multiaddr"))?;
+
+    let peer_id = match addr.pop() {
+      Some(Protocol::P2p(peer_id)) => peer_id,
+      _ => bail!("`{address}` must end with `/p2p/<peer id>`"),
+    };
+
+    if !addr.iter().any(|p| matches!(p, Protocol::Tcp(_))) {
+      bail!("`{address}` has no tcp port");
+    }
+
+    if key_seed.is_some() && keyfile.is_some() {
+      bail!("`key_seed` and `keyfile` are mutually exclusive");
+    }
+
+    if let Some(seed) = key_seed {
+      let derived = PeerId::from(generate_ed25519(seed).public());
+      if derived != peer_id {
+        bail!("key_seed {seed} yields peer id {derived}, but `{address}` names {peer_id}");
+      }
+    }
+
+    Ok(Self {
+      peer_id,
+      address: addr,
+      key_seed,
+      keyfile,
+      ws_port: None,
+      listen_addrs: Vec::new(),
+      external_addrs: Vec::new(),
+    })
+  }
+
+  pub fn peer_id(&self) -> &PeerId {
+    &self.peer_id
+  }
+
+  /// Transport address of the node, without the `/p2p/<peer id>` suffix.
+  pub fn address(&self) -> &Multiaddr {
+    &self.address
+  }
+
+  /// Dialable address of the node, including the `/p2p/<peer id>` suffix.
+  pub fn dial_addr(&self) -> Multiaddr {
+    self.address.clone().with(Protocol::P2p(self.peer_id))
+  }
+
+  /// Addresses to dial the node on, QUIC first when built with the `quic` feature.
+  pub fn dial_addrs(&self) -> Vec<Multiaddr> {
+    vec![
+      #[cfg(feature = "quic")]
+      self.quic_dial_addr(),
+      self.dial_addr(),
+    ]
+  }
+
+  /// Bootstrap nodes listen for QUIC on the UDP port matching their TCP one.
+  #[cfg(feature = "quic")]
+  fn quic_dial_addr(&self) -> Multiaddr {
+    self
+      .address
+      .iter()
+      .map(|p| match p {
+        Protocol::Tcp(port) => Protocol::Udp(port),
+        p => p,
+      })
+      .collect::<Multiaddr>()
+      .with(Protocol::QuicV1)
+      .with(Protocol::P2p(self.peer_id))
+  }
+
+  pub fn port(&self) -> u16 {
+    self
+      .address
+      .iter()
+      .find_map(|p| match p {
+        Protocol::Tcp(port) => Some(port),
+        _ => None,
+      })
+      .expect("validated when parsed")
+  }
+
+  pub fn key_seed(&self) -> Option<u8> {
+    self.key_seed
+  }
+
+  /// Keyfile holding this node's identity, used when running it in bootstrap mode.
+  pub fn keyfile(&self) -> Option<&Path> {
+    self.keyfile.as_deref()
+  }
+
+  /// Port browser clients connect to over WebSocket, when this node runs in bootstrap mode.
+  pub fn ws_port(&self) -> Option<u16> {
+    self.ws_port
+  }
+
+  /// Addresses to listen on in bootstrap mode, all IPv4 interfaces on `port` when empty.
+  pub fn listen_addrs(&self) -> &[Multiaddr] {
+    &self.listen_addrs
+  }
+
+  /// Addresses announced in bootstrap mode on top of the observed ones.
+  pub fn external_addrs(&self) -> &[Multiaddr] {
+    &self.external_addrs
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipsubSettings {
+  heartbeat_interval_secs: u64,
+  idle_timeout_secs: u64,
+  /// Largest payload delivered and forwarded, in bytes.
+  max_message_size: usize,
+  /// Messages a peer may publish to one room each minute, the rest is dropped.
+  max_messages_per_minute: u32,
+  /// How far ahead of our clock a message may be dated.
+  max_clock_skew_secs: u64,
+  /// Older messages are dropped, peers catch up on them through sync instead.
+  max_message_age_secs: u64,
+}
+
+impl Default for GossipsubSettings {
+  fn default() -> Self {
+    Self {
+      heartbeat_interval_secs: DEFAULT_GOSSIPSUB_HEARTBEAT_INTERVAL_SECS,
+      idle_timeout_secs: DEFAULT_GOSSIPSUB_IDLE_TIMEOUT_SECS,
+      max_message_size: DEFAULT_GOSSIPSUB_MAX_MESSAGE_SIZE,
+      max_messages_per_minute: DEFAULT_GOSSIPSUB_MAX_MESSAGES_PER_MINUTE,
+      max_clock_skew_secs: DEFAULT_GOSSIPSUB_MAX_CLOCK_SKEW_SECS,
+      max_message_age_secs: DEFAULT_GOSSIPSUB_MAX_MESSAGE_AGE_SECS,
+    }
+  }
+}
+
+impl GossipsubSettings {
+  fn validate(&self) -> Result<()> {
+    if self.heartbeat_interval_secs == 0 {
+      bail!("heartbeat_interval_secs must be greater than 0");
+    }
+    if self.max_message_size == 0 {
+      bail!("max_message_size must be greater than 0");
+    }
+    if self.max_messages_per_minute == 0 {
+      bail!("max_messages_per_minute must be greater than 0");
+    }
+    if self.max_message_age_secs == 0 {
+      bail!("max_message_age_secs must be greater than 0");
+    }
+    Ok(())
+  }
+
+  pub fn heartbeat_interval(&self) -> Duration {
+    Duration::from_secs(self.heartbeat_interval_secs)
+  }
+
+  pub fn idle_timeout(&self) -> Duration {
+    Duration::from_secs(self.idle_timeout_secs)
+  }
+
+  pub fn max_message_size(&self) -> usize {
+    self.max_message_size
+  }
+
+  pub fn max_messages_per_minute(&self) -> u32 {
+    self.max_messages_per_minute
+  }
+
+  pub fn max_clock_skew(&self) -> Duration {
+    Duration::from_secs(self.max_clock_skew_secs)
+  }
+
+  pub fn max_message_age(&self) -> Duration {
+    Duration::from_secs(self.max_message_age_secs)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KademliaSettings {
+  query_timeout_secs: u64,
+  connection_idle_timeout_secs: u64,
+  record_ttl_secs: u64,
+  provider_record_ttl_secs: u64,
+  bootstrap_interval_secs: u64,
+  /// Delay before retrying a failed bootstrap, doubled on each failure up to the interval.
+  bootstrap_retry_secs: u64,
+  /// Lifetime of a nickname claim, renewed halfway through while the node runs.
+  nickname_ttl_secs: u64,
+}
+
+impl Default for KademliaSettings {
+  fn default() -> Self {
+    Self {
+      query_timeout_secs: DEFAULT_KADEMLIA_QUERY_TIMEOUT_SECS,
+      connection_idle_timeout_secs: DEFAULT_KADEMLIA_CONNECTION_IDLE_TIMEOUT_SECS,
+      record_ttl_secs: DEFAULT_KADEMLIA_RECORD_TTL_SECS,
+      provider_record_ttl_secs: DEFAULT_KADEMLIA_PROVIDER_RECORD_TTL_SECS,
+      bootstrap_interval_secs: DEFAULT_KADEMLIA_BOOTSTRAP_INTERVAL_SECS,
+      bootstrap_retry_secs: DEFAULT_KADEMLIA_BOOTSTRAP_RETRY_SECS,
+      nickname_ttl_secs: DEFAULT_KADEMLIA_NICKNAME_TTL_SECS,
+    }
+  }
+}
+
+impl KademliaSettings {
+  fn validate(&self) -> Result<()> {
+    if self.query_timeout_secs == 0 {
+      bail!("query_timeout_secs must be greater than 0");
+    }
+    if self.bootstrap_interval_secs == 0 {
+      bail!("bootstrap_interval_secs must be greater than 0");
+    }
+    if self.bootstrap_retry_secs == 0 {
+      bail!("bootstrap_retry_secs must be greater than 0");
+    }
+    if self.nickname_ttl_secs < 2 {
+      bail!("nickname_ttl_secs must be at least 2");
+    }
+    if self.nickname_ttl_secs > NICKNAME_CLAIM_MAX_AGE_SECS / 2 {
+      bail!(
+        "nickname_ttl_secs must be at most {}",
+        NICKNAME_CLAIM_MAX_AGE_SECS / 2
+      );
+    }
+    Ok(())
+  }
+
+  pub fn query_timeout(&self) -> Duration {
+    Duration::from_secs(self.query_timeout_secs)
+  }
+
+  pub fn connection_idle_timeout(&self) -> Duration {
+    Duration::from_secs(self.connection_idle_timeout_secs)
+  }
+
+  pub fn record_ttl(&self) -> Duration {
+    Duration::from_secs(self.record_ttl_secs)
+  }
+
+  pub fn provider_record_ttl(&self) -> Duration {
+    Duration::from_secs(self.provider_record_ttl_secs)
+  }
+
+  pub fn bootstrap_interval(&self) -> Duration {
+    Duration::from_secs(self.bootstrap_interval_secs)
+  }
+
+  pub fn bootstrap_retry(&self) -> Duration {
+    Duration::from_secs(self.bootstrap_retry_secs)
+  }
+
+  pub fn nickname_ttl(&self) -> Duration {
+    Duration::from_secs(self.nickname_ttl_secs)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentifySettings {
+  protocol_version: String,
+}
+
+impl Default for IdentifySettings {
+  fn default() -> Self {
+    Self {
+      protocol_version: DEFAULT_IDENTIFY_PROTOCOL_VERSION.to_owned(),
+    }
+  }
+}
+
+impl IdentifySettings {
+  fn validate(&self) -> Result<()> {
+    if !self.protocol_version.starts_with('/') {
+      bail!("protocol_version must start with `/`");
+    }
+    Ok(())
+  }
+
+  pub fn protocol_version(&self) -> &str {
+    &self.protocol_version
+  }
+}
+
+/// How the actor supervisor restarts a failed peer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorSettings {
+  /// Restart limit per peer; unlimited when omitted, never restarted when 0.
+  max_restarts: Option<usize>,
+  /// Delay before the first restart; restarts immediately when 0.
+  backoff_secs: u64,
+  /// Growth factor of the delay between successive restarts.
+  backoff_multiplier: f64,
+}
+
+impl Default for SupervisorSettings {
+  fn default() -> Self {
+    Self {
+      max_restarts: Some(DEFAULT_SUPERVISOR_MAX_RESTARTS),
+      backoff_secs: DEFAULT_SUPERVISOR_BACKOFF_SECS,
+      backoff_multiplier: DEFAULT_SUPERVISOR_BACKOFF_MULTIPLIER,
+    }
+  }
+}
+
+impl SupervisorSettings {
+  fn validate(&self) -> Result<()> {
+    if self.backoff_multiplier.is_nan() || self.backoff_multiplier < 1.0 {
+      bail!("backoff_multiplier must be at least 1.0");
+    }
+    Ok(())
+  }
+
+  pub fn max_restarts(&self) -> Option<usize> {
+    self.max_restarts
+  }
+
+  pub fn backoff(&self) -> Duration {
+    Duration::from_secs(self.backoff_secs)
+  }
+
+  pub fn backoff_multiplier(&self) -> f64 {
+    self.backoff_multiplier
+  }
+}
+
+/// Mailbox run by bootstrap nodes, holding direct messages for offline peers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailboxSettings {
+  enabled: bool,
+  /// How long a message is held before it is dropped unread.
+  ttl_secs: u64,
+  /// How often messages past their TTL are dropped.
+  expire_interval_secs: u64,
+  /// Quota of each recipient.
+  max_messages_per_peer: usize,
+  max_bytes_per_peer: usize,
+  /// Quota of each depositor, across recipients.
+  max_messages_per_sender: usize,
+  max_bytes_per_sender: usize,
+  /// Quota of the whole mailbox.
+  max_messages: usize,
+  max_bytes: usize,
+}
+
+impl Default for MailboxSettings {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      ttl_secs: DEFAULT_MAILBOX_TTL_SECS,
+      expire_interval_secs: DEFAULT_MAILBOX_EXPIRE_INTERVAL_SECS,
+      max_messages_per_peer: DEFAULT_MAILBOX_MAX_MESSAGES_PER_PEER,
+      max_bytes_per_peer: DEFAULT_MAILBOX_MAX_BYTES_PER_PEER,
+      max_messages_per_sender: DEFAULT_MAILBOX_MAX_MESSAGES_PER_SENDER,
+      max_bytes_per_sender: DEFAULT_MAILBOX_MAX_BYTES_PER_SENDER,
+      max_messages: DEFAULT_MAILBOX_MAX_MESSAGES,
+      max_bytes: DEFAULT_MAILBOX_MAX_BYTES,
+    }
+  }
+}
+
+impl MailboxSettings {
+  fn validate(&self) -> Result<()> {
+    if self.ttl_secs == 0 {
+      bail!("ttl_secs must be greater than 0");
+    }
+    if self.expire_interval_secs == 0 {
+      bail!("expire_interval_secs must be greater than 0");
+    }
+    if self.max_messages_per_peer == 0 {
+      bail!("max_messages_per_peer must be greater than 0");
+    }
+    if self.max_messages_per_sender == 0 {
+      bail!("max_messages_per_sender must be greater than 0");
+    }
+    if self.max_messages == 0 {
+      bail!("max_messages must be greater than 0");
+    }
+    Ok(())
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn ttl(&self) -> Duration {
+    Duration::from_secs(self.ttl_secs)
+  }
+
+  pub fn max_messages_per_peer(&self) -> usize {
+    self.max_messages_per_peer
+  }
+
+  pub fn max_bytes_per_peer(&self) -> usize {
+    self.max_bytes_per_peer
+  }
+
+  pub fn expire_interval(&self) -> Duration {
+    Duration::from_secs(self.expire_interval_secs)
+  }
+
+  pub fn max_messages_per_sender(&self) -> usize {
+    self.max_messages_per_sender
+  }
+
+  pub fn max_bytes_per_sender(&self) -> usize {
+    self.max_bytes_per_sender
+  }
+
+  pub fn max_messages(&self) -> usize {
+    self.max_messages
+  }
+
+  pub fn max_bytes(&self) -> usize {
+    self.max_bytes
+  }
+}
+
+/// How peers probe whether they are reachable, with bootstrap nodes dialing back.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutonatSettings {
+  /// Delay before the first probe, leaving time to learn our observed address.
+  boot_delay_secs: u64,
+  refresh_interval_secs: u64,
+  /// Turn off to probe private addresses, e.g. on a LAN test setup.
+  only_global_ips: bool,
+}
+
+impl Default for AutonatSettings {
+  fn default() -> Self {
+    Self {
+      boot_delay_secs: DEFAULT_AUTONAT_BOOT_DELAY_SECS,
+      refresh_interval_secs: DEFAULT_AUTONAT_REFRESH_INTERVAL_SECS,
+      only_global_ips: true,
+    }
+  }
+}
+
+impl AutonatSettings {
+  fn validate(&self) -> Result<()> {
+    if self.refresh_interval_secs == 0 {
+      bail!("refresh_interval_secs must be greater than 0");
+    }
+    Ok(())
+  }
+
+  pub fn boot_delay(&self) -> Duration {
+    Duration::from_secs(self.boot_delay_secs)
+  }
+
+  pub fn refresh_interval(&self) -> Duration {
+    Duration::from_secs(self.refresh_interval_secs)
+  }
+
+  pub fn only_global_ips(&self) -> bool {
+    self.only_global_ips
+  }
+}
+
+/// How long a peer waits on each startup phase before moving on.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartupSettings {
+  /// Time for the listeners to report their addresses.
+  listen_timeout_secs: u64,
+  /// Time for each bootnode to connect and exchange Identify, before trying the next one.
+  bootnode_timeout_secs: u64,
+}
+
+impl Default for StartupSettings {
+  fn default() -> Self {
+    Self {
+      listen_timeout_secs: DEFAULT_STARTUP_LISTEN_TIMEOUT_SECS,
+      bootnode_timeout_secs: DEFAULT_STARTUP_BOOTNODE_TIMEOUT_SECS,
+    }
+  }
+}
+
+impl StartupSettings {
+  fn validate(&self) -> Result<()> {
+    if self.listen_timeout_secs == 0 {
+      bail!("listen_timeout_secs must be greater than 0");
+    }
+    if self.bootnode_timeout_secs == 0 {
+      bail!("bootnode_timeout_secs must be greater than 0");
+    }
+    Ok(())
+  }
+
+  pub fn listen_timeout(&self) -> Duration {
+    Duration::from_secs(self.listen_timeout_secs)
+  }
+
+  pub fn bootnode_timeout(&self) -> Duration {
+    Duration::from_secs(self.bootnode_timeout_secs)
+  }
+}
+
+/// Relay reservations a peer holds while AutoNAT finds it unreachable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySettings {
+  /// Relays reserved on at once, so losing one leaves the peer reachable.
+  reservations: usize,
+}
+
+impl Default for RelaySettings {
+  fn default() -> Self {
+    Self {
+      reservations: DEFAULT_RELAY_RESERVATIONS,
+    }
+  }
+}
+
+impl RelaySettings {
+  fn validate(&self) -> Result<()> {
+    if self.reservations == 0 {
+      bail!("reservations must be greater than 0");
+    }
+    Ok(())
+  }
+
+  pub fn reservations(&self) -> usize {
+    self.reservations
+  }
+}
+
+/// Presence heartbeats a peer sends to the rooms it's in, and how they're read.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceSettings {
+  heartbeat_interval_secs: u64,
+  /// Console inactivity after which we're shown as away.
+  away_after_secs: u64,
+  /// Silence after which a peer is shown as offline, a few heartbeats long.
+  offline_after_secs: u64,
+}
+
+impl Default for PresenceSettings {
+  fn default() -> Self {
+    Self {
+      heartbeat_interval_secs: DEFAULT_PRESENCE_HEARTBEAT_INTERVAL_SECS,
+      away_after_secs: DEFAULT_PRESENCE_AWAY_AFTER_SECS,
+      offline_after_secs: DEFAULT_PRESENCE_OFFLINE_AFTER_SECS,
+    }
+  }
+}
+
+impl PresenceSettings {
+  fn validate(&self) -> Result<()> {
+    if self.heartbeat_interval_secs == 0 {
+      bail!("heartbeat_interval_secs must be greater than 0");
+    }
+    if self.away_after_secs == 0 {
+      bail!("away_after_secs must be greater than 0");
+    }
+    if self.offline_after_secs <= self.heartbeat_interval_secs {
+      bail!("offline_after_secs must be greater than heartbeat_interval_secs");
+    }
+    Ok(())
+  }
+
+  pub fn heartbeat_interval(&self) -> Duration {
+    Duration::from_secs(self.heartbeat_interval_secs)
+  }
+
+  pub fn away_after(&self) -> Duration {
+    Duration::from_secs(self.away_after_secs)
+  }
+
+  pub fn offline_after(&self) -> Duration {
+    Duration::from_secs(self.offline_after_secs)
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNetworkConfig {
+  #[serde(default)]
+  bootnodes: Vec<RawBootNode>,
+  #[serde(default)]
+  peer: RawPeerSection,
+  #[serde(default)]
+  gossipsub: GossipsubSettings,
+  #[serde(default)]
+  kademlia: KademliaSettings,
+  #[serde(default)]
+  identify: IdentifySettings,
+  #[serde(default)]
+  supervisor: SupervisorSettings,
+  #[serde(default)]
+  mailbox: MailboxSettings,
+  #[serde(default)]
+  autonat: AutonatSettings,
+  #[serde(default)]
+  startup: StartupSettings,
+  #[serde(default)]
+  relay: RelaySettings,
+  #[serde(default)]
+  presence: PresenceSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBootNode {
+  address: String,
+  key_seed: Option<u8>,
+  keyfile: Option<PathBuf>,
+  ws_port: Option<u16>,
+  #[serde(default)]
+  listen_addrs: Vec<String>,
+  #[serde(default)]
+  external_addrs: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPeerSection {
+  #[serde(default)]
+  listen_addrs: Vec<String>,
+  #[serde(default)]
+  external_addrs: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bootnode() -> String {
+    format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", PeerId::random())
+  }
+
+  /// A config with a single boot node and `sections` appended.
+  fn config(sections: &str) -> Result<NetworkConfig> {
+    NetworkConfig::from_toml(&format!(
+      "[[bootnodes]]\naddress = \"{}\"\n\n{sections}",
+      bootnode()
+    ))
+  }
+
+  fn rejection(result: Result<NetworkConfig>) -> String {
+    format!("{:#}", result.expect_err("config should be rejected"))
+  }
+
+  #[test]
+  fn defaults_are_valid() {
+    let config = config("").unwrap();
+    assert_eq!(config.bootnodes().len(), 1);
+    assert_eq!(config.listen_addrs(), default_listen_addrs().as_slice());
+  }
+
+  #[test]
+  fn invalid_settings_are_rejected() {
+    let cases = [
+      (
+        "[gossipsub]\nheartbeat_interval_secs = 0",
+        "gossipsub: heartbeat_interval_secs",
+      ),
+      (
+        "[gossipsub]\nmax_message_size = 0",
+        "gossipsub: max_message_size",
+      ),
+      (
+        "[gossipsub]\nmax_messages_per_minute = 0",
+        "gossipsub: max_messages_per_minute",
+      ),
+      (
+        "[gossipsub]\nmax_message_age_secs = 0",
+        "gossipsub: max_message_age_secs",
+      ),
+      (
+        "[kademlia]\nquery_timeout_secs = 0",
+        "kademlia: query_timeout_secs",
+      ),
+      (
+        "[kademlia]\nbootstrap_interval_secs = 0",
+        "kademlia: bootstrap_interval_secs",
+      ),
+      (
+        "[kademlia]\nbootstrap_retry_secs = 0",
+        "kademlia: bootstrap_retry_secs",
+      ),
+      (
+        "[kademlia]\nnickname_ttl_secs = 1",
+        "kademlia: nickname_ttl_secs",
+      ),
+      (
+        "[kademlia]\nnickname_ttl_secs = 100000000",
+        "kademlia: nickname_ttl_secs",
+      ),
+      (
+        "[identify]\nprotocol_version = \"chat\"",
+        "identify: protocol_version",
+      ),
+      (
+        "[supervisor]\nbackoff_multiplier = 0.5",
+        "supervisor: backoff_multiplier",
+      ),
+      ("[mailbox]\nttl_secs = 0", "mailbox: ttl_secs"),
+      (
+        "[mailbox]\nexpire_interval_secs = 0",
+        "mailbox: expire_interval_secs",
+      ),
+      (
+        "[mailbox]\nmax_messages_per_peer = 0",
+        "mailbox: max_messages_per_peer",
+      ),
+      (
+        "[mailbox]\nmax_messages_per_sender = 0",
+        "mailbox: max_messages_per_sender",
+      ),
+      ("[mailbox]\nmax_messages = 0", "mailbox: max_messages"),
+      (
+        "[autonat]\nrefresh_interval_secs = 0",
+        "autonat: refresh_interval_secs",
+      ),
+      (
+        "[startup]\nlisten_timeout_secs = 0",
+        "startup: listen_timeout_secs",
+      ),
+      (
+        "[startup]\nbootnode_timeout_secs = 0",
+        "startup: bootnode_timeout_secs",
+      ),
+      ("[relay]\nreservations = 0", "relay: reservations"),
+      (
+        "[presence]\nheartbeat_interval_secs = 0",
+        "presence: heartbeat_interval_secs",
+      ),
+      (
+        "[presence]\naway_after_secs = 0",
+        "presence: away_after_secs",
+      ),
+      (
+        "[presence]\nheartbeat_interval_secs = 60\noffline_after_secs = 60",
+        "presence: offline_after_secs",
+      ),
+      (
+        "[peer]\nlisten_addrs = [\"not an address\"]",
+        "peer.listen_addrs",
+      ),
+      ("[gossipsub]\nunknown = 1", "unknown field"),
+    ];
+    for (section, expected) in cases {
+      let error = rejection(config(section));
+      assert!(
+        error.contains(expected),
+        "`{section}` failed with `{error}`"
+      );
+    }
+  }
+
+  #[test]
+  fn boot_nodes_are_checked() {
+    assert!(rejection(NetworkConfig::from_toml("")).contains("at least one entry"));
+
+    let node = |fields: &str| NetworkConfig::from_toml(&format!("[[bootnodes]]\n{fields}"));
+    let peer_id = PeerId::random();
+    let valid = format!("address = \"/ip4/127.0.0.1/tcp/4001/p2p/{peer_id}\"");
+    let cases = [
+      (
+        format!("address = \"/ip4/127.0.0.1/tcp/x/p2p/{peer_id}\""),
+        "not a valid multiaddr",
+      ),
+      (
+        "address = \"/ip4/127.0.0.1/tcp/4001\"".to_owned(),
+        "must end with `/p2p/<peer id>`",
+      ),
+      (
+        format!("address = \"/ip4/127.0.0.1/p2p/{peer_id}\""),
+        "has no tcp port",
+      ),
+      (
+        format!("{valid}\nkey_seed = 1"),
+        "key_seed 1 yields peer id",
+      ),
+      (
+        format!("{valid}\nkey_seed = 1\nkeyfile = \"key\""),
+        "mutually exclusive",
+      ),
+      (format!("{valid}\nws_port = 4001"), "ws_port must differ"),
+      (
+        format!("{valid}\n\n[[bootnodes]]\n{valid}"),
+        "listed more than once",
+      ),
+    ];
+    for (fields, expected) in cases {
+      let error = rejection(node(&fields));
+      assert!(error.contains(expected), "`{fields}` failed with `{error}`");
+    }
+  }
+
+  #[test]
+  fn boot_nodes_keep_their_address_and_peer_id() {
+    let seeded = PeerId::from(generate_ed25519(7).public());
+    let address = format!("/ip4/127.0.0.1/tcp/4001/p2p/{seeded}");
+    let config = NetworkConfig::from_toml(&format!(
+      "[[bootnodes]]\naddress = \"{address}\"\nkey_seed = 7"
+    ))
+    .unwrap();
+    let node = &config.bootnodes()[0];
+    assert_eq!(node.peer_id(), &seeded);
+    assert_eq!(node.key_seed(), Some(7));
+    assert_eq!(node.dial_addr().to_string(), address);
+    assert_eq!(node.port(), 4001);
+  }
+}
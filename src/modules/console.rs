@@ -0,0 +1,399 @@
+use std::fmt::{self, Display};
+
+use libp2p::{Multiaddr, PeerId};
+
+use crate::constants::HISTORY_MAX_MINUTES;
+
+pub const HELP: &str = "\
+Commands:
+  /join <room>          join a room and make it the current one
+  /leave [room]         leave a room, the current one by default
+  /rooms                list joined rooms
+  /peers                list connected peers
+  /dial <multiaddr>     dial a peer
+  /nick <name>          claim a nickname, unless another peer holds it
+  /msg <peer> <text>    send an encrypted direct message to a peer id or nickname
+  /private <room>       create an encrypted room that only invited peers can read
+  /invite <peer>        hand the key of the current private room to a peer
+  /accept <room> <peer> join a private room the peer invited you to
+  /kick <peer>          remove a peer from the current private room and rotate its key
+  /history [minutes]    show stored messages of the current room, the latest ones by default
+  /whoami               show your peer id, nickname and current room
+  /who [room]           show who is online in a room, the current one by default
+  /discover [room]      find members of a room on the DHT and connect to them
+  /dht                  show the routing table size, bootstrap state and pending DHT queries
+  /quit                 stop the node
+  /help                 show this help
+Any other line is sent to the current room. Start it with `//` to send a line beginning with `/`.";
+
+/// A line typed on the console.
+#[derive(Debug, PartialEq)]
+pub enum ConsoleInput {
+  Message(String),
+  Command(ConsoleCommand),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConsoleCommand {
+  Join(String),
+  Leave(Option<String>),
+  Rooms,
+  Peers,
+  Dial(Multiaddr),
+  Nick(String),
+  Msg {
+    peer: PeerRef,
+    content: String,
+  },
+  Private(String),
+  Invite(PeerId),
+  Accept {
+    room: String,
+    owner: PeerId,
+  },
+  Kick(PeerId),
+  /// Stored messages of the current room from the last given minutes, or the latest ones.
+  History(Option<i64>),
+  WhoAmI,
+  /// Presence of the members of a room, the current one by default.
+  Who(Option<String>),
+  /// Members of a room, the current one by default, found through the DHT.
+  Discover(Option<String>),
+  Dht,
+  Quit,
+  Help,
+}
+
+/// A peer as typed on the console, by peer id or by registered nickname.
+#[derive(Debug, PartialEq)]
+pub enum PeerRef {
+  Id(PeerId),
+  Nickname(String),
+}
+
+impl Display for PeerRef {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PeerRef::Id(peer_id) => write!(f, "{peer_id}"),
+      PeerRef::Nickname(nickname) => write!(f, "{nickname}"),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConsoleError {
+  UnknownCommand(String),
+  MissingArgument {
+    command: &'static str,
+    argument: &'static str,
+  },
+  InvalidArgument {
+    command: &'static str,
+    argument: String,
+    reason: String,
+  },
+  UnexpectedArgument {
+    command: &'static str,
+    argument: String,
+  },
+}
+
+impl Display for ConsoleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConsoleError::UnknownCommand(command) => {
+        write!(f, "unknown command `/{command}`, type /help for a list")
+      }
+      ConsoleError::MissingArgument { command, argument } => {
+        write!(f, "/{command} needs a <{argument}>, type /help for usage")
+      }
+      ConsoleError::InvalidArgument {
+        command,
+        argument,
+        reason,
+      } => write!(f, "/{command}: `{argument}` {reason}"),
+      ConsoleError::UnexpectedArgument { command, argument } => {
+        write!(f, "/{command} takes no argument `{argument}`")
+      }
+    }
+  }
+}
+
+impl std::error::Error for ConsoleError {}
+
+/// Splits a console line into a chat message or a slash command.
+pub fn parse(line: &str) -> Result<ConsoleInput, ConsoleError> {
+  let line = line.trim_end();
+
+  if let Some(escaped) = line.strip_prefix("//") {
+    return Ok(ConsoleInput::Message(format!("/{escaped}")));
+  }
+  let rest = match line.strip_prefix('/') {
+    Some(rest) => rest,
+    None => return Ok(ConsoleInput::Message(line.to_owned())),
+  };
+
+  let (name, args) = match rest.split_once(char::is_whitespace) {
+    Some((name, args)) => (name, args.trim()),
+    None => (rest, ""),
+  };
+
+  let command = match name {
+    "join" => ConsoleCommand::Join(room("join", args)?),
+    "leave" => match args.is_empty() {
+      true => ConsoleCommand::Leave(None),
+      false => ConsoleCommand::Leave(Some(room("leave", args)?)),
+    },
+    "rooms" => no_args("rooms", args, ConsoleCommand::Rooms)?,
+    "peers" => no_args("peers", args, ConsoleCommand::Peers)?,
+    "dial" => {
+      let addr = required("dial", "multiaddr", args)?;
+      ConsoleCommand::Dial(addr.parse().map_err(|e| ConsoleError::InvalidArgument {
+        command: "dial",
+        argument: addr.to_owned(),
+        reason: format!("is not a valid multiaddr: {e}"),
+      })?)
+    }
+    "nick" => {
+      let nick = required("nick", "name", args)?;
+      if nick.split_whitespace().count() > 1 {
+        return Err(ConsoleError::InvalidArgument {
+          command: "nick",
+          argument: nick.to_owned(),
+          reason: "must not contain spaces".to_owned(),
+        });
+      }
+      ConsoleCommand::Nick(nick.to_owned())
+    }
+    "msg" => {
+      let (peer, content) = match args.split_once(char::is_whitespace) {
+        Some((peer, content)) => (peer, content.trim()),
+        None => (required("msg", "peer", args)?, ""),
+      };
+      ConsoleCommand::Msg {
+        peer: peer_ref("msg", peer)?,
+        content: required("msg", "text", content)?.to_owned(),
+      }
+    }
+    "private" => ConsoleCommand::Private(room("private", args)?),
+    "invite" => ConsoleCommand::Invite(peer_id("invite", args)?),
+    "accept" => {
+      let (room_name, owner) = match args.split_once(char::is_whitespace) {
+        Some((room_name, owner)) => (room_name, owner.trim()),
+        None => (args, ""),
+      };
+      ConsoleCommand::Accept {
+        room: room("accept", room_name)?,
+        owner: peer_id("accept", owner)?,
+      }
+    }
+    "kick" => ConsoleCommand::Kick(peer_id("kick", args)?),
+    "history" => match args.is_empty() {
+      true => ConsoleCommand::History(None),
+      false => match args.parse::<i64>() {
+        Ok(minutes) if (1..=HISTORY_MAX_MINUTES).contains(&minutes) => {
+          ConsoleCommand::History(Some(minutes))
+        }
+        _ => {
+          return Err(ConsoleError::InvalidArgument {
+            command: "history",
+            argument: args.to_owned(),
+            reason: format!("must be a number of minutes from 1 to {HISTORY_MAX_MINUTES}"),
+          })
+        }
+      },
+    },
+    "whoami" => no_args("whoami", args, ConsoleCommand::WhoAmI)?,
+    "who" => match args.is_empty() {
+      true => ConsoleCommand::Who(None),
+      false => ConsoleCommand::Who(Some(room("who", args)?)),
+    },
+    "discover" => match args.is_empty() {
+      true => ConsoleCommand::Discover(None),
+      false => ConsoleCommand::Discover(Some(room("discover", args)?)),
+    },
+    "dht" => no_args("dht", args, ConsoleCommand::Dht)?,
+    "quit" => no_args("quit", args, ConsoleCommand::Quit)?,
+    "help" => no_args("help", args, ConsoleCommand::Help)?,
+    name => return Err(ConsoleError::UnknownCommand(name.to_owned())),
+  };
+
+  Ok(ConsoleInput::Command(command))
+}
+
+fn required<'a>(
+  command: &'static str,
+  argument: &'static str,
+  args: &'a str,
+) -> Result<&'a str, ConsoleError> {
+  match args.is_empty() {
+    true => Err(ConsoleError::MissingArgument { command, argument }),
+    false => Ok(args),
+  }
+}
+
+fn room(command: &'static str, args: &str) -> Result<String, ConsoleError> {
+  let room = required(command, "room", args)?;
+  if room.split_whitespace().count() > 1 {
+    return Err(ConsoleError::InvalidArgument {
+      command,
+      argument: room.to_owned(),
+      reason: "must not contain spaces".to_owned(),
+    });
+  }
+  Ok(room.to_owned())
+}
+
+fn peer_id(command: &'static str, args: &str) -> Result<PeerId, ConsoleError> {
+  let peer = required(command, "peer", args)?;
+  peer.parse().map_err(|_| ConsoleError::InvalidArgument {
+    command,
+    argument: peer.to_owned(),
+    reason: "is not a valid peer id".to_owned(),
+  })
+}
+
+/// A peer id, or else a nickname, optionally prefixed with `@`.
+fn peer_ref(command: &'static str, args: &str) -> Result<PeerRef, ConsoleError> {
+  let peer = required(command, "peer", args)?;
+  match peer.parse() {
+    Ok(peer_id) => Ok(PeerRef::Id(peer_id)),
+    Err(_) => Ok(PeerRef::Nickname(
+      peer.strip_prefix('@').unwrap_or(peer).to_owned(),
+    )),
+  }
+}
+
+fn no_args(
+  command: &'static str,
+  args: &str,
+  parsed: ConsoleCommand,
+) -> Result<ConsoleCommand, ConsoleError> {
+  match args.is_empty() {
+    true => Ok(parsed),
+    false => Err(ConsoleError::UnexpectedArgument {
+      command,
+      argument: args.to_owned(),
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn command(line: &str) -> ConsoleCommand {
+    match parse(line) {
+      Ok(ConsoleInput::Command(command)) => command,
+      other => panic!("{line:?} parsed as {other:?}"),
+    }
+  }
+
+  #[test]
+  fn plain_lines_are_messages() {
+    assert_eq!(
+      parse("hello  \n"),
+      Ok(ConsoleInput::Message("hello".to_owned()))
+    );
+    assert_eq!(
+      parse("//join"),
+      Ok(ConsoleInput::Message("/join".to_owned()))
+    );
+  }
+
+  #[test]
+  fn rooms_are_single_words() {
+    assert_eq!(
+      command("/join rust"),
+      ConsoleCommand::Join("rust".to_owned())
+    );
+    assert_eq!(command("/leave"), ConsoleCommand::Leave(None));
+    assert_eq!(
+      parse("/join"),
+      Err(ConsoleError::MissingArgument {
+        command: "join",
+        argument: "room"
+      })
+    );
+    assert!(matches!(
+      parse("/join two words"),
+      Err(ConsoleError::InvalidArgument {
+        command: "join",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn msg_takes_a_peer_id_or_nickname() {
+    let peer_id = PeerId::random();
+    assert_eq!(
+      command(&format!("/msg {peer_id} hi there")),
+      ConsoleCommand::Msg {
+        peer: PeerRef::Id(peer_id),
+        content: "hi there".to_owned()
+      }
+    );
+    assert_eq!(
+      command("/msg @alice hi"),
+      ConsoleCommand::Msg {
+        peer: PeerRef::Nickname("alice".to_owned()),
+        content: "hi".to_owned()
+      }
+    );
+    assert_eq!(
+      parse("/msg alice"),
+      Err(ConsoleError::MissingArgument {
+        command: "msg",
+        argument: "text"
+      })
+    );
+  }
+
+  #[test]
+  fn accept_takes_a_room_and_its_owner() {
+    let owner = PeerId::random();
+    assert_eq!(
+      command(&format!("/accept secret {owner}")),
+      ConsoleCommand::Accept {
+        room: "secret".to_owned(),
+        owner
+      }
+    );
+    assert!(matches!(
+      parse("/accept secret not-a-peer"),
+      Err(ConsoleError::InvalidArgument {
+        command: "accept",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn history_takes_positive_minutes() {
+    assert_eq!(command("/history"), ConsoleCommand::History(None));
+    assert_eq!(command("/history 15"), ConsoleCommand::History(Some(15)));
+    assert!(parse("/history 0").is_err());
+    assert!(parse("/history soon").is_err());
+    assert!(parse("/history 9999999999999").is_err());
+    assert_eq!(
+      command(&format!("/history {HISTORY_MAX_MINUTES}")),
+      ConsoleCommand::History(Some(HISTORY_MAX_MINUTES))
+    );
+  }
+
+  #[test]
+  fn unknown_commands_and_extra_arguments_fail() {
+    assert_eq!(
+      parse("/frobnicate"),
+      Err(ConsoleError::UnknownCommand("frobnicate".to_owned()))
+    );
+    assert_eq!(
+      parse("/quit now"),
+      Err(ConsoleError::UnexpectedArgument {
+        command: "quit",
+        argument: "now".to_owned()
+      })
+    );
+  }
+}
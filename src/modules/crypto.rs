@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+use crate::constants::{
+  DIRECT_KEY_INFO, INVITES_MAX, INVITES_PER_OWNER_MAX, INVITE_TTL_SECS, ROOM_KEYS_KEPT,
+};
+
+pub type SymmetricKey = [u8; 32];
+
+pub const NONCE_LEN: usize = 12;
+
+/// Derives the key shared by `local` and `remote` for direct messages.
+///
+/// Both Ed25519 identities are mapped to X25519, so either side derives the same key from its own
+/// secret and the other's peer id, without any handshake.
+pub fn direct_key(local: &Keypair, remote: &PeerId) -> Result<SymmetricKey> {
+  let secret = x25519_secret(local)?;
+  let shared = secret.diffie_hellman(&x25519_public(remote)?);
+
+  let local_id = local.public().to_peer_id().to_bytes();
+  let remote_id = remote.to_bytes();
+  let (first, second) = match local_id < remote_id {
+    true => (local_id, remote_id),
+    false => (remote_id, local_id),
+  };
+  let salt = Sha256::digest([first, second].concat());
+
+  let mut key = SymmetricKey::default();
+  Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+    .expand(DIRECT_KEY_INFO.as_bytes(), &mut key)
+    .expect("32 bytes is a valid HKDF-SHA256 output length");
+  Ok(key)
+}
+
+pub fn generate_key() -> SymmetricKey {
+  rand::random()
+}
+
+/// Encrypts `plaintext`, returning the random nonce and the ciphertext.
+pub fn seal(key: &SymmetricKey, aad: &[u8], plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+  let nonce: [u8; NONCE_LEN] = rand::random();
+  let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+    .encrypt(
+      Nonce::from_slice(&nonce),
+      Payload {
+        msg: plaintext,
+        aad,
+      },
+    )
+    .expect("encryption with a valid key and nonce never fails");
+  (nonce.to_vec(), ciphertext)
+}
+
+pub fn open(key: &SymmetricKey, aad: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+  if nonce.len() != NONCE_LEN {
+    bail!("nonce must be {NONCE_LEN} bytes, got {}", nonce.len());
+  }
+  ChaCha20Poly1305::new(Key::from_slice(key))
+    .decrypt(
+      Nonce::from_slice(nonce),
+      Payload {
+        msg: ciphertext,
+        aad,
+      },
+    )
+    .map_err(|_| anyhow!("authentication failed"))
+}
+
+fn x25519_secret(keypair: &Keypair) -> Result<StaticSecret> {
+  let keypair = match keypair.clone().try_into_ed25519() {
+    Ok(keypair) => keypair,
+    Err(_) => bail!("only Ed25519 identities support encryption"),
+  };
+  let hash = Sha512::digest(keypair.secret().as_ref());
+  let mut bytes = [0u8; 32];
+  bytes.copy_from_slice(&hash[..32]);
+  Ok(StaticSecret::from(bytes))
+}
+
+/// Public key of `peer_id`, for checking what it signed.
+pub fn public_key(peer_id: &PeerId) -> Result<PublicKey> {
+  // Ed25519 peer ids inline the public key as an identity multihash.
+  PublicKey::try_decode_protobuf(peer_id.as_ref().digest())
+    .map_err(|_| anyhow!("{peer_id} does not embed its public key"))
+}
+
+fn x25519_public(peer_id: &PeerId) -> Result<X25519PublicKey> {
+  let public = match public_key(peer_id)?.try_into_ed25519() {
+    Ok(public) => public,
+    Err(_) => bail!("{peer_id} is not an Ed25519 identity"),
+  };
+  let point = CompressedEdwardsY(public.to_bytes())
+    .decompress()
+    .ok_or_else(|| anyhow!("{peer_id} has an invalid Ed25519 public key"))?;
+  Ok(X25519PublicKey::from(point.to_montgomery().to_bytes()))
+}
+
+/// Symmetric keys of a private room.
+#[derive(Debug)]
+pub struct PrivateRoom {
+  owner: PeerId,
+  /// Members the owner hands keys to. Only tracked by the owner.
+  members: BTreeSet<PeerId>,
+  /// Recent keys by epoch, so messages sent just before a rotation still decrypt.
+  keys: BTreeMap<u32, SymmetricKey>,
+}
+
+impl PrivateRoom {
+  pub fn owner(&self) -> &PeerId {
+    &self.owner
+  }
+
+  /// Current epoch and key.
+  pub fn current(&self) -> (u32, &SymmetricKey) {
+    self
+      .keys
+      .iter()
+      .next_back()
+      .map(|(epoch, key)| (*epoch, key))
+      .expect("a private room always has a key")
+  }
+
+  pub fn key(&self, epoch: u32) -> Option<&SymmetricKey> {
+    self.keys.get(&epoch)
+  }
+
+  fn insert_key(&mut self, epoch: u32, key: SymmetricKey) {
+    self.keys.insert(epoch, key);
+    while self.keys.len() > ROOM_KEYS_KEPT {
+      let oldest = *self.keys.keys().next().unwrap();
+      self.keys.remove(&oldest);
+    }
+  }
+
+  /// Replaces the key with a new one, returning its epoch.
+  fn rotate(&mut self) -> u32 {
+    let epoch = self.current().0 + 1;
+    self.insert_key(epoch, generate_key());
+    epoch
+  }
+}
+
+/// Private rooms this node owns or accepted an invite to, keyed by room name.
+#[derive(Debug, Default)]
+pub struct PrivateRooms {
+  rooms: HashMap<String, PrivateRoom>,
+  /// Keys received for rooms we haven't accepted yet, by room and owner.
+  invites: HashMap<(String, PeerId), Invite>,
+}
+
+#[derive(Debug)]
+struct Invite {
+  epoch: u32,
+  key: SymmetricKey,
+  expires_at: Instant,
+}
+
+/// What a key received from a room owner was taken as.
+#[derive(Debug, PartialEq)]
+pub enum Grant {
+  /// A new key of a room we're in.
+  Rotated,
+  /// An invite, only used once accepted.
+  Invited,
+}
+
+impl PrivateRooms {
+  pub fn get(&self, room: &str) -> Option<&PrivateRoom> {
+    self.rooms.get(room)
+  }
+
+  /// Creates a private room owned by `owner`.
+  pub fn create(&mut self, room: &str, owner: PeerId) -> Result<()> {
+    if self.rooms.contains_key(room) {
+      bail!("{room} is already a private room");
+    }
+    let mut private = PrivateRoom {
+      owner,
+      members: BTreeSet::new(),
+      keys: BTreeMap::new(),
+    };
+    private.insert_key(0, generate_key());
+    self.rooms.insert(room.to_owned(), private);
+    Ok(())
+  }
+
+  /// Adds `member` to a room owned by `local`, returning the current epoch and key to send it.
+  pub fn invite(
+    &mut self,
+    room: &str,
+    local: &PeerId,
+    member: PeerId,
+  ) -> Result<(u32, SymmetricKey)> {
+    let private = self.owned_mut(room, local)?;
+    private.members.insert(member);
+    let (epoch, key) = private.current();
+    Ok((epoch, *key))
+  }
+
+  /// Removes `member` from a room owned by `local` and rotates its key.
+  ///
+  /// Returns the new epoch and key along with the remaining members to send them to.
+  pub fn remove_member(
+    &mut self,
+    room: &str,
+    local: &PeerId,
+    member: &PeerId,
+  ) -> Result<(u32, SymmetricKey, Vec<PeerId>)> {
+    let private = self.owned_mut(room, local)?;
+    if !private.members.remove(member) {
+      bail!("{member} is not a member of {room}");
+    }
+    let epoch = private.rotate();
+    let key = *private.key(epoch).unwrap();
+    Ok((epoch, key, private.members.iter().cloned().collect()))
+  }
+
+  /// Stores a key received from `owner`.
+  ///
+  /// Keys of rooms we're not in are kept as an invite, which only turns into a room once
+  /// accepted, so no peer can make a room private behind our back. Invites expire, and each
+  /// owner and all of them together can only leave so many pending.
+  pub fn accept_key(
+    &mut self,
+    room: &str,
+    owner: PeerId,
+    epoch: u32,
+    key: SymmetricKey,
+  ) -> Result<Grant> {
+    match self.rooms.get_mut(room) {
+      Some(private) if private.owner != owner => {
+        bail!(
+          "{owner} sent a key for {room}, which is owned by {}",
+          private.owner
+        )
+      }
+      Some(private) => {
+        private.insert_key(epoch, key);
+        Ok(Grant::Rotated)
+      }
+      None => {
+        self.expire_invites();
+        let expires_at = Instant::now() + Duration::from_secs(INVITE_TTL_SECS);
+        if let Some(invite) = self.invites.get_mut(&(room.to_owned(), owner)) {
+          if invite.epoch <= epoch {
+            *invite = Invite {
+              epoch,
+              key,
+              expires_at,
+            };
+          }
+          return Ok(Grant::Invited);
+        }
+        let from_owner = self.invites.keys().filter(|(_, by)| *by == owner).count();
+        if from_owner >= INVITES_PER_OWNER_MAX {
+          bail!("{owner} already has {from_owner} invites pending");
+        }
+        if self.invites.len() >= INVITES_MAX {
+          bail!("too many invites pending");
+        }
+        self.invites.insert(
+          (room.to_owned(), owner),
+          Invite {
+            epoch,
+            key,
+            expires_at,
+          },
+        );
+        Ok(Grant::Invited)
+      }
+    }
+  }
+
+  /// Turns the invite of `owner` to `room` into a private room.
+  pub fn accept_invite(&mut self, room: &str, owner: &PeerId) -> Result<()> {
+    if self.rooms.contains_key(room) {
+      bail!("{room} is already a private room");
+    }
+    self.expire_invites();
+    let Invite { epoch, key, .. } = self
+      .invites
+      .remove(&(room.to_owned(), *owner))
+      .ok_or_else(|| anyhow!("{owner} didn't invite you to {room}"))?;
+    let mut private = PrivateRoom {
+      owner: *owner,
+      members: BTreeSet::new(),
+      keys: BTreeMap::new(),
+    };
+    private.insert_key(epoch, key);
+    self.rooms.insert(room.to_owned(), private);
+    self.invites.retain(|(invited, _), _| invited != room);
+    Ok(())
+  }
+
+  /// Drops invites that were never accepted.
+  fn expire_invites(&mut self) {
+    let now = Instant::now();
+    self.invites.retain(|_, invite| invite.expires_at > now);
+  }
+
+  pub fn forget(&mut self, room: &str) -> Option<PrivateRoom> {
+    self.rooms.remove(room)
+  }
+
+  fn owned_mut(&mut self, room: &str, local: &PeerId) -> Result<&mut PrivateRoom> {
+    let private = self
+      .rooms
+      .get_mut(room)
+      .ok_or_else(|| anyhow!("{room} is not a private room"))?;
+    if &private.owner != local {
+      bail!("only {} can manage members of {room}", private.owner);
+    }
+    Ok(private)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn direct_key_is_the_same_on_both_sides() {
+    let (alice, bob, carol) = (
+      Keypair::generate_ed25519(),
+      Keypair::generate_ed25519(),
+      Keypair::generate_ed25519(),
+    );
+    let (alice_id, bob_id) = (alice.public().to_peer_id(), bob.public().to_peer_id());
+
+    let key = direct_key(&alice, &bob_id).unwrap();
+    assert_eq!(direct_key(&bob, &alice_id).unwrap(), key);
+    assert_ne!(direct_key(&carol, &bob_id).unwrap(), key);
+    assert_ne!(direct_key(&carol, &alice_id).unwrap(), key);
+  }
+
+  #[test]
+  fn keys_of_unknown_rooms_wait_for_an_accept() {
+    let (owner, other) = (PeerId::random(), PeerId::random());
+    let mut rooms = PrivateRooms::default();
+
+    assert_eq!(
+      rooms.accept_key("secret", owner, 0, [0; 32]).unwrap(),
+      Grant::Invited
+    );
+    assert_eq!(
+      rooms.accept_key("secret", owner, 2, [2; 32]).unwrap(),
+      Grant::Invited
+    );
+    assert_eq!(
+      rooms.accept_key("secret", owner, 1, [1; 32]).unwrap(),
+      Grant::Invited
+    );
+    assert!(rooms.get("secret").is_none());
+    assert!(rooms.accept_invite("secret", &other).is_err());
+
+    rooms.accept_invite("secret", &owner).unwrap();
+    assert_eq!(rooms.get("secret").unwrap().current(), (2, &[2; 32]));
+    assert_eq!(
+      rooms.accept_key("secret", owner, 3, [3; 32]).unwrap(),
+      Grant::Rotated
+    );
+    assert!(rooms.accept_key("secret", other, 4, [4; 32]).is_err());
+    assert_eq!(rooms.get("secret").unwrap().current(), (3, &[3; 32]));
+  }
+
+  #[test]
+  fn pending_invites_are_capped_and_expire() {
+    let owner = PeerId::random();
+    let mut rooms = PrivateRooms::default();
+    for room in 0..INVITES_PER_OWNER_MAX {
+      rooms
+        .accept_key(&room.to_string(), owner, 0, [0; 32])
+        .unwrap();
+    }
+    assert!(rooms.accept_key("one-more", owner, 0, [0; 32]).is_err());
+    // Rotated keys of pending invites still replace them.
+    assert!(rooms.accept_key("0", owner, 1, [1; 32]).is_ok());
+
+    while rooms.invites.len() < INVITES_MAX {
+      let room = rooms.invites.len().to_string();
+      rooms
+        .accept_key(&room, PeerId::random(), 0, [0; 32])
+        .unwrap();
+    }
+    assert!(rooms
+      .accept_key("one-more", PeerId::random(), 0, [0; 32])
+      .is_err());
+
+    for invite in rooms.invites.values_mut() {
+      invite.expires_at = Instant::now();
+    }
+    assert!(rooms.accept_invite("0", &owner).is_err());
+    assert!(rooms.invites.is_empty());
+    assert!(rooms.accept_key("one-more", owner, 0, [0; 32]).is_ok());
+  }
+}
@@ -0,0 +1,342 @@
+use std::fmt::{self, Display};
+
+use chrono::{DateTime, Utc};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use uuid::Uuid;
+
+use crate::constants::{ENVELOPE_LEGACY_VERSION, ENVELOPE_MIN_VERSION, ENVELOPE_VERSION};
+use crate::crypto::{self, SymmetricKey};
+
+pub const TEXT_PLAIN: &str = "text/plain";
+/// Content type of an envelope whose body is another, encrypted, envelope.
+pub const ENCRYPTED: &str = "application/vnd.chat-app.encrypted";
+
+/// Which key an encrypted envelope was sealed with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyRef {
+  /// The key shared by sender and recipient of a direct message.
+  Direct,
+  /// A private room key.
+  Room { epoch: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Encryption {
+  pub key: KeyRef,
+  pub nonce: ByteBuf,
+}
+
+/// Wire format of a chat message, encoded as CBOR.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Envelope {
+  pub version: u8,
+  pub id: Uuid,
+  pub timestamp: DateTime<Utc>,
+  pub nickname: Option<String>,
+  pub reply_to: Option<Uuid>,
+  pub content_type: String,
+  pub body: ByteBuf,
+  /// Set when `body` is an encrypted envelope. Added in version 2, and left out of plaintext
+  /// envelopes so version 1 peers still decode them.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub encryption: Option<Encryption>,
+  /// Peer id of the author, set along with `signature`. Added in version 2, but the version
+  /// only goes up for encrypted envelopes, signed plaintext ones keep version 1.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sender: Option<String>,
+  /// Signature of `sender` over the room and the message, so other peers can pass it on. The
+  /// nickname isn't covered, it's checked against the claims on the DHT instead.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub signature: Option<ByteBuf>,
+}
+
+/// What the author of an envelope signs.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+  room: &'a str,
+  sender: &'a str,
+  id: &'a Uuid,
+  timestamp: &'a DateTime<Utc>,
+  reply_to: Option<&'a Uuid>,
+  content_type: &'a str,
+  body: &'a ByteBuf,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+  Malformed(serde_cbor::Error),
+  UnsupportedVersion(u8),
+  NotUtf8,
+  NotEncrypted,
+  Decryption(anyhow::Error),
+  Unsigned,
+  InvalidSignature(String),
+}
+
+impl Display for EnvelopeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {e}"),
+      EnvelopeError::UnsupportedVersion(version) => {
+        write!(f, "unsupported envelope version {version}")
+      }
+      EnvelopeError::NotUtf8 => write!(f, "payload is neither an envelope nor UTF-8 text"),
+      EnvelopeError::NotEncrypted => write!(f, "envelope is not encrypted"),
+      EnvelopeError::Decryption(e) => write!(f, "decryption failed: {e}"),
+      EnvelopeError::Unsigned => write!(f, "envelope is not signed"),
+      EnvelopeError::InvalidSignature(reason) => write!(f, "invalid signature: {reason}"),
+    }
+  }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl Envelope {
+  /// A new plain-text message.
+  ///
+  /// It uses nothing added since version 1, so it's marked as such for older peers to read it.
+  pub fn text(content: String, nickname: Option<String>, reply_to: Option<Uuid>) -> Self {
+    Self {
+      version: ENVELOPE_MIN_VERSION,
+      id: Uuid::new_v4(),
+      timestamp: Utc::now(),
+      nickname,
+      reply_to,
+      content_type: TEXT_PLAIN.to_owned(),
+      body: ByteBuf::from(content.into_bytes()),
+      encryption: None,
+      sender: None,
+      signature: None,
+    }
+  }
+
+  /// Wraps this envelope in an encrypted one that only reveals its id.
+  pub fn seal(&self, key: &SymmetricKey, key_ref: KeyRef) -> Self {
+    let (nonce, ciphertext) = crypto::seal(key, self.id.as_bytes(), &self.encode());
+    Self {
+      version: ENVELOPE_VERSION,
+      id: self.id,
+      timestamp: self.timestamp,
+      nickname: None,
+      reply_to: None,
+      content_type: ENCRYPTED.to_owned(),
+      body: ByteBuf::from(ciphertext),
+      encryption: Some(Encryption {
+        key: key_ref,
+        nonce: ByteBuf::from(nonce),
+      }),
+      sender: None,
+      signature: None,
+    }
+  }
+
+  /// Decrypts the envelope sealed inside this one.
+  pub fn open(&self, key: &SymmetricKey) -> Result<Self, EnvelopeError> {
+    let encryption = self
+      .encryption
+      .as_ref()
+      .ok_or(EnvelopeError::NotEncrypted)?;
+    let plaintext = crypto::open(key, self.id.as_bytes(), &encryption.nonce, &self.body)
+      .map_err(EnvelopeError::Decryption)?;
+    let inner = Self::decode(&plaintext)?;
+    if inner.id != self.id || inner.timestamp != self.timestamp || inner.encryption.is_some() {
+      return Err(EnvelopeError::Decryption(anyhow::anyhow!(
+        "sealed envelope does not match its wrapper"
+      )));
+    }
+    Ok(inner)
+  }
+
+  /// Signs the message as posted to `room` by the owner of `keypair`, keeping its version.
+  pub fn sign(mut self, keypair: &Keypair, room: &str) -> anyhow::Result<Self> {
+    let sender = PeerId::from(keypair.public()).to_string();
+    let signature = keypair.sign(&self.signed_bytes(room, &sender))?;
+    self.sender = Some(sender);
+    self.signature = Some(ByteBuf::from(signature));
+    Ok(self)
+  }
+
+  /// Checks that the message was signed for `room` by its sender, returning the sender.
+  pub fn verify(&self, room: &str) -> Result<PeerId, EnvelopeError> {
+    let (sender, signature) = match (&self.sender, &self.signature) {
+      (Some(sender), Some(signature)) => (sender, signature),
+      _ => return Err(EnvelopeError::Unsigned),
+    };
+    let peer_id = sender
+      .parse::<PeerId>()
+      .map_err(|_| EnvelopeError::InvalidSignature(format!("{sender} is not a valid peer id")))?;
+    let public_key =
+      crypto::public_key(&peer_id).map_err(|e| EnvelopeError::InvalidSignature(e.to_string()))?;
+    if !public_key.verify(&self.signed_bytes(room, sender), signature) {
+      return Err(EnvelopeError::InvalidSignature(format!(
+        "not signed by {peer_id} for {room}"
+      )));
+    }
+    Ok(peer_id)
+  }
+
+  fn signed_bytes(&self, room: &str, sender: &str) -> Vec<u8> {
+    let fields = SignedFields {
+      room,
+      sender,
+      id: &self.id,
+      timestamp: &self.timestamp,
+      reply_to: self.reply_to.as_ref(),
+      content_type: &self.content_type,
+      body: &self.body,
+    };
+    serde_cbor::to_vec(&fields).expect("envelope is always serializable")
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    serde_cbor::to_vec(self).expect("envelope is always serializable")
+  }
+
+  /// Decodes a received payload.
+  ///
+  /// Envelopes are CBOR maps, whose first byte is never valid as the first byte of UTF-8 text, so
+  /// anything else that is valid UTF-8 is taken as a plain-text message from an older peer.
+  pub fn decode(data: &[u8]) -> Result<Self, EnvelopeError> {
+    match data.first() {
+      Some(0xa0..=0xbf) => {
+        let envelope: Envelope = serde_cbor::from_slice(data).map_err(EnvelopeError::Malformed)?;
+        if !(ENVELOPE_MIN_VERSION..=ENVELOPE_VERSION).contains(&envelope.version) {
+          return Err(EnvelopeError::UnsupportedVersion(envelope.version));
+        }
+        Ok(envelope)
+      }
+      _ => {
+        let content = std::str::from_utf8(data).map_err(|_| EnvelopeError::NotUtf8)?;
+        Ok(Self {
+          version: ENVELOPE_LEGACY_VERSION,
+          ..Self::text(content.to_owned(), None, None)
+        })
+      }
+    }
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    self.encryption.is_some()
+  }
+
+  /// The body as text, or a short placeholder for other content types.
+  pub fn display_body(&self) -> String {
+    match (self.content_type.as_str(), std::str::from_utf8(&self.body)) {
+      (TEXT_PLAIN, Ok(text)) => text.to_owned(),
+      (content_type, _) => format!("<{content_type}, {} bytes>", self.body.len()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use super::*;
+
+  #[test]
+  fn decodes_what_it_encodes() {
+    let envelope = Envelope::text("hello".to_owned(), Some("alice".to_owned()), None);
+    let decoded = Envelope::decode(&envelope.encode()).unwrap();
+    assert_eq!(decoded, envelope);
+    assert_eq!(decoded.display_body(), "hello");
+  }
+
+  #[test]
+  fn plain_text_is_a_legacy_message() {
+    let decoded = Envelope::decode("hi from an old peer".as_bytes()).unwrap();
+    assert_eq!(decoded.version, ENVELOPE_LEGACY_VERSION);
+    assert_eq!(decoded.display_body(), "hi from an old peer");
+    assert!(matches!(
+      Envelope::decode(&[0xff, 0xfe]),
+      Err(EnvelopeError::NotUtf8)
+    ));
+  }
+
+  #[test]
+  fn rejects_unknown_versions_and_fields() {
+    let mut envelope = Envelope::text("hello".to_owned(), None, None);
+    envelope.version = ENVELOPE_VERSION + 1;
+    assert!(matches!(
+      Envelope::decode(&envelope.encode()),
+      Err(EnvelopeError::UnsupportedVersion(version)) if version == ENVELOPE_VERSION + 1
+    ));
+
+    let mut fields: BTreeMap<String, serde_cbor::Value> =
+      serde_cbor::from_slice(&Envelope::text("hello".to_owned(), None, None).encode()).unwrap();
+    fields.insert("unknown".to_owned(), serde_cbor::Value::Bool(true));
+    assert!(matches!(
+      Envelope::decode(&serde_cbor::to_vec(&fields).unwrap()),
+      Err(EnvelopeError::Malformed(_))
+    ));
+  }
+
+  #[test]
+  fn plain_text_leaves_out_the_fields_of_version_2() {
+    let encoded = Envelope::text("hello".to_owned(), None, None).encode();
+    let fields: BTreeMap<String, serde_cbor::Value> = serde_cbor::from_slice(&encoded).unwrap();
+    assert_eq!(
+      fields["version"],
+      serde_cbor::Value::Integer(ENVELOPE_MIN_VERSION.into())
+    );
+    assert!(!fields.contains_key("encryption"));
+  }
+
+  #[test]
+  fn signing_keeps_the_version_of_plain_text() {
+    let keypair = Keypair::generate_ed25519();
+    let signed = Envelope::text("hello".to_owned(), None, None)
+      .sign(&keypair, "chat")
+      .unwrap();
+    assert_eq!(signed.version, ENVELOPE_MIN_VERSION);
+
+    let decoded = Envelope::decode(&signed.encode()).unwrap();
+    assert_eq!(decoded, signed);
+    assert_eq!(
+      decoded.verify("chat").unwrap(),
+      PeerId::from(keypair.public())
+    );
+    assert!(matches!(
+      decoded.verify("other"),
+      Err(EnvelopeError::InvalidSignature(_))
+    ));
+  }
+
+  #[test]
+  fn opens_what_it_seals() {
+    let key = crypto::generate_key();
+    let envelope = Envelope::text("secret".to_owned(), Some("alice".to_owned()), None);
+    let sealed = envelope.seal(&key, KeyRef::Room { epoch: 3 });
+    assert_eq!(sealed.version, ENVELOPE_VERSION);
+    assert_eq!(sealed.id, envelope.id);
+    assert_eq!(sealed.nickname, None);
+    assert!(sealed.is_encrypted());
+
+    let decoded = Envelope::decode(&sealed.encode()).unwrap();
+    assert_eq!(decoded.open(&key).unwrap(), envelope);
+    assert!(matches!(
+      decoded.open(&crypto::generate_key()),
+      Err(EnvelopeError::Decryption(_))
+    ));
+    assert!(matches!(
+      envelope.open(&key),
+      Err(EnvelopeError::NotEncrypted)
+    ));
+  }
+
+  #[test]
+  fn sealed_envelopes_are_bound_to_their_wrapper() {
+    let key = crypto::generate_key();
+    let mut sealed = Envelope::text("secret".to_owned(), None, None).seal(&key, KeyRef::Direct);
+    sealed.id = Uuid::new_v4();
+    assert!(matches!(
+      sealed.open(&key),
+      Err(EnvelopeError::Decryption(_))
+    ));
+  }
+}
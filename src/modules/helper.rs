@@ -1,10 +1,117 @@
-use libp2p::identity;
+use std::time::Duration;
+
+use anyhow::Result;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OptionalTransport};
+use libp2p::gossipsub::{Message, MessageId};
+use libp2p::multiaddr::Protocol;
+use libp2p::websocket::WsConfig;
+use libp2p::{dns, identity, tcp};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::config::NetworkConfig;
+use crate::constants::ENVELOPE_LEGACY_VERSION;
+use crate::envelope::Envelope;
+
+/// Gossipsub message id of `message`: its source and envelope id, so a message keeps its id
+/// however many times it's published, and a peer reusing the id of someone else's message
+/// doesn't get it dropped as a duplicate.
+///
+/// Presence heartbeats and plain text from older peers have no envelope id, they fall back to
+/// the sender and sequence number like the gossipsub default.
+pub fn message_id(message: &Message) -> MessageId {
+  match Envelope::decode(&message.data) {
+    Ok(envelope) if envelope.version != ENVELOPE_LEGACY_VERSION => {
+      let source = message.source.map(|peer_id| peer_id.to_base58());
+      MessageId::from(format!("{}/{}", source.unwrap_or_default(), envelope.id))
+    }
+    _ => {
+      let source = message.source.map(|peer_id| peer_id.to_base58());
+      let sequence_number = message.sequence_number.unwrap_or_default();
+      MessageId::from(format!("{}{sequence_number}", source.unwrap_or_default()))
+    }
+  }
+}
 
 pub fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
   let mut bytes = [0u8; 32];
   bytes[0] = secret_key_seed;
 
-  let secret_key = identity::ed25519::SecretKey::from_bytes(&mut bytes)
-    .expect("this returns `Err` only if the length is wrong; the length is correct; qed");
-  identity::Keypair::Ed25519(secret_key.into())
+  identity::Keypair::ed25519_from_bytes(bytes)
+    .expect("this returns `Err` only if the length is wrong; the length is correct; qed")
+}
+
+/// How long connections no protocol keeps alive stay open: the longest of the gossipsub and
+/// Kademlia idle timeouts, both now enforced by the swarm.
+pub fn idle_timeout(config: &NetworkConfig) -> Duration {
+  config
+    .gossipsub()
+    .idle_timeout()
+    .max(config.kademlia().connection_idle_timeout())
+}
+
+/// Adds a QUIC transport in front of `transport`, which is returned as is without the `quic` feature.
+#[cfg(feature = "quic")]
+pub fn with_quic(
+  keypair: &identity::Keypair,
+  transport: Boxed<(PeerId, StreamMuxerBox)>,
+) -> Boxed<(PeerId, StreamMuxerBox)> {
+  use libp2p::core::transport::OrTransport;
+  use libp2p::futures::future::Either;
+  use libp2p::quic;
+  use libp2p::Transport;
+
+  let quic = quic::tokio::Transport::new(quic::Config::new(keypair))
+    .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)));
+  OrTransport::new(quic, transport)
+    .map(|output, _| match output {
+      Either::Left(output) => output,
+      Either::Right(output) => output,
+    })
+    .boxed()
+}
+
+#[cfg(not(feature = "quic"))]
+pub fn with_quic(
+  _: &identity::Keypair,
+  transport: Boxed<(PeerId, StreamMuxerBox)>,
+) -> Boxed<(PeerId, StreamMuxerBox)> {
+  transport
+}
+
+/// WebSocket over TCP for browser clients, to be combined before the noise and yamux upgrades.
+pub fn websocket(
+  enabled: bool,
+) -> Result<OptionalTransport<WsConfig<dns::tokio::Transport<tcp::tokio::Transport>>>> {
+  if !enabled {
+    return Ok(OptionalTransport::none());
+  }
+  let tcp = dns::tokio::Transport::system(tcp::tokio::Transport::new(
+    tcp::Config::default().nodelay(true),
+  ))?;
+  Ok(OptionalTransport::some(WsConfig::new(tcp)))
+}
+
+pub fn is_websocket(addr: &Multiaddr) -> bool {
+  addr
+    .iter()
+    .any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_)))
+}
+
+#[cfg(feature = "quic")]
+pub fn is_quic(addr: &Multiaddr) -> bool {
+  addr
+    .iter()
+    .any(|p| matches!(p, Protocol::QuicV1 | Protocol::Quic))
+}
+
+pub fn is_circuit(addr: &Multiaddr) -> bool {
+  addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// Orders `addrs` so QUIC ones are dialed first.
+#[cfg(feature = "quic")]
+pub fn prefer_quic(mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+  addrs.sort_by_key(|addr| !is_quic(addr));
+  addrs
 }
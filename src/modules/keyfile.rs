@@ -0,0 +1,168 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use libp2p::identity::{KeyType, Keypair};
+use log::info;
+
+use crate::constants::DEFAULT_KEYFILE;
+
+/// Where the identity lives when no keyfile is given: in `$XDG_CONFIG_HOME`, `~/.config` or
+/// `%APPDATA%`, whichever is found first.
+pub fn default_path() -> Option<PathBuf> {
+  let config_dir = env::var_os("XDG_CONFIG_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+  Some(config_dir.join(DEFAULT_KEYFILE))
+}
+
+/// Loads the keypair stored at `path`, creating a new Ed25519 one if the file does not exist.
+pub fn load_or_create(path: &Path) -> Result<Keypair> {
+  match fs::metadata(path) {
+    Ok(_) => load(path),
+    Err(e) if e.kind() == ErrorKind::NotFound => {
+      info!(
+        "No keyfile at {}, generating a new identity",
+        path.display()
+      );
+      create(path, false)
+    }
+    Err(e) => Err(e).with_context(|| format!("failed to stat keyfile {}", path.display())),
+  }
+}
+
+/// Loads a protobuf-encoded keypair from `path`.
+pub fn load(path: &Path) -> Result<Keypair> {
+  check_permissions(path)?;
+
+  let bytes =
+    fs::read(path).with_context(|| format!("failed to read keyfile {}", path.display()))?;
+  let keypair = Keypair::from_protobuf_encoding(&bytes)
+    .with_context(|| format!("keyfile {} is not a valid protobuf keypair", path.display()))?;
+
+  match keypair.key_type() {
+    KeyType::Ed25519 => Ok(keypair),
+    _ => bail!(
+      "keyfile {} does not hold an Ed25519 keypair",
+      path.display()
+    ),
+  }
+}
+
+/// Generates a new Ed25519 keypair and writes it to `path` with owner-only permissions.
+pub fn create(path: &Path, overwrite: bool) -> Result<Keypair> {
+  let keypair = Keypair::generate_ed25519();
+  let bytes = keypair.to_protobuf_encoding()?;
+
+  if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    fs::create_dir_all(parent)
+      .with_context(|| format!("failed to create directory {}", parent.display()))?;
+  }
+
+  let mut options = OpenOptions::new();
+  options.write(true);
+  match overwrite {
+    true => options.create(true).truncate(true),
+    false => options.create_new(true),
+  };
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+
+  let mut file = options
+    .open(path)
+    .with_context(|| format!("failed to create keyfile {}", path.display()))?;
+  // The mode above only applies to new files, an overwritten one keeps its old permissions.
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    file
+      .set_permissions(fs::Permissions::from_mode(0o600))
+      .with_context(|| format!("failed to restrict keyfile {}", path.display()))?;
+  }
+  file.write_all(&bytes)?;
+  file.sync_all()?;
+
+  Ok(keypair)
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+
+  let mode = fs::metadata(path)?.permissions().mode();
+  if mode & 0o077 != 0 {
+    bail!(
+      "keyfile {} is accessible by other users (mode {:o}), run `chmod 600` on it",
+      path.display(),
+      mode & 0o777
+    );
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_: &Path) -> Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("chat-app-keyfile-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  #[test]
+  fn created_keyfiles_load_back() {
+    let dir = temp_dir("round-trip");
+    let path = dir.join("nested").join("identity.key");
+    let created = load_or_create(&path).unwrap();
+    let loaded = load_or_create(&path).unwrap();
+    assert_eq!(created.public(), loaded.public());
+    assert_eq!(load(&path).unwrap().public(), created.public());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn create_keeps_existing_keyfiles_unless_forced() {
+    let dir = temp_dir("create");
+    let path = dir.join("identity.key");
+    let first = create(&path, false).unwrap();
+    assert!(create(&path, false).is_err());
+    assert_eq!(load(&path).unwrap().public(), first.public());
+
+    let second = create(&path, true).unwrap();
+    assert_ne!(second.public(), first.public());
+    assert_eq!(load(&path).unwrap().public(), second.public());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn keyfiles_readable_by_others_are_refused() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = temp_dir("permissions");
+    let path = dir.join("identity.key");
+    create(&path, false).unwrap();
+    assert_eq!(
+      fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+      0o600
+    );
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(load(&path).is_err());
+    assert!(load_or_create(&path).is_err());
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,4 +1,6 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 use log::LevelFilter;
 
 use super::peer::mode::PeerMode;
@@ -27,10 +29,44 @@ pub struct Opts {
   /// Peer mode.
   #[clap(long, default_value = "peer")]
   pub peer_mode: PeerMode,
-  /// Key seed.
-  #[clap(long)]
+  /// Key seed. Only 256 identities exist this way, prefer `--keyfile`.
+  #[clap(long, conflicts_with = "keyfile")]
   pub key_seed: Option<u8>,
+  /// Keyfile holding the node identity. Created if missing, defaults to
+  /// `~/.config/chat-app/identity.key`.
+  #[clap(long)]
+  pub keyfile: Option<PathBuf>,
+  /// Directory of the on-disk message history. Messages are not kept when omitted.
+  #[clap(long)]
+  pub history: Option<PathBuf>,
+  /// Directory of the on-disk DHT records and routing table, one subdirectory per node. Both are
+  /// forgotten on restart when omitted.
+  #[clap(long)]
+  pub dht_store: Option<PathBuf>,
   /// Number of boot node.
   #[clap(long, short, default_value = "4")]
   pub number_of_boot_node: usize,
+  /// Network config file (TOML). Compiled-in defaults are used when omitted.
+  #[clap(long)]
+  pub config: Option<PathBuf>,
+  #[clap(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+  /// Generate a new Ed25519 keyfile and print its peer id.
+  Keygen {
+    /// Where to write the keyfile.
+    #[clap(long, short)]
+    out: PathBuf,
+    /// Overwrite an existing keyfile.
+    #[clap(long)]
+    force: bool,
+  },
+  /// Print the peer id of an existing keyfile.
+  ShowPeerId {
+    /// Keyfile to read.
+    keyfile: PathBuf,
+  },
 }
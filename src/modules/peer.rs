@@ -1,8 +1,21 @@
 mod behaviour;
 mod bootstrap;
+mod command;
+mod dht;
+mod direct;
+mod discovery;
 mod event;
+mod mailbox;
 pub mod mode;
+mod nickname;
 mod peer;
+mod presence;
+mod seen;
+mod startup;
+mod store;
+mod sync;
+mod validation;
 
 pub use bootstrap::*;
+pub use command::*;
 pub use peer::*;
@@ -1,31 +1,38 @@
+use super::direct::DirectMessageCodec;
 use super::event::Event;
-use libp2p::dcutr;
-use libp2p::gossipsub::Gossipsub;
-use libp2p::kad::store::MemoryStore;
-use libp2p::kad::Kademlia;
-use libp2p::mdns::TokioMdns;
-use libp2p::ping::Ping;
-use libp2p::relay::v2::{client::Client, relay::Relay};
-use libp2p::{identify::Identify, NetworkBehaviour};
+use super::mailbox::MailboxCodec;
+use super::store::DhtStore;
+use super::sync::SyncCodec;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{autonat, dcutr, gossipsub, identify, kad, mdns, ping, relay, request_response};
 
 #[derive(NetworkBehaviour)]
-#[behaviour(out_event = "Event", event_process = true)]
+#[behaviour(to_swarm = "Event")]
 pub struct PeerBehaviour {
-  pub client: Client,
-  pub ping: Ping,
-  pub identify: Identify,
-  pub dcutr: dcutr::behaviour::Behaviour,
-  pub kademlia: Kademlia<MemoryStore>,
-  pub gossipsub: Gossipsub,
-  pub mdns: TokioMdns,
+  pub client: relay::client::Behaviour,
+  pub ping: ping::Behaviour,
+  pub identify: identify::Behaviour,
+  pub dcutr: dcutr::Behaviour,
+  pub autonat: autonat::Behaviour,
+  pub kademlia: kad::Behaviour<DhtStore>,
+  pub gossipsub: gossipsub::Behaviour,
+  pub mdns: mdns::tokio::Behaviour,
+  pub direct: request_response::Behaviour<DirectMessageCodec>,
+  pub sync: request_response::Behaviour<SyncCodec>,
+  pub mailbox: request_response::Behaviour<MailboxCodec>,
 }
 
 #[derive(NetworkBehaviour)]
-#[behaviour(out_event = "Event", event_process = true)]
+#[behaviour(to_swarm = "Event")]
 pub struct BootstrapBehaviour {
-  pub relay: Relay,
-  pub ping: Ping,
-  pub identify: Identify,
-  pub kademlia: Kademlia<MemoryStore>,
-  pub gossipsub: Gossipsub,
+  pub relay: relay::Behaviour,
+  pub ping: ping::Behaviour,
+  pub identify: identify::Behaviour,
+  /// Dials peers back so they learn whether they are reachable.
+  pub autonat: autonat::Behaviour,
+  pub kademlia: kad::Behaviour<DhtStore>,
+  pub gossipsub: gossipsub::Behaviour,
+  /// Only enabled when the network config turns the mailbox on.
+  pub mailbox: Toggle<request_response::Behaviour<MailboxCodec>>,
 }
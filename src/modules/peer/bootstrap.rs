@@ -1,68 +1,124 @@
+use std::iter;
 use std::net::Ipv4Addr;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::constants::{BOOTSTRAP_ADDRESS, PORTS};
+use crate::config::{BootNode, NetworkConfig};
+use crate::constants::DEFAULT_ROOM;
 use crate::peer::event::Event;
+use crate::peer::PeerCommand;
 use crate::traits::peer::{TBuilder, TPeer};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use libp2p::core::transport::OrTransport;
 use libp2p::core::upgrade;
-use libp2p::dns::DnsConfig;
 use libp2p::futures::StreamExt;
-use libp2p::gossipsub::{self, Gossipsub, MessageAuthenticity, ValidationMode};
-use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
+use libp2p::gossipsub::{
+  self, IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId, ValidationMode,
+};
 use libp2p::identity::Keypair;
-use libp2p::kad::{store::MemoryStore, Kademlia, KademliaConfig};
 use libp2p::multiaddr::Protocol;
-use libp2p::noise;
-use libp2p::ping::{Ping, PingConfig};
-use libp2p::relay::v2::relay::Relay;
-use libp2p::swarm::{Swarm, SwarmBuilder, SwarmEvent};
-use libp2p::tcp::{GenTcpConfig, TokioTcpTransport};
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::{self, behaviour::toggle::Toggle, Swarm, SwarmEvent};
 use libp2p::Multiaddr;
 use libp2p::PeerId;
 use libp2p::Transport;
-use log::{debug, error, info};
+use libp2p::{autonat, dns, identify, kad, noise, ping, relay, tcp, yamux};
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::time::Instant;
 
-use super::super::helper::generate_ed25519;
+use super::super::helper::{
+  generate_ed25519, idle_timeout, is_circuit, message_id, websocket, with_quic,
+};
+use super::super::keyfile;
 use super::behaviour::BootstrapBehaviour;
-
-const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(3 * 60);
+use super::dht::{restore_routes, Dht};
+use super::mailbox::{Mailbox, MailboxCodec, MailboxProtocol, MailboxRequest, MailboxResponse};
+use super::presence;
+use super::store::DhtStore;
+use super::validation::{Invalid, Validator};
 
 pub struct Bootstrap {
   swarm: Swarm<BootstrapBehaviour>,
-  port: u16,
+  listen_addrs: Vec<Multiaddr>,
+  external_addrs: Vec<Multiaddr>,
+  /// WebSocket listeners for browser clients, when a WebSocket port is set.
+  ws_listen_addrs: Vec<Multiaddr>,
+  /// How often held messages are checked for expiry.
+  expire_interval: Duration,
+  /// Messages held for offline peers, when the mailbox is enabled.
+  mailbox: Option<Mailbox>,
+  dht: Dht,
+  /// Checks the messages of the topics meshed for browser clients before they're forwarded.
+  validator: Validator,
+  /// How long heartbeats count, as peers consider members offline after that.
+  heartbeat_age: Duration,
 }
 
 #[async_trait]
 impl TPeer for Bootstrap {
-  async fn run(&mut self, boot_nodes: &[&str]) -> Result<()> {
-    let listen_addr = Multiaddr::empty()
-      .with(Protocol::from(Ipv4Addr::UNSPECIFIED))
-      .with(Protocol::Tcp(self.port));
-    self.swarm.listen_on(listen_addr)?;
-
-    for (idx, peer) in boot_nodes.iter().enumerate() {
-      self.swarm.behaviour_mut().kademlia.add_address(
-        &PeerId::from_str(peer)?,
-        format!("{BOOTSTRAP_ADDRESS}/{}", PORTS[idx]).parse::<Multiaddr>()?,
-      );
+  async fn run(
+    &mut self,
+    boot_nodes: &[BootNode],
+    mut commands: UnboundedReceiver<PeerCommand>,
+  ) -> Result<()> {
+    for addr in self.listen_addrs.clone() {
+      self.swarm.listen_on(addr)?;
+    }
+    for addr in self.external_addrs.clone() {
+      info!("Announcing {addr}");
+      self.swarm.add_external_address(addr);
+    }
+    if !self.ws_listen_addrs.is_empty() {
+      for addr in self.ws_listen_addrs.clone() {
+        self.swarm.listen_on(addr)?;
+      }
+      // Browser clients usually only reach this node, so it meshes them into the default room
+      // and its presence topic.
+      for topic in [IdentTopic::new(DEFAULT_ROOM), presence::topic(DEFAULT_ROOM)] {
+        self
+          .swarm
+          .behaviour_mut()
+          .gossipsub
+          .subscribe(&topic)
+          .map_err(|e| anyhow!("failed to subscribe to {topic}: {e:?}"))?;
+      }
     }
-    match self.swarm.behaviour_mut().kademlia.bootstrap() {
-      Ok(_) => info!("Bootstrapped!"),
-      Err(_) => info!("No known servers"),
+
+    for node in boot_nodes {
+      self
+        .swarm
+        .behaviour_mut()
+        .kademlia
+        .add_address(node.peer_id(), node.address().clone());
     }
+    // The first bootstrap node knows no one yet, it keeps retrying until peers connect.
+    self.dht.bootstrap(&mut self.swarm.behaviour_mut().kademlia);
 
-    let sleep = tokio::time::sleep(BOOTSTRAP_INTERVAL);
+    let sleep = tokio::time::sleep(self.expire_interval);
     tokio::pin!(sleep);
 
     loop {
       tokio::select! {
         () = &mut sleep => {
-          sleep.as_mut().reset(Instant::now() + BOOTSTRAP_INTERVAL);
-          let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
+          sleep.as_mut().reset(Instant::now() + self.expire_interval);
+          if let Some(mailbox) = self.mailbox.as_mut() {
+            mailbox.expire();
+          }
+        }
+        () = tokio::time::sleep_until(self.dht.next_bootstrap()) => {
+          self.dht.bootstrap(&mut self.swarm.behaviour_mut().kademlia);
+        }
+        Some(command) = commands.recv() => {
+          match command {
+            PeerCommand::Dial(addr) => {
+              if let Err(e) = self.swarm.dial(addr) {
+                error!("{e:?}");
+              }
+            }
+            command => warn!("Bootstrap nodes don't support {command:?}"),
+          }
         }
         event = self.swarm.select_next_some() => {
           match event {
@@ -74,11 +130,8 @@ impl TPeer for Bootstrap {
             }
             SwarmEvent::Behaviour(Event::Identify(event)) => {
               info!("{:?}", event);
-              if let IdentifyEvent::Received { peer_id, info: IdentifyInfo { ref listen_addrs, ref protocols, .. } } = event {
-                if protocols
-                  .iter()
-                  .any(|p| p.as_bytes() == libp2p::kad::protocol::DEFAULT_PROTO_NAME)
-                {
+              if let identify::Event::Received { peer_id, info: identify::Info { ref listen_addrs, ref protocols, .. }, .. } = event {
+                if protocols.contains(&kad::PROTOCOL_NAME) {
                   for addr in listen_addrs.iter().cloned() {
                     self.swarm
                       .behaviour_mut()
@@ -87,17 +140,27 @@ impl TPeer for Bootstrap {
                   }
                 }
 
-                if listen_addrs
-                  .iter()
-                  .any(|address| address.iter().any(|p| p == Protocol::P2pCircuit))
-                {
+                if listen_addrs.iter().any(is_circuit) {
                   println!("{:?}", event);
                 }
               };
             }
             SwarmEvent::Behaviour(Event::Ping(_)) => {}
+            SwarmEvent::Behaviour(Event::Kademlia(event)) => {
+              self.dht.handle_event(&mut self.swarm.behaviour_mut().kademlia, &event);
+            }
+            SwarmEvent::Behaviour(Event::Mailbox(event)) => {
+              self.handle_mailbox_event(event);
+            }
+            SwarmEvent::Behaviour(Event::Gossipsub(gossipsub::Event::Message {
+              propagation_source,
+              message_id,
+              message,
+            })) => {
+              self.validate(propagation_source, message_id, message);
+            }
             SwarmEvent::Behaviour(Event::Autonat(e)) => {
-              info!("{e:?}");
+              debug!("AutoNAT: {e:?}");
             }
             SwarmEvent::ConnectionEstablished {
               peer_id, endpoint, ..
@@ -108,7 +171,7 @@ impl TPeer for Bootstrap {
               info!("Connection to {peer_id} closed due to: {cause:?}");
               // self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
             }
-            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
               error!("Outgoing connection error to {:?} due to: {:?}", peer_id, error);
               // if let Some(peer_id) = peer_id {
               //   self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
@@ -122,11 +185,94 @@ impl TPeer for Bootstrap {
   }
 }
 
+impl Bootstrap {
+  /// Tells gossipsub whether to forward a message, the node itself doesn't read them.
+  fn validate(
+    &mut self,
+    propagation_source: PeerId,
+    message_id: MessageId,
+    message: gossipsub::Message,
+  ) {
+    let subscribed = self
+      .swarm
+      .behaviour()
+      .gossipsub
+      .topics()
+      .any(|topic| topic == &message.topic);
+    let validated = match subscribed {
+      true => self
+        .validator
+        // Gossipsub drops the copies it already forwarded, nothing else is kept here.
+        .validate(&message, self.heartbeat_age, |_| false)
+        .map(|_| ()),
+      false => Err(Invalid::Ignore(format!(
+        "not subscribed to {}",
+        message.topic
+      ))),
+    };
+    let acceptance = match validated {
+      Ok(()) => MessageAcceptance::Accept,
+      Err(invalid) => {
+        debug!("Message {message_id} from {:?}: {invalid}", message.source);
+        invalid.acceptance()
+      }
+    };
+    // Nothing to report once the message dropped out of the cache.
+    let _ = self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .report_message_validation_result(&message_id, &propagation_source, acceptance);
+  }
+
+  fn handle_mailbox_event(
+    &mut self,
+    event: request_response::Event<MailboxRequest, MailboxResponse>,
+  ) {
+    match event {
+      request_response::Event::Message {
+        peer,
+        message: request_response::Message::Request {
+          request, channel, ..
+        },
+      } => {
+        let mailbox = match self.mailbox.as_mut() {
+          Some(mailbox) => mailbox,
+          None => return,
+        };
+        let response = mailbox.handle(peer, request);
+        match &response {
+          MailboxResponse::Stored { id } => info!("Holding message {id} from {peer}"),
+          MailboxResponse::Mail(mail) => debug!("Handing {} messages to {peer}", mail.len()),
+          MailboxResponse::Acked => debug!("{peer} acknowledged its messages"),
+          MailboxResponse::Rejected { reason } => {
+            warn!("Mailbox request from {peer} rejected: {reason}")
+          }
+        }
+        if let Some(mailbox) = self.swarm.behaviour_mut().mailbox.as_mut() {
+          if mailbox.send_response(channel, response).is_err() {
+            debug!("Connection to {peer} closed before its mailbox request was answered");
+          }
+        }
+      }
+      request_response::Event::InboundFailure { peer, error, .. } => {
+        debug!("Inbound mailbox request from {peer} failed: {error:?}");
+      }
+      event => debug!("{event:?}"),
+    }
+  }
+}
+
 #[derive(Default)]
 pub struct BootstrapBuilder {
   local_key: Option<Keypair>,
   local_peer_id: Option<PeerId>,
   port: Option<u16>,
+  listen_addrs: Vec<Multiaddr>,
+  external_addrs: Vec<Multiaddr>,
+  ws_port: Option<u16>,
+  config: NetworkConfig,
+  dht_store: Option<PathBuf>,
 }
 
 impl BootstrapBuilder {
@@ -142,10 +288,84 @@ impl BootstrapBuilder {
     self
   }
 
+  pub fn local_key_from_file(mut self, path: &Path) -> Result<Self> {
+    self.local_key = Some(keyfile::load_or_create(path)?);
+    self.local_peer_id = Some(PeerId::from(&self.local_key.as_ref().unwrap().public()));
+    Ok(self)
+  }
+
   pub fn port(mut self, port: u16) -> Self {
     self.port = Some(port);
     self
   }
+
+  /// Listens on `addrs` instead of all IPv4 interfaces on the port.
+  pub fn listen_addrs(mut self, addrs: &[Multiaddr]) -> Self {
+    self.listen_addrs = addrs.to_vec();
+    self
+  }
+
+  pub fn external_addrs(mut self, addrs: &[Multiaddr]) -> Self {
+    self.external_addrs = addrs.to_vec();
+    self
+  }
+
+  /// Also accepts WebSocket connections on `port`, for browser clients.
+  pub fn ws_port(mut self, port: Option<u16>) -> Self {
+    self.ws_port = port;
+    self
+  }
+
+  pub fn config(mut self, config: NetworkConfig) -> Self {
+    self.config = config;
+    self
+  }
+
+  /// Keeps DHT records and the routing table under `path`, in a directory named after the peer id.
+  pub fn dht_store(mut self, path: Option<PathBuf>) -> Self {
+    self.dht_store = path;
+    self
+  }
+}
+
+impl BootstrapBuilder {
+  /// Configured listen addresses, or all IPv4 interfaces on the port, over QUIC too with the
+  /// `quic` feature.
+  fn resolved_listen_addrs(&self) -> Vec<Multiaddr> {
+    if !self.listen_addrs.is_empty() {
+      return self.listen_addrs.clone();
+    }
+    let port = self.port.unwrap();
+    let any = Multiaddr::empty().with(Protocol::from(Ipv4Addr::UNSPECIFIED));
+    vec![
+      any.clone().with(Protocol::Tcp(port)),
+      #[cfg(feature = "quic")]
+      any.with(Protocol::Udp(port)).with(Protocol::QuicV1),
+    ]
+  }
+
+  /// WebSocket listeners on the WebSocket port, one per IP address of the listen addresses.
+  fn ws_listen_addrs(&self) -> Vec<Multiaddr> {
+    let port = match self.ws_port {
+      Some(port) => port,
+      None => return Vec::new(),
+    };
+    let mut addrs = Vec::new();
+    for addr in self.resolved_listen_addrs() {
+      let ip = match addr.iter().next() {
+        Some(ip @ (Protocol::Ip4(_) | Protocol::Ip6(_))) => ip,
+        _ => continue,
+      };
+      let ws_addr = Multiaddr::empty()
+        .with(ip)
+        .with(Protocol::Tcp(port))
+        .with(Protocol::Ws("/".into()));
+      if !addrs.contains(&ws_addr) {
+        addrs.push(ws_addr);
+      }
+    }
+    addrs
+  }
 }
 
 #[async_trait]
@@ -160,63 +380,95 @@ impl TBuilder for BootstrapBuilder {
 
     info!("Local peer id: {local_peer_id}");
 
-    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
-      .into_authentic(&local_key)
-      .expect("Signing libp2p-noise static DH keypair failed.");
-
-    let transport = TokioTcpTransport::new(GenTcpConfig::default().nodelay(true));
-    let transport = DnsConfig::system(transport).await?;
-    let transport = transport
+    let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+    let transport = dns::tokio::Transport::system(transport)?;
+    let transport = OrTransport::new(websocket(self.ws_port.is_some())?, transport)
       .upgrade(upgrade::Version::V1)
-      .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-      .multiplex(libp2p::yamux::YamuxConfig::default())
+      .authenticate(noise::Config::new(local_key)?)
+      .multiplex(yamux::Config::default())
       .boxed();
+    let transport = with_quic(local_key, transport);
 
-    let mut config = KademliaConfig::default();
+    let kademlia_settings = self.config.kademlia();
+    let mut config = kad::Config::new(kad::PROTOCOL_NAME);
     config
-      .set_query_timeout(Duration::from_secs(10))
-      .set_connection_idle_timeout(Duration::from_secs(10))
-      .set_record_ttl(Some(Duration::from_secs(120)))
+      .set_query_timeout(kademlia_settings.query_timeout())
+      .set_record_ttl(Some(kademlia_settings.record_ttl()))
       .set_publication_interval(None)
       .set_replication_interval(None)
-      .set_provider_record_ttl(Some(Duration::from_secs(120)))
-      .set_provider_publication_interval(None);
-    let store = MemoryStore::new(local_peer_id);
-    let kademlia = Kademlia::with_config(local_peer_id, store, config);
-
-    let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
-      .heartbeat_interval(std::time::Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
-      .idle_timeout(Duration::from_secs(30))
+      .set_provider_record_ttl(Some(kademlia_settings.provider_record_ttl()))
+      .set_provider_publication_interval(None)
+      .set_record_filtering(kad::StoreInserts::FilterBoth);
+    let store = match &self.dht_store {
+      Some(dir) => DhtStore::open(&dir.join(local_peer_id.to_string()), local_peer_id)?,
+      None => DhtStore::memory(local_peer_id),
+    };
+    let mut kademlia = kad::Behaviour::with_config(local_peer_id, store, config);
+    restore_routes(&mut kademlia)?;
+
+    let gossipsub_settings = self.config.gossipsub();
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+      .heartbeat_interval(gossipsub_settings.heartbeat_interval()) // This is set to aid debugging by not cluttering the log space
       .validation_mode(ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
+      .message_id_fn(message_id)
+      .validate_messages() // Messages are only forwarded once `Bootstrap::validate` accepts them
       .do_px()
       .build()
       .expect("Valid config");
 
-    let gossipsub = Gossipsub::new(
+    let gossipsub = gossipsub::Behaviour::new(
       MessageAuthenticity::Signed(local_key.clone()),
       gossipsub_config,
     )
     .expect("Correct configuration");
 
+    let autonat_settings = self.config.autonat();
+    let autonat = autonat::Behaviour::new(
+      local_peer_id,
+      autonat::Config {
+        only_global_ips: autonat_settings.only_global_ips(),
+        ..Default::default()
+      },
+    );
+
     let behaviour = BootstrapBehaviour {
-      relay: Relay::new(PeerId::from(local_key.public()), Default::default()),
-      ping: Ping::new(PingConfig::default().with_keep_alive(true)),
-      identify: Identify::new(IdentifyConfig::new(
-        "/TODO/0.0.1".to_string(),
+      relay: relay::Behaviour::new(local_peer_id, Default::default()),
+      ping: ping::Behaviour::default(),
+      identify: identify::Behaviour::new(identify::Config::new(
+        self.config.identify().protocol_version().to_owned(),
         local_key.public(),
       )),
+      autonat,
       kademlia,
       gossipsub,
+      mailbox: Toggle::from(self.config.mailbox().enabled().then(|| {
+        request_response::Behaviour::<MailboxCodec>::new(
+          iter::once((MailboxProtocol, ProtocolSupport::Inbound)),
+          Default::default(),
+        )
+      })),
     };
 
-    let swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
-      .executor(Box::new(|fut| {
-        tokio::spawn(fut);
-      }))
-      .build();
+    let swarm = Swarm::new(
+      transport,
+      behaviour,
+      local_peer_id,
+      swarm::Config::with_tokio_executor().with_idle_connection_timeout(idle_timeout(&self.config)),
+    );
     Ok(Box::new(Bootstrap {
       swarm,
-      port: self.port.unwrap(),
+      listen_addrs: self.resolved_listen_addrs(),
+      external_addrs: self.external_addrs.clone(),
+      ws_listen_addrs: self.ws_listen_addrs(),
+      expire_interval: self.config.mailbox().expire_interval(),
+      mailbox: self
+        .config
+        .mailbox()
+        .enabled()
+        .then(|| Mailbox::new(self.config.mailbox().clone())),
+      dht: Dht::new(kademlia_settings),
+      validator: Validator::new(gossipsub_settings),
+      heartbeat_age: self.config.presence().offline_after(),
     }))
   }
 }
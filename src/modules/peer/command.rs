@@ -0,0 +1,16 @@
+use libp2p::{Multiaddr, PeerId};
+
+/// Requests other actors can send to a running peer.
+#[derive(Debug, Clone)]
+pub enum PeerCommand {
+  /// Post a message to a joined room, as if typed on the console.
+  Publish { room: String, content: String },
+  /// Join a room.
+  Join(String),
+  /// Leave a room.
+  Leave(String),
+  /// Send a direct message to a peer.
+  Direct { peer: PeerId, content: String },
+  /// Dial the given address.
+  Dial(Multiaddr),
+}
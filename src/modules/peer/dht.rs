@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use libp2p::kad::store::RecordStore;
+use libp2p::kad::{self, BootstrapError, InboundRequest, QueryId, QueryResult};
+use log::{debug, info, warn};
+use tokio::time::Instant;
+
+use crate::config::KademliaSettings;
+
+use super::nickname;
+use super::store::DhtStore;
+
+/// Where a node stands on the DHT, kept up to date from Kademlia events.
+pub struct Dht {
+  /// Peers in the routing table after the last update.
+  routing_peers: usize,
+  /// Whether a bootstrap completed with at least one peer in the routing table.
+  bootstrapped: bool,
+  /// Bootstraps failed in a row, each one doubling the delay before the next attempt.
+  failures: u32,
+  next_bootstrap: Instant,
+  retry: Duration,
+  interval: Duration,
+  /// Queries this node started that haven't completed, with a description for the logs.
+  pending: HashMap<QueryId, String>,
+}
+
+impl Dht {
+  pub fn new(settings: &KademliaSettings) -> Self {
+    Self {
+      routing_peers: 0,
+      bootstrapped: false,
+      failures: 0,
+      next_bootstrap: Instant::now() + settings.bootstrap_interval(),
+      retry: settings.bootstrap_retry(),
+      interval: settings.bootstrap_interval(),
+      pending: HashMap::new(),
+    }
+  }
+
+  pub fn is_bootstrapped(&self) -> bool {
+    self.bootstrapped
+  }
+
+  pub fn routing_peers(&self) -> usize {
+    self.routing_peers
+  }
+
+  pub fn pending(&self) -> impl Iterator<Item = (&QueryId, &String)> {
+    self.pending.iter()
+  }
+
+  /// When the next bootstrap is due, sooner after a failure.
+  pub fn next_bootstrap(&self) -> Instant {
+    self.next_bootstrap
+  }
+
+  /// Records a query started by this node, so its completion is matched up in the logs.
+  pub fn track(&mut self, id: QueryId, description: String) {
+    self.pending.insert(id, description);
+  }
+
+  /// Starts a bootstrap, retrying with backoff when no peer is known yet.
+  pub fn bootstrap(&mut self, kademlia: &mut kad::Behaviour<DhtStore>) {
+    match kademlia.bootstrap() {
+      Ok(id) => {
+        self.track(id, "bootstrap".to_owned());
+        self.next_bootstrap = Instant::now() + self.interval;
+      }
+      Err(e) => {
+        warn!("Kademlia bootstrap skipped: {e:?}");
+        self.failed();
+      }
+    }
+  }
+
+  fn failed(&mut self) {
+    self.failures += 1;
+    let backoff = self
+      .retry
+      .saturating_mul(2u32.saturating_pow(self.failures - 1))
+      .min(self.interval);
+    warn!(
+      "Bootstrap failed {} times in a row, retrying in {backoff:?}",
+      self.failures
+    );
+    self.next_bootstrap = Instant::now() + backoff;
+  }
+
+  /// Updates the routing table size and the pending queries from `event`.
+  pub fn handle_event(&mut self, kademlia: &mut kad::Behaviour<DhtStore>, event: &kad::Event) {
+    match event {
+      kad::Event::RoutingUpdated {
+        peer,
+        is_new_peer,
+        addresses,
+        old_peer,
+        ..
+      } => {
+        kademlia
+          .store_mut()
+          .save_route(peer, addresses.iter().cloned().collect());
+        if let Some(old_peer) = old_peer {
+          kademlia.store_mut().remove_route(old_peer);
+        }
+        self.routing_peers = routing_peers(kademlia);
+        if *is_new_peer {
+          debug!(
+            "Added {peer} to the routing table, {} peers",
+            self.routing_peers
+          );
+        }
+        if let Some(old_peer) = old_peer {
+          debug!("Evicted {old_peer} from the routing table for {peer}");
+        }
+      }
+      kad::Event::UnroutablePeer { peer } => {
+        debug!("{peer} connected, but none of its addresses is known");
+      }
+      kad::Event::RoutablePeer { peer, address }
+      | kad::Event::PendingRoutablePeer { peer, address } => {
+        debug!("{peer} at {address} waits for room in a full bucket");
+      }
+      // Records are filtered, so nickname claims can't be overwritten by a later claimer.
+      kad::Event::InboundRequest {
+        request:
+          InboundRequest::PutRecord {
+            source,
+            record: Some(record),
+            ..
+          },
+      } => {
+        let existing = kademlia
+          .store_mut()
+          .get(&record.key)
+          .map(|r| r.into_owned());
+        match nickname::admit(existing.as_ref(), record) {
+          Ok(()) => {
+            if let Err(e) = kademlia.store_mut().put(record.clone()) {
+              warn!("Could not store a record from {source}: {e:?}");
+            }
+          }
+          Err(e) => debug!("Refused a record from {source}: {e}"),
+        }
+      }
+      kad::Event::InboundRequest {
+        request: InboundRequest::AddProvider {
+          record: Some(record),
+        },
+      } => {
+        if let Err(e) = kademlia.store_mut().add_provider(record.clone()) {
+          warn!(
+            "Could not store a provider record of {}: {e:?}",
+            record.provider
+          );
+        }
+      }
+      kad::Event::InboundRequest { request } => {
+        debug!("Kademlia request: {request:?}");
+      }
+      kad::Event::OutboundQueryProgressed {
+        id, result, step, ..
+      } => {
+        // Queries report each step, the description is only dropped with the last one.
+        let description = match step.last {
+          true => self.pending.remove(id),
+          false => self.pending.get(id).cloned(),
+        };
+        let description = match description {
+          Some(description) => description,
+          None => return,
+        };
+        match result {
+          QueryResult::Bootstrap(_) if !step.last => {}
+          QueryResult::Bootstrap(Ok(_)) => {
+            self.routing_peers = routing_peers(kademlia);
+            match self.routing_peers {
+              0 => {
+                warn!("Bootstrap found no peers");
+                self.failed();
+              }
+              peers => {
+                info!("Bootstrapped with {peers} peers in the routing table");
+                self.bootstrapped = true;
+                self.failures = 0;
+              }
+            }
+          }
+          QueryResult::Bootstrap(Err(BootstrapError::Timeout { .. })) => {
+            self.routing_peers = routing_peers(kademlia);
+            warn!(
+              "Bootstrap timed out with {} peers in the routing table",
+              self.routing_peers
+            );
+            self.failed();
+          }
+          result => debug!("Query {description} progressed: {result:?}"),
+        }
+      }
+      kad::Event::ModeChanged { new_mode } => {
+        debug!("Kademlia switched to {new_mode} mode");
+      }
+    }
+  }
+}
+
+fn routing_peers(kademlia: &mut kad::Behaviour<DhtStore>) -> usize {
+  kademlia.kbuckets().map(|bucket| bucket.num_entries()).sum()
+}
+
+/// Re-adds the routing table entries saved by a previous run, before the first bootstrap.
+pub fn restore_routes(kademlia: &mut kad::Behaviour<DhtStore>) -> Result<()> {
+  let routes = kademlia.store_mut().routes()?;
+  if routes.is_empty() {
+    return Ok(());
+  }
+  info!("Restoring {} peers of the routing table", routes.len());
+  for (peer, addresses) in routes {
+    for address in addresses {
+      kademlia.add_address(&peer, address);
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,170 @@
+use std::io;
+
+use async_trait::async_trait;
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::Codec;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use uuid::Uuid;
+
+use crate::constants::{DIRECT_MESSAGE_MAX_SIZE, DIRECT_MESSAGE_PROTOCOL};
+
+#[derive(Debug, Clone)]
+pub struct DirectMessageProtocol;
+
+impl AsRef<str> for DirectMessageProtocol {
+  fn as_ref(&self) -> &str {
+    DIRECT_MESSAGE_PROTOCOL
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectRequest {
+  /// A one-to-one chat message, carrying an encoded `Envelope`.
+  Message { envelope: ByteBuf },
+  /// A private room key, carrying an encoded `RoomKeyGrant` sealed with the direct key.
+  RoomKey {
+    room: String,
+    nonce: ByteBuf,
+    sealed: ByteBuf,
+  },
+  /// The sender left a private room owned by the recipient.
+  RoomLeft { room: String },
+}
+
+/// Acknowledgement for a `DirectRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectResponse {
+  Delivered { id: Uuid },
+  Accepted,
+  Rejected { reason: String },
+}
+
+/// Key of a private room, handed by its owner to a member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomKeyGrant {
+  pub epoch: u32,
+  pub key: ByteBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectMessageCodec;
+
+#[async_trait]
+impl Codec for DirectMessageCodec {
+  type Protocol = DirectMessageProtocol;
+  type Request = DirectRequest;
+  type Response = DirectResponse;
+
+  async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, DIRECT_MESSAGE_MAX_SIZE).await
+  }
+
+  async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, DIRECT_MESSAGE_MAX_SIZE).await
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    request: Self::Request,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &request).await
+  }
+
+  async fn write_response<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    response: Self::Response,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &response).await
+  }
+}
+
+pub(crate) async fn read_cbor<T, M>(io: &mut T, max_size: usize) -> io::Result<M>
+where
+  T: AsyncRead + Unpin + Send,
+  M: DeserializeOwned,
+{
+  let data = read_length_prefixed(io, max_size).await?;
+  serde_cbor::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) async fn write_cbor<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+  T: AsyncWrite + Unpin + Send,
+  M: Serialize,
+{
+  let data =
+    serde_cbor::to_vec(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  write_length_prefixed(io, &data).await?;
+  io.close().await
+}
+
+/// Reads a message prefixed with its length as an unsigned varint, the framing libp2p protocols use.
+async fn read_length_prefixed<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let mut len = 0usize;
+  let mut shift = 0;
+  loop {
+    let mut byte = [0u8];
+    io.read_exact(&mut byte).await?;
+    len |= usize::from(byte[0] & 0x7f) << shift;
+    if byte[0] & 0x80 == 0 {
+      break;
+    }
+    shift += 7;
+    if shift > 28 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "length prefix is too long",
+      ));
+    }
+  }
+  if len > max_size {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("message of {len} bytes is over the limit of {max_size} bytes"),
+    ));
+  }
+  let mut data = vec![0; len];
+  io.read_exact(&mut data).await?;
+  Ok(data)
+}
+
+async fn write_length_prefixed<T>(io: &mut T, data: &[u8]) -> io::Result<()>
+where
+  T: AsyncWrite + Unpin + Send,
+{
+  let mut len = data.len();
+  let mut prefix = Vec::new();
+  loop {
+    let byte = (len & 0x7f) as u8;
+    len >>= 7;
+    if len == 0 {
+      prefix.push(byte);
+      break;
+    }
+    prefix.push(byte | 0x80);
+  }
+  io.write_all(&prefix).await?;
+  io.write_all(data).await?;
+  io.flush().await
+}
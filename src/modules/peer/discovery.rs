@@ -0,0 +1,25 @@
+use std::collections::HashSet;
+
+use libp2p::kad::RecordKey as Key;
+use libp2p::PeerId;
+
+use crate::constants::ROOM_KEY_PREFIX;
+
+/// DHT key that the members of `room` provide, so other peers can find them.
+pub fn room_key(room: &str) -> Key {
+  Key::new(&format!("{ROOM_KEY_PREFIX}{room}"))
+}
+
+pub fn is_room_key(key: &Key) -> bool {
+  key.as_ref().starts_with(ROOM_KEY_PREFIX.as_bytes())
+}
+
+/// A lookup of the providers of a room, whose members get dialed once found.
+#[derive(Debug)]
+pub struct RoomDiscovery {
+  pub room: String,
+  /// Asked for on the console, so the members found are printed.
+  pub requested: bool,
+  /// Members found so far, the lookup reports them as it goes.
+  pub providers: HashSet<PeerId>,
+}
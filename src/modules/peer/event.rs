@@ -1,33 +1,33 @@
-use libp2p::gossipsub::GossipsubEvent;
-use libp2p::identify::IdentifyEvent;
-use libp2p::{autonat, dcutr};
+use libp2p::{autonat, dcutr, gossipsub, identify, kad, mdns, ping, relay, request_response};
 
-use libp2p::kad::KademliaEvent;
-use libp2p::mdns::MdnsEvent;
-use libp2p::ping::PingEvent;
-use libp2p::relay::v2::{client, relay};
+use super::direct::{DirectRequest, DirectResponse};
+use super::mailbox::{MailboxRequest, MailboxResponse};
+use super::sync::{SyncRequest, SyncResponse};
 
 #[derive(Debug)]
 pub enum Event {
   Relay(relay::Event),
-  Client(client::Event),
-  Ping(PingEvent),
-  Identify(IdentifyEvent),
-  Dcutr(dcutr::behaviour::Event),
-  Gossipsub(GossipsubEvent),
-  Mdns(MdnsEvent),
-  Kademlia(KademliaEvent),
+  Client(relay::client::Event),
+  Ping(ping::Event),
+  Identify(identify::Event),
+  Dcutr(dcutr::Event),
+  Gossipsub(gossipsub::Event),
+  Mdns(mdns::Event),
+  Kademlia(kad::Event),
   Autonat(autonat::Event),
+  Direct(request_response::Event<DirectRequest, DirectResponse>),
+  Sync(request_response::Event<SyncRequest, SyncResponse>),
+  Mailbox(request_response::Event<MailboxRequest, MailboxResponse>),
 }
 
-impl From<PingEvent> for Event {
-  fn from(e: PingEvent) -> Self {
+impl From<ping::Event> for Event {
+  fn from(e: ping::Event) -> Self {
     Event::Ping(e)
   }
 }
 
-impl From<IdentifyEvent> for Event {
-  fn from(e: IdentifyEvent) -> Self {
+impl From<identify::Event> for Event {
+  fn from(e: identify::Event) -> Self {
     Event::Identify(e)
   }
 }
@@ -38,32 +38,32 @@ impl From<relay::Event> for Event {
   }
 }
 
-impl From<client::Event> for Event {
-  fn from(e: client::Event) -> Self {
+impl From<relay::client::Event> for Event {
+  fn from(e: relay::client::Event) -> Self {
     Event::Client(e)
   }
 }
 
-impl From<dcutr::behaviour::Event> for Event {
-  fn from(e: dcutr::behaviour::Event) -> Self {
+impl From<dcutr::Event> for Event {
+  fn from(e: dcutr::Event) -> Self {
     Event::Dcutr(e)
   }
 }
 
-impl From<GossipsubEvent> for Event {
-  fn from(e: GossipsubEvent) -> Self {
+impl From<gossipsub::Event> for Event {
+  fn from(e: gossipsub::Event) -> Self {
     Event::Gossipsub(e)
   }
 }
 
-impl From<MdnsEvent> for Event {
-  fn from(e: MdnsEvent) -> Self {
+impl From<mdns::Event> for Event {
+  fn from(e: mdns::Event) -> Self {
     Event::Mdns(e)
   }
 }
 
-impl From<KademliaEvent> for Event {
-  fn from(e: KademliaEvent) -> Self {
+impl From<kad::Event> for Event {
+  fn from(e: kad::Event) -> Self {
     Event::Kademlia(e)
   }
 }
@@ -73,3 +73,21 @@ impl From<autonat::Event> for Event {
     Event::Autonat(e)
   }
 }
+
+impl From<request_response::Event<DirectRequest, DirectResponse>> for Event {
+  fn from(e: request_response::Event<DirectRequest, DirectResponse>) -> Self {
+    Event::Direct(e)
+  }
+}
+
+impl From<request_response::Event<SyncRequest, SyncResponse>> for Event {
+  fn from(e: request_response::Event<SyncRequest, SyncResponse>) -> Self {
+    Event::Sync(e)
+  }
+}
+
+impl From<request_response::Event<MailboxRequest, MailboxResponse>> for Event {
+  fn from(e: request_response::Event<MailboxRequest, MailboxResponse>) -> Self {
+    Event::Mailbox(e)
+  }
+}
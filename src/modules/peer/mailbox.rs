@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use libp2p::request_response::Codec;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use uuid::Uuid;
+
+use crate::config::MailboxSettings;
+use crate::constants::{MAILBOX_MAX_SIZE, MAILBOX_PROTOCOL};
+use crate::envelope::Envelope;
+
+use super::direct::{read_cbor, write_cbor};
+
+#[derive(Debug, Clone)]
+pub struct MailboxProtocol;
+
+impl AsRef<str> for MailboxProtocol {
+  fn as_ref(&self) -> &str {
+    MAILBOX_PROTOCOL
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxRequest {
+  /// Holds an encrypted direct message, an encoded `Envelope`, until `recipient` fetches it.
+  Deposit {
+    recipient: String,
+    envelope: ByteBuf,
+  },
+  /// Asks for the messages held for the requester.
+  Fetch,
+  /// The requester got these messages, they can be dropped.
+  Ack { ids: Vec<Uuid> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxResponse {
+  Stored { id: Uuid },
+  Mail(Vec<Mail>),
+  Acked,
+  Rejected { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+  pub id: Uuid,
+  /// Peer id of the depositor, as authenticated by the mailbox.
+  pub sender: String,
+  pub envelope: ByteBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MailboxCodec;
+
+#[async_trait]
+impl Codec for MailboxCodec {
+  type Protocol = MailboxProtocol;
+  type Request = MailboxRequest;
+  type Response = MailboxResponse;
+
+  async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, MAILBOX_MAX_SIZE).await
+  }
+
+  async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, MAILBOX_MAX_SIZE).await
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    request: Self::Request,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &request).await
+  }
+
+  async fn write_response<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    response: Self::Response,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &response).await
+  }
+}
+
+#[derive(Debug)]
+struct HeldMail {
+  mail: Mail,
+  expires_at: Instant,
+}
+
+/// Direct messages held by a bootstrap node for offline peers, in memory.
+///
+/// Messages are dropped once acknowledged or when their TTL runs out. Each recipient and each
+/// depositor has a quota of messages and bytes, so a single peer can't fill the node, and the
+/// whole mailbox has one too, so many peers can't either.
+#[derive(Debug)]
+pub struct Mailbox {
+  settings: MailboxSettings,
+  held: HashMap<PeerId, VecDeque<HeldMail>>,
+}
+
+impl Mailbox {
+  pub fn new(settings: MailboxSettings) -> Self {
+    Self {
+      settings,
+      held: HashMap::new(),
+    }
+  }
+
+  pub fn handle(&mut self, requester: PeerId, request: MailboxRequest) -> MailboxResponse {
+    self.expire();
+    let result = match request {
+      MailboxRequest::Deposit {
+        recipient,
+        envelope,
+      } => self
+        .deposit(requester, &recipient, envelope)
+        .map(|id| MailboxResponse::Stored { id }),
+      MailboxRequest::Fetch => Ok(MailboxResponse::Mail(self.fetch(&requester))),
+      MailboxRequest::Ack { ids } => {
+        self.ack(&requester, &ids);
+        Ok(MailboxResponse::Acked)
+      }
+    };
+    result.unwrap_or_else(|e| MailboxResponse::Rejected {
+      reason: e.to_string(),
+    })
+  }
+
+  fn deposit(&mut self, sender: PeerId, recipient: &str, envelope: ByteBuf) -> Result<Uuid> {
+    let recipient: PeerId = recipient
+      .parse()
+      .map_err(|_| anyhow!("{recipient} is not a valid peer id"))?;
+    // Only sealed envelopes are held, the mailbox never sees plain text.
+    if !Envelope::decode(&envelope)?.is_encrypted() {
+      bail!("only encrypted messages are held");
+    }
+
+    let sender_id = sender.to_string();
+    let (mut total, mut total_bytes, mut sent, mut sent_bytes) = (0, 0, 0, 0);
+    for held in self.held.values().flatten() {
+      total += 1;
+      total_bytes += held.mail.envelope.len();
+      if held.mail.sender == sender_id {
+        sent += 1;
+        sent_bytes += held.mail.envelope.len();
+      }
+    }
+    if total >= self.settings.max_messages()
+      || total_bytes + envelope.len() > self.settings.max_bytes()
+    {
+      bail!("mailbox is full");
+    }
+    if sent >= self.settings.max_messages_per_sender()
+      || sent_bytes + envelope.len() > self.settings.max_bytes_per_sender()
+    {
+      bail!("{sender} holds too many messages already");
+    }
+
+    let (held, held_bytes) = self
+      .held
+      .get(&recipient)
+      .map(|queue| {
+        let bytes: usize = queue.iter().map(|held| held.mail.envelope.len()).sum();
+        (queue.len(), bytes)
+      })
+      .unwrap_or_default();
+    if held >= self.settings.max_messages_per_peer() {
+      bail!("mailbox of {recipient} is full");
+    }
+    if held_bytes + envelope.len() > self.settings.max_bytes_per_peer() {
+      bail!("mailbox of {recipient} is out of space");
+    }
+
+    // The queue is only created once the message is sure to be held.
+    let id = Uuid::new_v4();
+    self.held.entry(recipient).or_default().push_back(HeldMail {
+      mail: Mail {
+        id,
+        sender: sender_id,
+        envelope,
+      },
+      expires_at: Instant::now() + self.settings.ttl(),
+    });
+    Ok(id)
+  }
+
+  fn fetch(&self, recipient: &PeerId) -> Vec<Mail> {
+    self
+      .held
+      .get(recipient)
+      .map(|queue| queue.iter().map(|held| held.mail.clone()).collect())
+      .unwrap_or_default()
+  }
+
+  fn ack(&mut self, recipient: &PeerId, ids: &[Uuid]) {
+    if let Some(queue) = self.held.get_mut(recipient) {
+      queue.retain(|held| !ids.contains(&held.mail.id));
+      if queue.is_empty() {
+        self.held.remove(recipient);
+      }
+    }
+  }
+
+  /// Drops messages past their TTL.
+  pub fn expire(&mut self) {
+    let now = Instant::now();
+    self.held.retain(|_, queue| {
+      queue.retain(|held| held.expires_at > now);
+      !queue.is_empty()
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto;
+  use crate::envelope::KeyRef;
+
+  fn mailbox(settings: &str) -> Mailbox {
+    Mailbox::new(toml::from_str(settings).unwrap())
+  }
+
+  fn sealed(content: &str) -> ByteBuf {
+    let envelope = Envelope::text(content.to_owned(), None, None);
+    ByteBuf::from(
+      envelope
+        .seal(&crypto::generate_key(), KeyRef::Direct)
+        .encode(),
+    )
+  }
+
+  fn deposit(mailbox: &mut Mailbox, sender: PeerId, recipient: PeerId) -> MailboxResponse {
+    let request = MailboxRequest::Deposit {
+      recipient: recipient.to_string(),
+      envelope: sealed("hello"),
+    };
+    mailbox.handle(sender, request)
+  }
+
+  fn fetch(mailbox: &mut Mailbox, recipient: PeerId) -> Vec<Mail> {
+    match mailbox.handle(recipient, MailboxRequest::Fetch) {
+      MailboxResponse::Mail(mail) => mail,
+      response => panic!("unexpected response {response:?}"),
+    }
+  }
+
+  #[test]
+  fn only_encrypted_messages_are_held() {
+    let mut mailbox = mailbox("");
+    let plain = Envelope::text("hello".to_owned(), None, None).encode();
+    let request = MailboxRequest::Deposit {
+      recipient: PeerId::random().to_string(),
+      envelope: ByteBuf::from(plain),
+    };
+    assert!(matches!(
+      mailbox.handle(PeerId::random(), request),
+      MailboxResponse::Rejected { .. }
+    ));
+  }
+
+  #[test]
+  fn quotas_are_enforced() {
+    let mut mailbox = mailbox("max_messages_per_peer = 2\nmax_messages_per_sender = 3");
+    let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+    assert!(matches!(
+      deposit(&mut mailbox, alice, bob),
+      MailboxResponse::Stored { .. }
+    ));
+    assert!(matches!(
+      deposit(&mut mailbox, alice, bob),
+      MailboxResponse::Stored { .. }
+    ));
+    assert!(matches!(
+      deposit(&mut mailbox, alice, bob),
+      MailboxResponse::Rejected { .. }
+    ));
+    assert!(matches!(
+      deposit(&mut mailbox, alice, carol),
+      MailboxResponse::Stored { .. }
+    ));
+    assert!(matches!(
+      deposit(&mut mailbox, alice, carol),
+      MailboxResponse::Rejected { .. }
+    ));
+    assert_eq!(fetch(&mut mailbox, bob).len(), 2);
+    assert_eq!(fetch(&mut mailbox, carol).len(), 1);
+  }
+
+  #[test]
+  fn rejected_deposits_leave_no_queue_behind() {
+    let mut mailbox = mailbox("max_bytes_per_peer = 1");
+    let (alice, bob) = (PeerId::random(), PeerId::random());
+    assert!(matches!(
+      deposit(&mut mailbox, alice, bob),
+      MailboxResponse::Rejected { .. }
+    ));
+    assert!(mailbox.held.is_empty());
+  }
+
+  #[test]
+  fn messages_expire_after_their_ttl() {
+    let mut mailbox = mailbox("");
+    let (alice, bob) = (PeerId::random(), PeerId::random());
+    deposit(&mut mailbox, alice, bob);
+    deposit(&mut mailbox, alice, bob);
+    mailbox.held.get_mut(&bob).unwrap()[0].expires_at = Instant::now();
+
+    assert_eq!(fetch(&mut mailbox, bob).len(), 1);
+    mailbox.held.get_mut(&bob).unwrap()[0].expires_at = Instant::now();
+    assert!(fetch(&mut mailbox, bob).is_empty());
+    assert!(mailbox.held.is_empty());
+  }
+
+  #[test]
+  fn only_the_recipient_acks_its_messages() {
+    let mut mailbox = mailbox("");
+    let (alice, bob) = (PeerId::random(), PeerId::random());
+    deposit(&mut mailbox, alice, bob);
+    deposit(&mut mailbox, alice, bob);
+    let mail = fetch(&mut mailbox, bob);
+    assert_eq!(mail[0].sender, alice.to_string());
+
+    let ids = vec![mail[0].id];
+    mailbox.handle(alice, MailboxRequest::Ack { ids: ids.clone() });
+    assert_eq!(fetch(&mut mailbox, bob).len(), 2);
+    mailbox.handle(bob, MailboxRequest::Ack { ids });
+    assert_eq!(fetch(&mut mailbox, bob)[0].id, mail[1].id);
+
+    mailbox.handle(
+      bob,
+      MailboxRequest::Ack {
+        ids: vec![mail[1].id],
+      },
+    );
+    assert!(mailbox.held.is_empty());
+  }
+}
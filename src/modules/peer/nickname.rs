@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use libp2p::identity::Keypair;
+use libp2p::kad::{QueryId, Record, RecordKey as Key};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::time::Instant;
+
+use crate::constants::{
+  NICKNAME_CLAIM_MAX_AGE_SECS, NICKNAME_CLOCK_SKEW_SECS, NICKNAME_KEY_PREFIX, NICKNAME_MAX_LEN,
+};
+use crate::crypto;
+
+/// A signed claim of `nickname` by `peer_id`, stored in the DHT under `key(nickname)`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NicknameClaim {
+  pub nickname: String,
+  pub peer_id: String,
+  /// When the nickname was first claimed, kept on renewal so the claim stays first.
+  pub claimed_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  /// Signature of the other fields by the identity key of `peer_id`.
+  pub signature: ByteBuf,
+}
+
+/// The claim without its signature, which is what gets signed.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+  nickname: &'a str,
+  peer_id: &'a str,
+  claimed_at: &'a DateTime<Utc>,
+  expires_at: &'a DateTime<Utc>,
+}
+
+impl NicknameClaim {
+  /// Claims `nickname` for the owner of `keypair` until `ttl` from now.
+  ///
+  /// Claim times older than half the accepted age are dated forward, so a renewed claim stays
+  /// valid until the next renewal.
+  pub fn new(
+    keypair: &Keypair,
+    nickname: &str,
+    claimed_at: DateTime<Utc>,
+    ttl: Duration,
+  ) -> Result<Self> {
+    let mut claim = Self {
+      nickname: normalize(nickname),
+      peer_id: PeerId::from(keypair.public()).to_string(),
+      claimed_at: claimed_at.max(Utc::now() - max_age() / 2),
+      expires_at: Utc::now() + chrono::Duration::from_std(ttl)?,
+      signature: ByteBuf::new(),
+    };
+    claim.signature = ByteBuf::from(keypair.sign(&claim.signed_bytes())?);
+    Ok(claim)
+  }
+
+  fn signed_bytes(&self) -> Vec<u8> {
+    let fields = SignedFields {
+      nickname: &self.nickname,
+      peer_id: &self.peer_id,
+      claimed_at: &self.claimed_at,
+      expires_at: &self.expires_at,
+    };
+    serde_cbor::to_vec(&fields).expect("nickname claim is always serializable")
+  }
+
+  /// Checks the signature, expiry and claim time, returning the peer holding the nickname.
+  ///
+  /// Claim times are chosen by the claimer, so ones in the future or too far in the past are
+  /// refused rather than letting them win every comparison.
+  pub fn verify(&self) -> Result<PeerId> {
+    validate(&self.nickname)?;
+    if self.nickname != normalize(&self.nickname) {
+      bail!("nickname {} is not normalized", self.nickname);
+    }
+    let now = Utc::now();
+    if self.expires_at <= now {
+      bail!("claim of {} expired at {}", self.nickname, self.expires_at);
+    }
+    let skew = chrono::Duration::seconds(NICKNAME_CLOCK_SKEW_SECS as i64);
+    if self.claimed_at > now + skew {
+      bail!(
+        "claim of {} is dated in the future, at {}",
+        self.nickname,
+        self.claimed_at
+      );
+    }
+    if self.claimed_at < now - max_age() {
+      bail!(
+        "claim of {} is dated too far in the past, at {}",
+        self.nickname,
+        self.claimed_at
+      );
+    }
+    let peer_id = self
+      .peer_id
+      .parse::<PeerId>()
+      .map_err(|_| anyhow!("{} is not a valid peer id", self.peer_id))?;
+    if !crypto::public_key(&peer_id)?.verify(&self.signed_bytes(), &self.signature) {
+      bail!("claim of {} has an invalid signature", self.nickname);
+    }
+    Ok(peer_id)
+  }
+
+  /// Whether this claim wins over `other`: the earliest claim does, ties go to the lowest peer id.
+  pub fn precedes(&self, other: &Self) -> bool {
+    (self.claimed_at, &self.peer_id) < (other.claimed_at, &other.peer_id)
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    serde_cbor::to_vec(self).expect("nickname claim is always serializable")
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self> {
+    Ok(serde_cbor::from_slice(data)?)
+  }
+
+  /// DHT record holding this claim, expiring with it.
+  pub fn record(&self, publisher: PeerId) -> Record {
+    let ttl = (self.expires_at - Utc::now()).to_std().unwrap_or_default();
+    let mut record = Record::new(key(&self.nickname), self.encode());
+    record.publisher = Some(publisher);
+    record.expires = Some(std::time::Instant::now() + ttl);
+    record
+  }
+}
+
+fn max_age() -> chrono::Duration {
+  chrono::Duration::seconds(NICKNAME_CLAIM_MAX_AGE_SECS as i64)
+}
+
+/// Nicknames are case-insensitive.
+pub fn normalize(nickname: &str) -> String {
+  nickname.to_lowercase()
+}
+
+pub fn validate(nickname: &str) -> Result<()> {
+  if nickname.is_empty() || nickname.chars().count() > NICKNAME_MAX_LEN {
+    bail!("nicknames are 1 to {NICKNAME_MAX_LEN} characters long");
+  }
+  if !nickname
+    .chars()
+    .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+  {
+    bail!("nicknames may only contain letters, digits, `-` and `_`");
+  }
+  Ok(())
+}
+
+pub fn key(nickname: &str) -> Key {
+  Key::new(&format!("{NICKNAME_KEY_PREFIX}{}", normalize(nickname)))
+}
+
+pub fn is_nickname_key(key: &Key) -> bool {
+  key.as_ref().starts_with(NICKNAME_KEY_PREFIX.as_bytes())
+}
+
+/// The valid claim that wins among `records`, with the peer holding it.
+pub fn winner<'a>(
+  records: impl IntoIterator<Item = &'a Record>,
+) -> Option<(PeerId, NicknameClaim)> {
+  records
+    .into_iter()
+    .filter_map(|record| {
+      let claim = NicknameClaim::decode(&record.value).ok()?;
+      if record.key != key(&claim.nickname) {
+        return None;
+      }
+      Some((claim.verify().ok()?, claim))
+    })
+    .reduce(|winner, other| match other.1.precedes(&winner.1) {
+      true => other,
+      false => winner,
+    })
+}
+
+/// Checks a record another peer asks this node to store.
+///
+/// Nickname records must hold a valid claim, and only the holder of an unexpired claim may
+/// replace it, to renew it. Other records are stored as they are.
+pub fn admit(existing: Option<&Record>, incoming: &Record) -> Result<()> {
+  if !is_nickname_key(&incoming.key) {
+    return Ok(());
+  }
+  let (peer_id, claim) = winner([incoming]).ok_or_else(|| anyhow!("not a valid nickname claim"))?;
+  if let Some((holder, held)) = existing.and_then(|existing| winner([existing])) {
+    if holder != peer_id {
+      bail!(
+        "{} is held by {holder} until {}",
+        claim.nickname,
+        held.expires_at
+      );
+    }
+  }
+  Ok(())
+}
+
+/// What a nickname lookup was started for.
+#[derive(Debug)]
+pub enum Lookup {
+  /// Claiming the nickname for this node, unless another peer holds it.
+  Claim(String),
+  /// Resolving the nickname to send it the queued direct messages.
+  Resolve {
+    nickname: String,
+    queued: Vec<String>,
+  },
+  /// Checking the nickname a message was signed with.
+  Verify(String),
+}
+
+impl Lookup {
+  pub fn nickname(&self) -> &str {
+    match self {
+      Lookup::Claim(nickname) | Lookup::Resolve { nickname, .. } | Lookup::Verify(nickname) => {
+        nickname
+      }
+    }
+  }
+}
+
+/// Nicknames resolved from the DHT, and the one this node holds.
+pub struct NicknameRegistry {
+  ttl: Duration,
+  /// Holders of verified claims by nickname, with the expiry of the claim.
+  resolved: HashMap<String, (PeerId, DateTime<Utc>)>,
+  /// This node's claim, renewed halfway through its lifetime.
+  own: Option<NicknameClaim>,
+  renew_at: Option<Instant>,
+  /// Lookups in flight, with the records found so far.
+  lookups: HashMap<QueryId, (Lookup, Vec<Record>)>,
+}
+
+impl NicknameRegistry {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      resolved: HashMap::new(),
+      own: None,
+      renew_at: None,
+      lookups: HashMap::new(),
+    }
+  }
+
+  pub fn ttl(&self) -> Duration {
+    self.ttl
+  }
+
+  /// Peer holding `nickname`, as long as its claim hasn't expired.
+  pub fn holder(&self, nickname: &str) -> Option<PeerId> {
+    self
+      .resolved
+      .get(&normalize(nickname))
+      .filter(|(_, expires_at)| *expires_at > Utc::now())
+      .map(|(peer_id, _)| *peer_id)
+  }
+
+  /// Remembers the holder of a verified claim.
+  pub fn resolved(&mut self, peer_id: PeerId, claim: &NicknameClaim) {
+    self
+      .resolved
+      .insert(claim.nickname.clone(), (peer_id, claim.expires_at));
+  }
+
+  pub fn own(&self) -> Option<&NicknameClaim> {
+    self.own.as_ref()
+  }
+
+  pub fn set_own(&mut self, claim: NicknameClaim) {
+    self.renew_at = Some(Instant::now() + self.ttl / 2);
+    self.own = Some(claim);
+  }
+
+  /// When this node's claim is due for renewal.
+  pub fn renew_at(&self) -> Option<Instant> {
+    self.renew_at
+  }
+
+  pub fn start(&mut self, id: QueryId, lookup: Lookup) {
+    self.lookups.insert(id, (lookup, Vec::new()));
+  }
+
+  /// Adds a record found by the lookup `id`.
+  pub fn found(&mut self, id: &QueryId, record: Record) {
+    if let Some((_, records)) = self.lookups.get_mut(id) {
+      records.push(record);
+    }
+  }
+
+  pub fn finish(&mut self, id: &QueryId) -> Option<(Lookup, Vec<Record>)> {
+    self.lookups.remove(id)
+  }
+
+  pub fn is_looking_up(&self, nickname: &str) -> bool {
+    let nickname = normalize(nickname);
+    self
+      .lookups
+      .values()
+      .any(|(lookup, _)| lookup.nickname() == nickname)
+  }
+
+  /// Queues `content` on a lookup of `nickname` already in flight, handing it back if there is none.
+  pub fn queue(&mut self, nickname: &str, content: String) -> Option<String> {
+    let nickname = normalize(nickname);
+    let queued = self
+      .lookups
+      .values_mut()
+      .find_map(|(lookup, _)| match lookup {
+        Lookup::Resolve {
+          nickname: pending,
+          queued,
+        } if *pending == nickname => Some(queued),
+        _ => None,
+      });
+    match queued {
+      Some(queued) => {
+        queued.push(content);
+        None
+      }
+      None => Some(content),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TTL: Duration = Duration::from_secs(60);
+
+  fn claim(keypair: &Keypair, nickname: &str, claimed_at: DateTime<Utc>) -> Record {
+    let claim = NicknameClaim::new(keypair, nickname, claimed_at, TTL).unwrap();
+    claim.record(PeerId::from(keypair.public()))
+  }
+
+  #[test]
+  fn first_claim_wins() {
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let now = Utc::now();
+    let first = claim(&bob, "Nick", now - chrono::Duration::minutes(1));
+    let second = claim(&alice, "nick", now);
+
+    let (holder, won) = winner([&second, &first]).unwrap();
+    assert_eq!(holder, PeerId::from(bob.public()));
+    assert_eq!(won.nickname, "nick");
+    assert!(admit(Some(&first), &second).is_err());
+  }
+
+  #[test]
+  fn only_the_holder_renews_an_unexpired_claim() {
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let now = Utc::now();
+    let held = claim(&alice, "nick", now);
+
+    // An earlier claim time doesn't take over a nickname someone else holds.
+    let earlier = claim(&bob, "nick", now - chrono::Duration::minutes(1));
+    assert!(admit(Some(&held), &earlier).is_err());
+    assert!(admit(Some(&held), &claim(&alice, "nick", now)).is_ok());
+
+    let expired = NicknameClaim::new(&alice, "nick", now, Duration::ZERO)
+      .unwrap()
+      .record(PeerId::from(alice.public()));
+    assert!(admit(Some(&expired), &earlier).is_ok());
+  }
+
+  #[test]
+  fn claim_times_are_bounded() {
+    let alice = Keypair::generate_ed25519();
+    let now = Utc::now();
+    let future = claim(&alice, "nick", now + chrono::Duration::hours(1));
+    assert!(winner([&future]).is_none());
+    assert!(admit(None, &future).is_err());
+
+    let mut ancient = NicknameClaim::new(&alice, "nick", now, TTL).unwrap();
+    ancient.claimed_at = now - max_age() - chrono::Duration::days(1);
+    ancient.signature = ByteBuf::from(alice.sign(&ancient.signed_bytes()).unwrap());
+    assert!(ancient.verify().is_err());
+
+    // Renewing a long-held claim dates it forward instead.
+    let renewed = NicknameClaim::new(&alice, "nick", ancient.claimed_at, TTL).unwrap();
+    assert!(renewed.verify().is_ok());
+  }
+
+  #[test]
+  fn ties_go_to_the_lowest_peer_id() {
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let now = Utc::now();
+    let lowest = std::cmp::min(
+      PeerId::from(alice.public()).to_string(),
+      PeerId::from(bob.public()).to_string(),
+    );
+    let (holder, _) = winner([&claim(&alice, "nick", now), &claim(&bob, "nick", now)]).unwrap();
+    assert_eq!(holder.to_string(), lowest);
+  }
+
+  #[test]
+  fn invalid_claims_never_win() {
+    let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let now = Utc::now();
+    let valid = claim(&alice, "nick", now);
+
+    let mut forged =
+      NicknameClaim::new(&bob, "nick", now - chrono::Duration::hours(1), TTL).unwrap();
+    forged.peer_id = PeerId::from(alice.public()).to_string();
+    let forged = forged.record(PeerId::from(bob.public()));
+    let mut misplaced = claim(&bob, "other", now - chrono::Duration::hours(1));
+    misplaced.key = key("nick");
+
+    let (holder, _) = winner([&forged, &misplaced, &valid]).unwrap();
+    assert_eq!(holder, PeerId::from(alice.public()));
+    assert!(winner([&forged, &misplaced]).is_none());
+  }
+}
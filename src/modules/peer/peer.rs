@@ -1,210 +1,1949 @@
-use std::net::Ipv4Addr;
-use std::str::FromStr;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::iter;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
-use bastion::prelude::block_on;
-use libp2p::core::{transport::OrTransport, upgrade};
-use libp2p::dcutr;
-use libp2p::dns::DnsConfig;
+use bastion::prelude::Bastion;
+use chrono::{DateTime, Utc};
+use libp2p::autonat::{self, NatStatus};
+use libp2p::core::transport::{ListenerId, OrTransport};
+use libp2p::core::upgrade;
 use libp2p::futures::StreamExt;
-use libp2p::gossipsub::{self, GossipsubEvent, IdentTopic, MessageAuthenticity, ValidationMode};
-use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
+use libp2p::gossipsub::{self, IdentTopic, MessageAcceptance, MessageAuthenticity, ValidationMode};
 use libp2p::identity::Keypair;
-use libp2p::kad::{store::MemoryStore, Kademlia, KademliaConfig};
-use libp2p::mdns::{MdnsEvent, TokioMdns};
+use libp2p::kad::{
+  self, GetProvidersOk, GetRecordError, GetRecordOk, PeerRecord, QueryId, QueryResult, Quorum,
+  Record,
+};
 use libp2p::multiaddr::Protocol;
-use libp2p::noise;
-use libp2p::ping::{Ping, PingConfig};
-use libp2p::relay::v2::client::{self, Client};
-use libp2p::swarm::{Swarm, SwarmBuilder, SwarmEvent};
-use libp2p::tcp::{GenTcpConfig, TokioTcpTransport};
+use libp2p::request_response::{self, OutboundFailure, OutboundRequestId, ProtocolSupport};
+use libp2p::swarm::{self, dial_opts::DialOpts, DialError, Swarm, SwarmEvent};
 use libp2p::Multiaddr;
 use libp2p::PeerId;
 use libp2p::Transport;
-use log::{debug, error, info};
+use libp2p::{dcutr, dns, identify, mdns, noise, ping, relay, tcp, yamux};
+use log::{debug, error, info, warn};
+use serde_bytes::ByteBuf;
 use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use uuid::Uuid;
 
-use crate::constants::{BOOTNODES, BOOTSTRAP_ADDRESS, PORTS};
+use crate::chat::{ChatMessage, ChatRoomCommand, ChatService, HistoryStore, PostedMessage};
+use crate::config::{BootNode, NetworkConfig, StartupSettings};
+use crate::console::{self, ConsoleCommand, ConsoleInput, PeerRef};
+use crate::constants::{
+  DEFAULT_ROOM, HISTORY_REPLAY_MESSAGES, RELAY_HOP_PROTOCOL, SEEN_MESSAGES_MAX, SYNC_MAX_MESSAGES,
+};
+use crate::crypto::{self, Grant, PrivateRooms, SymmetricKey};
+use crate::envelope::{Envelope, KeyRef};
 use crate::modules::peer::event::Event;
+use crate::modules::peer::PeerCommand;
 use crate::traits::peer::{TBuilder, TPeer};
 
-use super::super::helper::generate_ed25519;
+#[cfg(feature = "quic")]
+use super::super::helper::prefer_quic;
+use super::super::helper::{
+  generate_ed25519, idle_timeout, is_circuit, is_websocket, message_id, websocket, with_quic,
+};
+use super::super::keyfile;
 use super::behaviour::PeerBehaviour;
+use super::dht::{restore_routes, Dht};
+use super::direct::{
+  DirectMessageCodec, DirectMessageProtocol, DirectRequest, DirectResponse, RoomKeyGrant,
+};
+use super::discovery::{self, RoomDiscovery};
+use super::mailbox::{Mail, MailboxCodec, MailboxProtocol, MailboxRequest, MailboxResponse};
+use super::nickname::{self, Lookup, NicknameClaim, NicknameRegistry};
+use super::presence::{self, Heartbeat, Presence, Status};
+use super::seen::SeenMessages;
+use super::startup::{Handshake, StartupPhase};
+use super::store::DhtStore;
+use super::sync::{SyncCodec, SyncProtocol, SyncRequest, SyncResponse, SyncedMessage};
+use super::validation::{Invalid, Validated, Validator};
 
 pub struct Peer {
   swarm: Swarm<PeerBehaviour>,
-  topic: IdentTopic,
+  /// Identity keypair, also used to derive direct message keys.
+  local_key: Keypair,
+  /// Joined rooms, keyed by name.
+  rooms: BTreeMap<String, IdentTopic>,
+  /// Room that console input is sent to.
+  current_room: Option<String>,
+  nickname: Option<String>,
+  listen_addrs: Vec<Multiaddr>,
+  /// Announced through Identify, for nodes behind port forwarding.
+  external_addrs: Vec<Multiaddr>,
+  /// Relay candidates with their dialable address, bootnodes first, then relays learned through
+  /// Identify. Also used to reach peers we aren't connected to.
+  relays: Vec<(PeerId, Multiaddr)>,
+  /// Circuit listeners on the relays we hold a reservation on, only while AutoNAT finds us
+  /// unreachable.
+  relay_listeners: HashMap<PeerId, ListenerId>,
+  /// Relays whose reservation failed or was lost, skipped until we connect to them again.
+  failed_relays: HashSet<PeerId>,
+  /// Number of relays to hold a reservation on.
+  relay_reservations: usize,
+  /// Direct requests awaiting an acknowledgement, with a description for the logs.
+  pending_direct: HashMap<OutboundRequestId, (PeerId, String)>,
+  /// Sealed direct messages awaiting an acknowledgement, left in a mailbox if the peer is offline.
+  undelivered: HashMap<OutboundRequestId, ByteBuf>,
+  /// Bootstrap nodes that may hold messages for us while we are offline.
+  mailboxes: Vec<PeerId>,
+  /// Sync requests awaiting an answer, with the room they are about.
+  pending_sync: HashMap<OutboundRequestId, (PeerId, String)>,
+  /// Direct message keys, keyed by remote peer.
+  direct_keys: HashMap<PeerId, SymmetricKey>,
+  private_rooms: PrivateRooms,
+  chat: ChatService,
+  /// On-disk message history, replayed when joining a room.
+  history: Option<HistoryStore>,
+  startup: StartupSettings,
+  dht: Dht,
+  /// Nicknames claimed on the DHT, ours and those of the peers we hear from.
+  nicknames: NicknameRegistry,
+  /// Provider lookups of rooms that haven't completed.
+  discoveries: HashMap<QueryId, RoomDiscovery>,
+  /// Who is online in the rooms we're in.
+  presence: Presence,
+  /// Envelope ids of the latest messages received or sent.
+  seen: SeenMessages,
+  /// Checks gossipsub messages before they're delivered or forwarded.
+  validator: Validator,
+  /// Connection and Identify exchange with each connected peer, so a bootnode we're already
+  /// connected to doesn't have to go through them again.
+  handshakes: HashMap<PeerId, Handshake>,
 }
 
 #[async_trait]
 impl TPeer for Peer {
-  async fn run(&mut self, _: &[&str]) -> Result<()> {
-    self.swarm.listen_on(
-      Multiaddr::empty()
-        .with("0.0.0.0".parse::<Ipv4Addr>().unwrap().into())
-        .with(Protocol::Tcp(0)),
-    )?;
+  async fn run(
+    &mut self,
+    boot_nodes: &[BootNode],
+    mut commands: UnboundedReceiver<PeerCommand>,
+  ) -> Result<()> {
+    let mut listeners = HashSet::new();
+    for addr in self.listen_addrs.clone() {
+      listeners.insert(self.swarm.listen_on(addr)?);
+    }
+    for addr in self.external_addrs.clone() {
+      info!("Announcing {addr}");
+      self.swarm.add_external_address(addr);
+    }
+
+    let saved_rooms = match &self.history {
+      Some(history) => history.rooms().unwrap_or_else(|e| {
+        error!("Failed to load the rooms to rejoin: {e:?}");
+        Vec::new()
+      }),
+      None => Vec::new(),
+    };
+    for room in saved_rooms.iter().filter(|room| *room != DEFAULT_ROOM) {
+      if let Err(e) = self.join_room(room).await {
+        error!("Failed to rejoin {room}: {e:?}");
+      }
+    }
+    // Joined last so it's the current room.
+    self.join_room(DEFAULT_ROOM).await?;
+
+    self.await_listeners(listeners).await?;
+
+    match self.connect_bootnode(boot_nodes).await {
+      Some(bootnode) => {
+        // The relay doubles as our mailbox, it's the node we stay connected to.
+        let relay = *bootnode.peer_id();
+        self.add_relay(relay, bootnode.dial_addr());
+        self.mailboxes.push(relay);
+        self.fetch_mail(relay);
+      }
+      None => warn!("No bootnode reachable, running on mDNS only"),
+    }
+
+    for node in boot_nodes {
+      // Every bootnode runs a relay, reserved on once AutoNAT finds us unreachable.
+      self.add_relay(*node.peer_id(), node.dial_addr());
+      self
+        .swarm
+        .behaviour_mut()
+        .kademlia
+        .add_address(node.peer_id(), node.address().clone());
+      self
+        .swarm
+        .behaviour_mut()
+        .autonat
+        .add_server(*node.peer_id(), Some(node.address().clone()));
+    }
+
+    self.dht.bootstrap(&mut self.swarm.behaviour_mut().kademlia);
+
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
 
     loop {
       tokio::select! {
-        event = self.swarm.select_next_some() => {
-          match event {
-            SwarmEvent::NewListenAddr { address, .. } => {
-              info!("Listening on {:?}", address);
+        line = stdin.next_line() => {
+          let line = line?.expect("stdin closed");
+          if self.presence.input() {
+            self.send_heartbeats(Status::Online);
+          }
+          match console::parse(&line) {
+            Ok(ConsoleInput::Message(content)) => match self.current_room.clone() {
+              Some(room) => self.publish(&room, content).await,
+              None => println!("Not in any room, /join one first"),
+            },
+            Ok(ConsoleInput::Command(command)) => {
+              if let ControlFlow::Break(()) = self.handle_console_command(command).await {
+                Bastion::stop();
+                return Ok(());
+              }
+            }
+            Err(e) => println!("{e}"),
+          }
+        }
+        Some(command) = commands.recv() => {
+          match command {
+            PeerCommand::Publish { room, content } => self.publish(&room, content).await,
+            PeerCommand::Join(room) => {
+              if let Err(e) = self.join_room(&room).await {
+                error!("{e:?}");
+              }
+            }
+            PeerCommand::Leave(room) => {
+              if let Err(e) = self.leave_room(&room).await {
+                error!("{e:?}");
+              }
+            }
+            PeerCommand::Direct { peer, content } => self.send_direct(peer, content),
+            PeerCommand::Dial(addr) => {
+              if let Err(e) = self.swarm.dial(addr) {
+                error!("{e:?}");
+              }
             }
-            event => debug!("{:?}", event),
           }
         }
-        _ = tokio::time::sleep(Duration::from_secs(1)) => {
-          // Likely listening on all interfaces now, thus continuing by breaking the loop.
-          break;
+        () = tokio::time::sleep_until(self.dht.next_bootstrap()) => {
+          self.dht.bootstrap(&mut self.swarm.behaviour_mut().kademlia);
         }
+        () = sleep_until_some(self.nicknames.renew_at()) => self.renew_nickname(),
+        () = tokio::time::sleep_until(self.presence.next_heartbeat()) => {
+          self.send_heartbeats(self.presence.own_status());
+        }
+        event = self.swarm.select_next_some() => self.handle_swarm_event(event).await,
       }
     }
+  }
+}
 
-    let dial_addr =
-      format!("{}/4003/p2p/{}", BOOTSTRAP_ADDRESS, BOOTNODES[0]).parse::<Multiaddr>()?;
-    info!("Dial addr: {dial_addr}");
-    self.swarm.dial(dial_addr.clone())?;
-    let mut learned_observed_addr = false;
-    let mut told_relay_observed_addr = false;
+impl Peer {
+  /// Waits until every listener reported an address, failing the listen phase if none did in time.
+  async fn await_listeners(&mut self, mut pending: HashSet<ListenerId>) -> Result<()> {
+    let timeout = self.startup.listen_timeout();
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
 
-    loop {
-      match self.swarm.select_next_some().await {
-        SwarmEvent::NewListenAddr { .. } => {}
-        SwarmEvent::Dialing { .. } => {}
-        SwarmEvent::ConnectionEstablished { .. } => {}
-        SwarmEvent::Behaviour(Event::Ping(_)) => {}
-        SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Sent { .. })) => {
-          info!("Told relay its public address.");
-          told_relay_observed_addr = true;
-        }
-        SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received {
-          info: IdentifyInfo { observed_addr, .. },
-          ..
-        })) => {
-          info!("Relay told us our public address: {:?}", observed_addr);
-          learned_observed_addr = true;
+    while !pending.is_empty() {
+      tokio::select! {
+        () = &mut deadline => break,
+        event = self.swarm.select_next_some() => {
+          match &event {
+            SwarmEvent::NewListenAddr { listener_id, .. }
+            | SwarmEvent::ListenerClosed { listener_id, .. } => {
+              pending.remove(listener_id);
+            }
+            _ => {}
+          }
+          self.handle_swarm_event(event).await;
         }
-        event => debug!("{:?}", event),
       }
+    }
+
+    if self.swarm.listeners().next().is_none() {
+      bail!(
+        "startup failed in the {} phase: no listen address came up within {timeout:?}",
+        StartupPhase::Listen
+      );
+    }
+    if !pending.is_empty() {
+      warn!(
+        "{} listeners reported no address within {timeout:?}",
+        pending.len()
+      );
+    }
+    Ok(())
+  }
 
-      if learned_observed_addr && told_relay_observed_addr {
-        break;
+  /// Tries each bootnode in turn until one completes the handshake.
+  async fn connect_bootnode<'a>(&mut self, boot_nodes: &'a [BootNode]) -> Option<&'a BootNode> {
+    for node in boot_nodes {
+      match self.handshake(node).await {
+        Ok(()) => {
+          info!("Connected to bootnode {}", node.peer_id());
+          return Some(node);
+        }
+        Err(phase) => warn!("Bootnode {} failed in the {phase} phase", node.peer_id()),
       }
     }
+    None
+  }
 
-    self
-      .swarm
-      .listen_on(dial_addr.with(Protocol::P2pCircuit))
-      .unwrap();
+  /// Dials `node` and exchanges Identify with it, returning the phase that failed or timed out.
+  async fn handshake(&mut self, node: &BootNode) -> std::result::Result<(), StartupPhase> {
+    let bootnode = *node.peer_id();
 
-    for (idx, peer) in BOOTNODES.iter().enumerate() {
-      self.swarm.behaviour_mut().kademlia.add_address(
-        &PeerId::from_str(peer)?,
-        format!("{BOOTSTRAP_ADDRESS}/{}", PORTS[idx]).parse::<Multiaddr>()?,
-      );
+    let dial_addrs = node.dial_addrs();
+    info!("Dialing bootnode {bootnode} on {dial_addrs:?}");
+    match self
+      .swarm
+      .dial(DialOpts::peer_id(bootnode).addresses(dial_addrs).build())
+    {
+      Ok(()) => {}
+      // Connected already, through mDNS or an earlier attempt.
+      Err(DialError::DialPeerConditionFalse(_)) => {
+        debug!("Already connected to bootnode {bootnode}");
+      }
+      Err(e) => {
+        debug!("Dialing {bootnode} failed: {e:?}");
+        return Err(self.handshake_with(&bootnode).phase());
+      }
+    }
+    // Identify may have run on an existing connection.
+    if self.handshake_with(&bootnode).is_done() {
+      return Ok(());
     }
 
-    self.swarm.behaviour_mut().kademlia.bootstrap()?;
-
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let deadline = tokio::time::sleep(self.startup.bootnode_timeout());
+    tokio::pin!(deadline);
 
     loop {
       tokio::select! {
-        line = stdin.next_line() => {
-          let line = line?.expect("stdin closed");
-          if let Err(e) = self.swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(self.topic.clone(), line.as_bytes()) {
-              error!("{e:?}");
-          }
+        () = &mut deadline => {
+          debug!("Bootnode {bootnode} timed out");
+          return Err(self.handshake_with(&bootnode).phase());
         }
         event = self.swarm.select_next_some() => {
-          match event {
-            SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Message { message, .. })) => {
-              info!(
-                "Received: '{:?}' from {:?}",
-                String::from_utf8_lossy(&message.data),
-                message.source
-              );
-            }
-            SwarmEvent::NewListenAddr { address, .. } => {
-              info!("Listening on {:?}", address);
+          let failed = matches!(
+            &event,
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), .. } if peer_id == &bootnode
+          );
+          let phase = self.handshake_with(&bootnode).phase();
+          self.handle_swarm_event(event).await;
+          if failed {
+            return Err(phase);
+          }
+          if self.handshake_with(&bootnode).is_done() {
+            return Ok(());
+          }
+        }
+      }
+    }
+  }
+
+  fn handshake_with(&self, peer: &PeerId) -> Handshake {
+    self.handshakes.get(peer).copied().unwrap_or_default()
+  }
+
+  /// Handles a swarm event, during startup and once running alike.
+  async fn handle_swarm_event(&mut self, event: SwarmEvent<Event>) {
+    match event {
+      SwarmEvent::Behaviour(Event::Gossipsub(gossipsub::Event::Message {
+        propagation_source,
+        message_id,
+        message,
+      })) => {
+        let validated = self.validate(&message);
+        let acceptance = match &validated {
+          Ok(_) => MessageAcceptance::Accept,
+          Err(invalid) => {
+            debug!("Message {message_id} from {:?}: {invalid}", message.source);
+            invalid.acceptance()
+          }
+        };
+        // Nothing to report once the message dropped out of the cache.
+        let _ = self
+          .swarm
+          .behaviour_mut()
+          .gossipsub
+          .report_message_validation_result(&message_id, &propagation_source, acceptance);
+        let room = message.topic.as_str();
+        match validated {
+          Ok(Validated::Envelope(envelope)) => {
+            self.receive(room, message.source, envelope).await;
+          }
+          Ok(Validated::Heartbeat(peer, heartbeat)) => {
+            let room = presence::room_of(room).unwrap_or(room);
+            self.receive_heartbeat(room, peer, heartbeat);
+          }
+          Err(_) => {}
+        }
+      }
+      SwarmEvent::Behaviour(Event::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+        // A room member (re)connected, it may have messages posted while we were away.
+        if self.rooms.contains_key(topic.as_str()) {
+          self.request_sync(peer_id, topic.as_str());
+        }
+        // Newcomers learn we're here without waiting for the next heartbeat.
+        if let Some(room) = presence::room_of(topic.as_str()) {
+          if self.rooms.contains_key(room) {
+            self.send_heartbeat(room, self.presence.own_status());
+          }
+        }
+      }
+      SwarmEvent::NewListenAddr { address, .. } => {
+        info!("Listening on {:?}", address);
+        if is_circuit(&address) {
+          // Tell connected peers right away instead of waiting for the next Identify round.
+          let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+          self.swarm.behaviour_mut().identify.push(peers);
+        }
+      }
+      SwarmEvent::ListenerClosed {
+        listener_id,
+        addresses,
+        reason,
+      } => {
+        let relay = self
+          .relay_listeners
+          .iter()
+          .find(|(_, listener)| **listener == listener_id)
+          .map(|(relay, _)| *relay);
+        match relay {
+          Some(relay) => {
+            warn!("Lost the reservation on {relay}: {reason:?}");
+            self.replace_relay(relay);
+          }
+          None => info!("Listener on {addresses:?} closed: {reason:?}"),
+        }
+      }
+      SwarmEvent::Behaviour(Event::Mdns(event)) => {
+        debug!("{event:?}");
+        match event {
+          mdns::Event::Discovered(list) => {
+            for (peer, _) in list {
+              self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .add_explicit_peer(&peer);
             }
-            SwarmEvent::Behaviour(Event::Mdns(event)) => {
-              debug!("{event:?}");
-              match event {
-                MdnsEvent::Discovered(list) => {
-                  for (peer, _) in list {
-                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
-                  }
-                }
-                MdnsEvent::Expired(list) => {
-                  for (peer, _) in list {
-                    if !self.swarm.behaviour().mdns.has_node(&peer) {
-                      self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
-                    }
-                  }
+          }
+          mdns::Event::Expired(list) => {
+            for (peer, _) in list {
+              if !self
+                .swarm
+                .behaviour()
+                .mdns
+                .discovered_nodes()
+                .any(|p| p == &peer)
+              {
+                self
+                  .swarm
+                  .behaviour_mut()
+                  .gossipsub
+                  .remove_explicit_peer(&peer);
+                if !self.swarm.is_connected(&peer) {
+                  self.presence.disconnected(&peer);
                 }
               }
-            },
-            SwarmEvent::Behaviour(Event::Client(client::Event::ReservationReqAccepted {
-                ..
-            })) => {
-                info!("Relay accepted our reservation request.");
-            }
-            SwarmEvent::Behaviour(Event::Client(event)) => {
-                info!("{:?}", event)
             }
-            SwarmEvent::Behaviour(Event::Dcutr(event)) => {
-                info!("{:?}", event)
+          }
+        }
+      }
+      SwarmEvent::Behaviour(Event::Client(relay::client::Event::ReservationReqAccepted {
+        relay_peer_id,
+        renewal,
+        ..
+      })) => {
+        if !renewal {
+          info!("Relay {relay_peer_id} accepted our reservation request.");
+        }
+      }
+      SwarmEvent::Behaviour(Event::Client(event)) => {
+        info!("{:?}", event)
+      }
+      SwarmEvent::Behaviour(Event::Autonat(autonat::Event::StatusChanged { old, new })) => {
+        debug!("NAT status changed from {old:?} to {new:?}");
+        self.handle_nat_status(new);
+      }
+      SwarmEvent::Behaviour(Event::Autonat(event)) => {
+        debug!("AutoNAT: {event:?}");
+      }
+      SwarmEvent::Behaviour(Event::Dcutr(event)) => {
+        info!("{:?}", event)
+      }
+      SwarmEvent::Behaviour(Event::Direct(event)) => {
+        self.handle_direct_event(event);
+      }
+      SwarmEvent::Behaviour(Event::Sync(event)) => {
+        self.handle_sync_event(event).await;
+      }
+      SwarmEvent::Behaviour(Event::Mailbox(event)) => {
+        self.handle_mailbox_event(event);
+      }
+      SwarmEvent::Behaviour(Event::Identify(event)) => {
+        info!("Identify: {:?}", event);
+        if let identify::Event::Sent { peer_id, .. } = &event {
+          info!("Told {peer_id} its public address.");
+          self.handshakes.entry(*peer_id).or_default().identify_sent = true;
+        }
+        if let identify::Event::Received {
+          peer_id,
+          info:
+            identify::Info {
+              listen_addrs,
+              protocols,
+              observed_addr,
+              ..
+            },
+          ..
+        } = event
+        {
+          info!("{peer_id} told us our public address: {observed_addr:?}");
+          self
+            .handshakes
+            .entry(peer_id)
+            .or_default()
+            .identify_received = true;
+          #[cfg(feature = "quic")]
+          let listen_addrs = prefer_quic(listen_addrs);
+          if protocols.iter().any(|p| p == &RELAY_HOP_PROTOCOL) {
+            if let Some(addr) = listen_addrs.iter().find(|addr| !is_circuit(addr)) {
+              self.add_relay(peer_id, addr.clone().with(Protocol::P2p(peer_id)));
             }
-            SwarmEvent::Behaviour(Event::Identify(event)) => {
-                info!("Identify: {:?}", event);
-                if let IdentifyEvent::Received {
-                  peer_id,
-                  info:
-                    IdentifyInfo {
-                      listen_addrs,
-                      protocols,
-                      ..
-                    },
-                } = event
-                {
-                  if protocols
-                    .iter()
-                    .any(|p| p.as_bytes() == libp2p::kad::protocol::DEFAULT_PROTO_NAME)
-                  {
-                    for addr in listen_addrs {
-                      self.swarm
-                        .behaviour_mut()
-                        .kademlia
-                        .add_address(&peer_id, addr);
-                    }
-                  }
-                }
+          }
+          if protocols.contains(&kad::PROTOCOL_NAME) {
+            // Kademlia hands addresses to the swarm in the order they were added.
+            for addr in listen_addrs {
+              self
+                .swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, addr);
             }
-            SwarmEvent::Behaviour(Event::Ping(e)) => {
-              debug!("Ping: {e:?}");
+          }
+        }
+      }
+      SwarmEvent::Behaviour(Event::Kademlia(event)) => {
+        self.handle_kademlia_event(event);
+      }
+      SwarmEvent::Behaviour(Event::Ping(e)) => {
+        debug!("Ping: {e:?}");
+      }
+      SwarmEvent::ConnectionEstablished {
+        peer_id, endpoint, ..
+      } => {
+        info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+        self.handshakes.entry(peer_id).or_default().connected = true;
+        if self.mailboxes.contains(&peer_id) {
+          self.fetch_mail(peer_id);
+        }
+        if self.failed_relays.remove(&peer_id) {
+          // The relay is back, it may take a reservation again.
+          self.reserve_relays();
+        }
+        self.presence.connected(&peer_id);
+        self
+          .swarm
+          .behaviour_mut()
+          .gossipsub
+          .add_explicit_peer(&peer_id);
+      }
+      SwarmEvent::ConnectionClosed {
+        peer_id,
+        num_established,
+        cause,
+        ..
+      } => {
+        debug!("Connection to {peer_id} closed: {cause:?}");
+        if num_established == 0 {
+          self.presence.disconnected(&peer_id);
+          self.handshakes.remove(&peer_id);
+        }
+      }
+      SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+        debug!("Outgoing connection error to {peer_id:?}: {error:?}");
+      }
+      event => info!("Other: {event:?}"),
+    }
+  }
+
+  /// Runs a slash command typed on the console, breaking when the node should stop.
+  async fn handle_console_command(&mut self, command: ConsoleCommand) -> ControlFlow<()> {
+    match command {
+      ConsoleCommand::Join(room) => {
+        if let Err(e) = self.join_room(&room).await {
+          println!("{e}");
+        }
+      }
+      ConsoleCommand::Leave(room) => match room.or_else(|| self.current_room.clone()) {
+        Some(room) => {
+          if let Err(e) = self.leave_room(&room).await {
+            println!("{e}");
+          }
+        }
+        None => println!("Not in any room"),
+      },
+      ConsoleCommand::Rooms => {
+        for room in self.rooms.keys() {
+          let marker = match self.current_room.as_ref() == Some(room) {
+            true => "*",
+            false => " ",
+          };
+          let private = match self.private_rooms.get(room) {
+            Some(_) => " (private)",
+            None => "",
+          };
+          println!("{marker} {room}{private}");
+        }
+      }
+      ConsoleCommand::Peers => {
+        for peer_id in self.swarm.connected_peers() {
+          println!("{peer_id}");
+        }
+      }
+      ConsoleCommand::Dial(addr) => {
+        if let Err(e) = self.swarm.dial(addr) {
+          println!("Dial failed: {e}");
+        }
+      }
+      ConsoleCommand::Nick(nickname) => self.claim_nickname(&nickname),
+      ConsoleCommand::Msg {
+        peer: PeerRef::Id(peer),
+        content,
+      } => self.send_direct(peer, content),
+      ConsoleCommand::Msg {
+        peer: PeerRef::Nickname(nickname),
+        content,
+      } => self.send_direct_to_nickname(&nickname, content),
+      ConsoleCommand::Private(room) => {
+        if let Err(e) = self.create_private_room(&room).await {
+          println!("{e}");
+        }
+      }
+      ConsoleCommand::Invite(peer) => {
+        if let Err(e) = self.invite(peer) {
+          println!("{e}");
+        }
+      }
+      ConsoleCommand::Accept { room, owner } => {
+        if let Err(e) = self.accept_invite(&room, &owner).await {
+          println!("{e}");
+        }
+      }
+      ConsoleCommand::Kick(peer) => match self.current_room.clone() {
+        Some(room) => {
+          if let Err(e) = self.remove_member(&room, &peer) {
+            println!("{e}");
+          }
+        }
+        None => println!("Not in any room"),
+      },
+      ConsoleCommand::WhoAmI => {
+        println!("Peer id: {}", self.swarm.local_peer_id());
+        println!("Nickname: {}", self.nickname.as_deref().unwrap_or("-"));
+        println!("Room: {}", self.current_room.as_deref().unwrap_or("-"));
+        for addr in self.swarm.listeners() {
+          println!("Listening on: {addr}");
+        }
+      }
+      ConsoleCommand::History(minutes) => match (self.current_room.clone(), &self.history) {
+        (Some(room), Some(history)) => {
+          let now = Utc::now();
+          let messages = match minutes {
+            Some(minutes) => history.range(&room, now - chrono::Duration::minutes(minutes), now),
+            None => history.last(&room, HISTORY_REPLAY_MESSAGES),
+          };
+          match messages {
+            Ok(messages) => print_history(&room, &messages),
+            Err(e) => println!("{e}"),
+          }
+        }
+        (None, _) => println!("Not in any room"),
+        (_, None) => println!("Message history is disabled, start with --history <dir>"),
+      },
+      ConsoleCommand::Who(room) => match room.or_else(|| self.current_room.clone()) {
+        Some(room) if self.rooms.contains_key(&room) => {
+          println!(
+            "{} (you): {}",
+            self.swarm.local_peer_id(),
+            self.presence.own_status()
+          );
+          for (peer, status, nickname) in self.presence.members(&room) {
+            match nickname {
+              Some(nickname) => println!("{nickname} ({peer}): {status}"),
+              None => println!("{peer}: {status}"),
             }
-            SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
-            } => {
-                info!("Established connection to {:?} via {:?}", peer_id, endpoint);
-                self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+          }
+        }
+        Some(room) => println!("Not in {room}"),
+        None => println!("Not in any room"),
+      },
+      ConsoleCommand::Discover(room) => match room.or_else(|| self.current_room.clone()) {
+        Some(room) => self.discover_room(&room, true),
+        None => println!("Not in any room, name the room to discover"),
+      },
+      ConsoleCommand::Dht => {
+        let state = match self.dht.is_bootstrapped() {
+          true => "bootstrapped",
+          false => "not bootstrapped",
+        };
+        println!(
+          "{state}, {} peers in the routing table",
+          self.dht.routing_peers()
+        );
+        for (id, description) in self.dht.pending() {
+          println!("pending {id:?}: {description}");
+        }
+      }
+      ConsoleCommand::Quit => {
+        // Best effort, the node may stop before the heartbeats go out.
+        self.send_heartbeats(Status::Offline);
+        return ControlFlow::Break(());
+      }
+      ConsoleCommand::Help => println!("{}", console::HELP),
+    }
+    ControlFlow::Continue(())
+  }
+
+  /// Subscribes to `room` and makes it the current room.
+  async fn join_room(&mut self, room: &str) -> Result<()> {
+    if self.rooms.contains_key(room) {
+      self.current_room = Some(room.to_owned());
+      return Ok(());
+    }
+
+    let topic = IdentTopic::new(room);
+    self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .subscribe(&topic)
+      .map_err(|e| anyhow!("failed to join {room}: {e:?}"))?;
+    self.rooms.insert(room.to_owned(), topic);
+    self.current_room = Some(room.to_owned());
+    if let Err(e) = self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .subscribe(&presence::topic(room))
+    {
+      error!("Failed to follow the presence of {room}: {e:?}");
+    }
+    self.send_heartbeat(room, self.presence.own_status());
+
+    if let Some(history) = &self.history {
+      match history.last(room, HISTORY_REPLAY_MESSAGES) {
+        Ok(messages) => print_history(room, &messages),
+        Err(e) => error!("Failed to load the history of {room}: {e:?}"),
+      }
+      // Private rooms aren't rejoined, their keys only live in memory.
+      if self.private_rooms.get(room).is_none() {
+        if let Err(e) = history.save_room(room) {
+          error!("Failed to remember {room}: {e:?}");
+        }
+      }
+    }
+
+    let command = ChatRoomCommand::JoinRoom {
+      member: self.swarm.local_peer_id().to_string(),
+    };
+    if let Err(e) = self.chat.execute(room, command).await {
+      error!("{e}");
+    }
+    info!("Joined {room}");
+
+    match self
+      .swarm
+      .behaviour_mut()
+      .kademlia
+      .start_providing(discovery::room_key(room))
+    {
+      Ok(id) => self.dht.track(id, format!("announcement of {room}")),
+      Err(e) => error!("Could not announce {room} on the DHT: {e:?}"),
+    }
+    // Members found on the DHT join our mesh even when mDNS and the bootnodes don't connect us.
+    self.discover_room(room, false);
+
+    let topic_hash = IdentTopic::new(room).hash();
+    let members: Vec<PeerId> = self
+      .swarm
+      .behaviour()
+      .gossipsub
+      .all_peers()
+      .filter(|(_, topics)| topics.contains(&&topic_hash))
+      .map(|(peer_id, _)| *peer_id)
+      .collect();
+    for peer_id in members {
+      self.request_sync(peer_id, room);
+    }
+    Ok(())
+  }
+
+  /// Unsubscribes from `room`, switching the current room to another joined one if needed.
+  async fn leave_room(&mut self, room: &str) -> Result<()> {
+    let topic = self
+      .rooms
+      .remove(room)
+      .ok_or_else(|| anyhow!("not in {room}"))?;
+    self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .unsubscribe(&topic)
+      .map_err(|e| anyhow!("failed to leave {room}: {e:?}"))?;
+    self.send_heartbeat(room, Status::Offline);
+    if let Err(e) = self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .unsubscribe(&presence::topic(room))
+    {
+      error!("Failed to stop following the presence of {room}: {e:?}");
+    }
+    self.presence.leave(room);
+    if let Some(history) = &self.history {
+      if let Err(e) = history.forget_room(room) {
+        error!("Failed to forget {room}: {e:?}");
+      }
+    }
+    self
+      .swarm
+      .behaviour_mut()
+      .kademlia
+      .stop_providing(&discovery::room_key(room));
+    if self.current_room.as_deref() == Some(room) {
+      self.current_room = self.rooms.keys().next().cloned();
+    }
+    // The owner of a private room rotates its key once a member leaves.
+    let local_peer_id = *self.swarm.local_peer_id();
+    if let Some(private) = self.private_rooms.forget(room) {
+      if private.owner() != &local_peer_id {
+        let request = DirectRequest::RoomLeft {
+          room: room.to_owned(),
+        };
+        self.send_request(*private.owner(), request, format!("leaving {room}"));
+      }
+    }
+
+    let command = ChatRoomCommand::LeaveRoom {
+      member: self.swarm.local_peer_id().to_string(),
+    };
+    if let Err(e) = self.chat.execute(room, command).await {
+      error!("{e}");
+    }
+    info!("Left {room}");
+    Ok(())
+  }
+
+  /// Records `content` as a local chat message and publishes it to `room`.
+  ///
+  /// Messages to private rooms are sealed with the current room key.
+  async fn publish(&mut self, room: &str, content: String) {
+    let topic = match self.rooms.get(room) {
+      Some(topic) => topic.clone(),
+      None => {
+        error!("Not in {room}, message dropped");
+        return;
+      }
+    };
+    let envelope = Envelope::text(content, self.nickname.clone(), None);
+    let envelope = match envelope.sign(&self.local_key, room) {
+      Ok(envelope) => envelope,
+      Err(e) => {
+        error!("Could not sign a message for {room}: {e}");
+        return;
+      }
+    };
+    self.seen.insert(envelope.id);
+    let wire = match self.private_rooms.get(room) {
+      Some(private) => {
+        let (epoch, key) = private.current();
+        envelope.seal(key, KeyRef::Room { epoch })
+      }
+      None => envelope.clone(),
+    };
+    let message = ChatMessage::from_envelope(
+      self.swarm.local_peer_id().to_string(),
+      &envelope,
+      wire.is_encrypted(),
+    );
+    if let Err(e) = self
+      .chat
+      .execute(room, ChatRoomCommand::SendMessage(message))
+      .await
+    {
+      error!("{e}");
+      return;
+    }
+    if let Err(e) = self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .publish(topic, wire.encode())
+    {
+      error!("{e:?}");
+    }
+  }
+
+  /// Tells every room we're in that we're `status`.
+  fn send_heartbeats(&mut self, status: Status) {
+    let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+    for room in rooms {
+      self.send_heartbeat(&room, status);
+    }
+    self.presence.heartbeat_sent();
+  }
+
+  fn send_heartbeat(&mut self, room: &str, status: Status) {
+    let heartbeat = match Heartbeat::new(&self.local_key, room, status, self.nickname.clone()) {
+      Ok(heartbeat) => heartbeat,
+      Err(e) => {
+        error!("Could not sign a heartbeat for {room}: {e}");
+        return;
+      }
+    };
+    // Fails while no other member is around, which is fine for a heartbeat.
+    if let Err(e) = self
+      .swarm
+      .behaviour_mut()
+      .gossipsub
+      .publish(presence::topic(room), heartbeat.encode())
+    {
+      debug!("No heartbeat sent to {room}: {e:?}");
+    }
+  }
+
+  /// Updates the presence table from a validated heartbeat of `peer` in `room`.
+  fn receive_heartbeat(&mut self, room: &str, peer: PeerId, heartbeat: Heartbeat) {
+    let nickname = self.verified_nickname(Some(peer), heartbeat.nickname);
+    let name = nickname.clone().unwrap_or_else(|| peer.to_string());
+    if let Some(status) = self.presence.heard(room, peer, heartbeat.status, nickname) {
+      info!("[{room}] {name} is {status}");
+    }
+  }
+
+  /// Key shared with `peer` for direct messages, derived once per peer.
+  fn direct_key(&mut self, peer: &PeerId) -> Result<SymmetricKey> {
+    if let Some(key) = self.direct_keys.get(peer) {
+      return Ok(*key);
+    }
+    let key = crypto::direct_key(&self.local_key, peer)?;
+    self.direct_keys.insert(*peer, key);
+    Ok(key)
+  }
+
+  /// Sends an encrypted direct message to `peer`.
+  fn send_direct(&mut self, peer: PeerId, content: String) {
+    let key = match self.direct_key(&peer) {
+      Ok(key) => key,
+      Err(e) => {
+        println!("Can't encrypt for {peer}: {e}");
+        return;
+      }
+    };
+    let envelope = Envelope::text(content, self.nickname.clone(), None);
+    let sealed = ByteBuf::from(envelope.seal(&key, KeyRef::Direct).encode());
+    let request = DirectRequest::Message {
+      envelope: sealed.clone(),
+    };
+    let request_id = self.send_request(peer, request, format!("direct message {}", envelope.id));
+    self.undelivered.insert(request_id, sealed);
+  }
+
+  /// Sends `request` to `peer`, through a relay circuit when there is no direct connection.
+  fn send_request(
+    &mut self,
+    peer: PeerId,
+    request: DirectRequest,
+    description: String,
+  ) -> OutboundRequestId {
+    if !self.swarm.is_connected(&peer) {
+      for circuit_addr in self.circuit_addrs(&peer) {
+        self.swarm.add_peer_address(peer, circuit_addr);
+      }
+    }
+
+    let request_id = self
+      .swarm
+      .behaviour_mut()
+      .direct
+      .send_request(&peer, request);
+    self.pending_direct.insert(request_id, (peer, description));
+    request_id
+  }
+
+  /// Addresses reaching `peer` through each of our relays.
+  ///
+  /// DCUtR upgrades the relayed connection to a direct one when hole punching succeeds.
+  fn circuit_addrs(&self, peer: &PeerId) -> Vec<Multiaddr> {
+    self
+      .relays
+      .iter()
+      .map(|(_, relay_addr)| {
+        relay_addr
+          .clone()
+          .with(Protocol::P2pCircuit)
+          .with(Protocol::P2p(*peer))
+      })
+      .collect()
+  }
+
+  fn handle_kademlia_event(&mut self, event: kad::Event) {
+    self
+      .dht
+      .handle_event(&mut self.swarm.behaviour_mut().kademlia, &event);
+    match event {
+      kad::Event::OutboundQueryProgressed {
+        id,
+        result: QueryResult::GetRecord(result),
+        step,
+        ..
+      } => {
+        // Records come one per step, the lookup is settled once they're all in.
+        match result {
+          Ok(GetRecordOk::FoundRecord(PeerRecord { record, .. })) => {
+            self.nicknames.found(&id, record)
+          }
+          Err(GetRecordError::QuorumFailed { records, .. }) => {
+            for PeerRecord { record, .. } in records {
+              self.nicknames.found(&id, record);
             }
-            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
-                debug!("Outgoing connection error to {peer_id:?}: {error:?}");
+          }
+          Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) | Err(_) => {}
+        }
+        if !step.last {
+          return;
+        }
+        if let Some((lookup, records)) = self.nicknames.finish(&id) {
+          self.handle_nickname_lookup(lookup, &records);
+        }
+      }
+      kad::Event::OutboundQueryProgressed {
+        result: QueryResult::PutRecord(result),
+        ..
+      } => match result {
+        Ok(ok) if nickname::is_nickname_key(&ok.key) => info!("Published our nickname"),
+        Err(e) if nickname::is_nickname_key(e.key()) => {
+          warn!("Our nickname is only stored locally until the next renewal: {e:?}")
+        }
+        _ => {}
+      },
+      kad::Event::OutboundQueryProgressed {
+        id,
+        result: QueryResult::GetProviders(result),
+        step,
+        ..
+      } => {
+        if let Ok(GetProvidersOk::FoundProviders { providers, .. }) = result {
+          if let Some(discovery) = self.discoveries.get_mut(&id) {
+            discovery.providers.extend(providers);
+          }
+        }
+        if !step.last {
+          return;
+        }
+        if let Some(discovery) = self.discoveries.remove(&id) {
+          self.handle_discovery(discovery);
+        }
+      }
+      kad::Event::OutboundQueryProgressed {
+        result: QueryResult::StartProviding(Err(e)),
+        ..
+      } if discovery::is_room_key(e.key()) => {
+        warn!("Our room membership is only stored locally until it's announced again: {e:?}");
+      }
+      _ => {}
+    }
+  }
+
+  /// Looks up the members of `room` on the DHT, printing them if `requested` on the console.
+  fn discover_room(&mut self, room: &str, requested: bool) {
+    let id = self
+      .swarm
+      .behaviour_mut()
+      .kademlia
+      .get_providers(discovery::room_key(room));
+    self.dht.track(id, format!("discovery of {room}"));
+    self.discoveries.insert(
+      id,
+      RoomDiscovery {
+        room: room.to_owned(),
+        requested,
+        providers: HashSet::new(),
+      },
+    );
+  }
+
+  /// Dials the members of a room we aren't connected to yet, through our relays if need be.
+  fn handle_discovery(&mut self, discovery: RoomDiscovery) {
+    let local_peer_id = *self.swarm.local_peer_id();
+    let members: Vec<PeerId> = discovery
+      .providers
+      .into_iter()
+      .filter(|peer| *peer != local_peer_id)
+      .collect();
+    let room = discovery.room;
+    debug!("Found {} members of {room} on the DHT", members.len());
+    if discovery.requested && members.is_empty() {
+      println!("No other member of {room} found");
+    }
+    for member in members {
+      let connected = self.swarm.is_connected(&member);
+      if discovery.requested {
+        let state = match connected {
+          true => "connected",
+          false => "dialing",
+        };
+        println!("{member} ({state})");
+      }
+      if connected {
+        continue;
+      }
+      // Kademlia adds the addresses it knows to the circuit ones.
+      let dial = DialOpts::peer_id(member)
+        .addresses(self.circuit_addrs(&member))
+        .extend_addresses_through_behaviour()
+        .build();
+      if let Err(e) = self.swarm.dial(dial) {
+        debug!("Could not dial {member}, a member of {room}: {e}");
+      }
+    }
+  }
+
+  /// Looks `nickname` up on the DHT, the outcome being handled according to `lookup`.
+  fn lookup_nickname(&mut self, lookup: Lookup) {
+    let nickname = lookup.nickname().to_owned();
+    let id = self
+      .swarm
+      .behaviour_mut()
+      .kademlia
+      .get_record(nickname::key(&nickname));
+    self.dht.track(id, format!("lookup of nickname {nickname}"));
+    self.nicknames.start(id, lookup);
+  }
+
+  /// Claims `nickname` on the DHT, unless another peer claimed it first.
+  fn claim_nickname(&mut self, nickname: &str) {
+    if let Err(e) = nickname::validate(nickname) {
+      println!("{e}");
+      return;
+    }
+    self.lookup_nickname(Lookup::Claim(nickname::normalize(nickname)));
+  }
+
+  /// Signs a claim of `nickname` and stores it in the DHT.
+  fn publish_nickname(&mut self, nickname: &str, claimed_at: DateTime<Utc>) {
+    let ttl = self.nicknames.ttl();
+    let claim = match NicknameClaim::new(&self.local_key, nickname, claimed_at, ttl) {
+      Ok(claim) => claim,
+      Err(e) => {
+        error!("Could not sign a claim of {nickname}: {e}");
+        return;
+      }
+    };
+    let local_peer_id = *self.swarm.local_peer_id();
+    let record = claim.record(local_peer_id);
+    match self
+      .swarm
+      .behaviour_mut()
+      .kademlia
+      .put_record(record, Quorum::One)
+    {
+      Ok(id) => self
+        .dht
+        .track(id, format!("publication of nickname {nickname}")),
+      Err(e) => error!("Could not store the claim of {nickname}: {e:?}"),
+    }
+    self.nicknames.resolved(local_peer_id, &claim);
+    self.nicknames.set_own(claim);
+    self.nickname = Some(nickname.to_owned());
+  }
+
+  /// Republishes our claim before it expires, keeping its original claim time.
+  fn renew_nickname(&mut self) {
+    if let Some((nickname, claimed_at)) = self
+      .nicknames
+      .own()
+      .map(|claim| (claim.nickname.clone(), claim.claimed_at))
+    {
+      debug!("Renewing our claim of {nickname}");
+      self.publish_nickname(&nickname, claimed_at);
+    }
+  }
+
+  fn handle_nickname_lookup(&mut self, lookup: Lookup, records: &[Record]) {
+    let local_peer_id = *self.swarm.local_peer_id();
+    let winner = nickname::winner(records);
+    if let Some((holder, claim)) = &winner {
+      self.nicknames.resolved(*holder, claim);
+    }
+    match lookup {
+      Lookup::Claim(nickname) => match winner {
+        Some((holder, _)) if holder != local_peer_id => {
+          println!("{nickname} is taken by {holder}");
+        }
+        Some((_, claim)) => {
+          self.publish_nickname(&nickname, claim.claimed_at);
+          println!("You are still known as {nickname}");
+        }
+        None => {
+          self.publish_nickname(&nickname, Utc::now());
+          println!("You are now known as {nickname}");
+        }
+      },
+      Lookup::Resolve { nickname, queued } => match winner {
+        Some((holder, _)) => {
+          for content in queued {
+            self.send_direct(holder, content);
+          }
+        }
+        None => println!(
+          "No peer is known as {nickname}, {} messages dropped",
+          queued.len()
+        ),
+      },
+      Lookup::Verify(nickname) => match winner {
+        Some((holder, _)) => debug!("{nickname} is held by {holder}"),
+        None => debug!("No one holds {nickname}"),
+      },
+    }
+  }
+
+  /// Sends a direct message to the holder of `nickname`, looking it up first if needed.
+  fn send_direct_to_nickname(&mut self, nickname: &str, content: String) {
+    if let Some(peer) = self.nicknames.holder(nickname) {
+      self.send_direct(peer, content);
+      return;
+    }
+    if let Some(content) = self.nicknames.queue(nickname, content) {
+      self.lookup_nickname(Lookup::Resolve {
+        nickname: nickname::normalize(nickname),
+        queued: vec![content],
+      });
+    }
+  }
+
+  /// The nickname `sender` signed its message with, if the registry says it holds it.
+  ///
+  /// Unknown nicknames are looked up for the next messages and not shown meanwhile.
+  fn verified_nickname(
+    &mut self,
+    sender: Option<PeerId>,
+    claimed: Option<String>,
+  ) -> Option<String> {
+    let (sender, nickname) = (sender?, claimed?);
+    match self.nicknames.holder(&nickname) {
+      Some(holder) if holder == sender => Some(nickname),
+      Some(holder) => {
+        warn!("{sender} calls itself {nickname}, which is held by {holder}");
+        None
+      }
+      None => {
+        if nickname::validate(&nickname).is_ok() && !self.nicknames.is_looking_up(&nickname) {
+          self.lookup_nickname(Lookup::Verify(nickname::normalize(&nickname)));
+        }
+        None
+      }
+    }
+  }
+
+  /// Reserves slots on relays while we're unreachable, and gives them up once we're public.
+  fn handle_nat_status(&mut self, status: NatStatus) {
+    match status {
+      NatStatus::Private => {
+        info!(
+          "Behind a NAT, reserving slots on {} relays",
+          self.relay_reservations
+        );
+        self.reserve_relays();
+      }
+      NatStatus::Public(addr) => {
+        info!("Publicly reachable at {addr}");
+        for (relay, listener) in self.relay_listeners.drain() {
+          info!("Dropping the reservation on {relay}");
+          let _ = self.swarm.remove_listener(listener);
+        }
+      }
+      NatStatus::Unknown => {}
+    }
+  }
+
+  /// Adds `relay` as a candidate, reserving on it if we're short of reservations.
+  fn add_relay(&mut self, relay: PeerId, addr: Multiaddr) {
+    if self.relays.iter().any(|(peer_id, _)| peer_id == &relay) {
+      return;
+    }
+    debug!("Relay candidate {relay} at {addr}");
+    self.relays.push((relay, addr));
+    self.reserve_relays();
+  }
+
+  /// Reserves slots on relays until `relay_reservations` are held, while AutoNAT finds us
+  /// unreachable.
+  fn reserve_relays(&mut self) {
+    if !matches!(
+      self.swarm.behaviour().autonat.nat_status(),
+      NatStatus::Private
+    ) {
+      return;
+    }
+    let missing = self
+      .relay_reservations
+      .saturating_sub(self.relay_listeners.len());
+    if missing == 0 {
+      return;
+    }
+    let candidates: Vec<(PeerId, Multiaddr)> = self
+      .relays
+      .iter()
+      .filter(|(relay, _)| {
+        !self.relay_listeners.contains_key(relay) && !self.failed_relays.contains(relay)
+      })
+      .take(missing)
+      .cloned()
+      .collect();
+    if candidates.len() < missing {
+      warn!(
+        "Behind a NAT, but only {} of {} relays can be reserved on",
+        self.relay_listeners.len() + candidates.len(),
+        self.relay_reservations
+      );
+    }
+    for (relay, addr) in candidates {
+      info!("Reserving a slot on {addr}");
+      match self.swarm.listen_on(addr.with(Protocol::P2pCircuit)) {
+        Ok(listener) => {
+          self.relay_listeners.insert(relay, listener);
+        }
+        Err(e) => {
+          error!("Relay reservation on {relay} failed: {e:?}");
+          self.failed_relays.insert(relay);
+        }
+      }
+    }
+  }
+
+  /// Drops the reservation on `relay` and reserves on another one instead.
+  fn replace_relay(&mut self, relay: PeerId) {
+    if let Some(listener) = self.relay_listeners.remove(&relay) {
+      let _ = self.swarm.remove_listener(listener);
+    }
+    self.failed_relays.insert(relay);
+    self.reserve_relays();
+  }
+
+  /// Leaves a sealed direct message for `recipient` with a bootstrap node's mailbox.
+  fn deposit(&mut self, recipient: PeerId, envelope: ByteBuf) {
+    let mailbox = match self.mailboxes.first() {
+      Some(mailbox) => *mailbox,
+      None => {
+        error!("{recipient} is offline and no mailbox is known, message dropped");
+        return;
+      }
+    };
+    info!("{recipient} is offline, leaving the message with {mailbox}");
+    let request = MailboxRequest::Deposit {
+      recipient: recipient.to_string(),
+      envelope,
+    };
+    self
+      .swarm
+      .behaviour_mut()
+      .mailbox
+      .send_request(&mailbox, request);
+  }
+
+  /// Asks `mailbox` for direct messages sent while this node was offline.
+  fn fetch_mail(&mut self, mailbox: PeerId) {
+    debug!("Fetching mail from {mailbox}");
+    self
+      .swarm
+      .behaviour_mut()
+      .mailbox
+      .send_request(&mailbox, MailboxRequest::Fetch);
+  }
+
+  fn handle_mailbox_event(
+    &mut self,
+    event: request_response::Event<MailboxRequest, MailboxResponse>,
+  ) {
+    match event {
+      request_response::Event::Message {
+        peer,
+        message: request_response::Message::Response { response, .. },
+      } => match response {
+        MailboxResponse::Stored { id } => {
+          info!("{peer} holds message {id} until its recipient is back")
+        }
+        MailboxResponse::Mail(mail) => {
+          let mut ids = Vec::with_capacity(mail.len());
+          for Mail {
+            id,
+            sender,
+            envelope,
+          } in mail
+          {
+            let result = self.receive_mail(&sender, &envelope);
+            if let Err(e) = result {
+              warn!("Dropped message {id} held by {peer}: {e}");
             }
-            event => info!("Other: {event:?}"),
+            // Undecryptable messages are acknowledged too, they would never get better.
+            ids.push(id);
+          }
+          if !ids.is_empty() {
+            info!("Received {} messages held by {peer}", ids.len());
+            self
+              .swarm
+              .behaviour_mut()
+              .mailbox
+              .send_request(&peer, MailboxRequest::Ack { ids });
           }
         }
+        MailboxResponse::Acked => debug!("{peer} dropped the messages it held for us"),
+        MailboxResponse::Rejected { reason } => {
+          error!("Mailbox {peer} rejected a request: {reason}")
+        }
+      },
+      request_response::Event::OutboundFailure { peer, error, .. } => {
+        debug!("Mailbox request to {peer} failed: {error:?}");
+      }
+      event => debug!("{event:?}"),
+    }
+  }
+
+  /// Seals the key of `room` for `member` and sends it.
+  fn send_room_key(&mut self, room: &str, member: PeerId, epoch: u32, key: SymmetricKey) {
+    let direct_key = match self.direct_key(&member) {
+      Ok(key) => key,
+      Err(e) => {
+        error!("Can't hand the key of {room} to {member}: {e}");
+        return;
       }
+    };
+    let grant = RoomKeyGrant {
+      epoch,
+      key: ByteBuf::from(key.to_vec()),
+    };
+    let encoded = serde_cbor::to_vec(&grant).expect("room key grant is always serializable");
+    let (nonce, sealed) = crypto::seal(&direct_key, room.as_bytes(), &encoded);
+    let request = DirectRequest::RoomKey {
+      room: room.to_owned(),
+      nonce: ByteBuf::from(nonce),
+      sealed: ByteBuf::from(sealed),
+    };
+    self.send_request(member, request, format!("key {epoch} of {room}"));
+  }
+
+  /// Creates a private room owned by this node and joins it.
+  async fn create_private_room(&mut self, room: &str) -> Result<()> {
+    if self.rooms.contains_key(room) {
+      bail!("already in {room}, pick another name for a private room");
     }
+    let local_peer_id = *self.swarm.local_peer_id();
+    self.private_rooms.create(room, local_peer_id)?;
+    self.join_room(room).await
+  }
+
+  fn invite(&mut self, member: PeerId) -> Result<()> {
+    let room = self
+      .current_room
+      .clone()
+      .ok_or_else(|| anyhow!("not in any room"))?;
+    let local_peer_id = *self.swarm.local_peer_id();
+    let (epoch, key) = self.private_rooms.invite(&room, &local_peer_id, member)?;
+    self.send_room_key(&room, member, epoch, key);
+    Ok(())
+  }
+
+  /// Removes `member` from the private `room` and hands a fresh key to everyone left.
+  fn remove_member(&mut self, room: &str, member: &PeerId) -> Result<()> {
+    let local_peer_id = *self.swarm.local_peer_id();
+    let (epoch, key, members) = self
+      .private_rooms
+      .remove_member(room, &local_peer_id, member)?;
+    info!("Rotated the key of {room} to epoch {epoch} after {member} left");
+    for member in members {
+      self.send_room_key(room, member, epoch, key);
+    }
+    Ok(())
+  }
+
+  fn handle_direct_event(&mut self, event: request_response::Event<DirectRequest, DirectResponse>) {
+    match event {
+      request_response::Event::Message {
+        peer,
+        message: request_response::Message::Request {
+          request, channel, ..
+        },
+      } => {
+        let response = self.handle_direct_request(peer, request);
+        if self
+          .swarm
+          .behaviour_mut()
+          .direct
+          .send_response(channel, response)
+          .is_err()
+        {
+          warn!("Connection to {peer} closed before the direct request was acknowledged");
+        }
+      }
+      request_response::Event::Message {
+        peer,
+        message:
+          request_response::Message::Response {
+            request_id,
+            response,
+          },
+      } => {
+        self.undelivered.remove(&request_id);
+        let description = self
+          .pending_direct
+          .remove(&request_id)
+          .map(|(_, description)| description)
+          .unwrap_or_else(|| "direct request".to_owned());
+        match response {
+          DirectResponse::Delivered { id } => info!("Direct message {id} delivered to {peer}"),
+          DirectResponse::Accepted => info!("{peer} accepted {description}"),
+          DirectResponse::Rejected { reason } => {
+            error!("{peer} rejected {description}: {reason}")
+          }
+        }
+      }
+      request_response::Event::OutboundFailure {
+        peer,
+        request_id,
+        error,
+      } => {
+        let undelivered = self.undelivered.remove(&request_id);
+        if let Some((_, description)) = self.pending_direct.remove(&request_id) {
+          error!("Sending {description} to {peer} failed: {error:?}");
+        }
+        if let (Some(envelope), OutboundFailure::DialFailure) = (undelivered, error) {
+          self.deposit(peer, envelope);
+        }
+      }
+      request_response::Event::InboundFailure { peer, error, .. } => {
+        debug!("Inbound direct request from {peer} failed: {error:?}");
+      }
+      request_response::Event::ResponseSent { .. } => {}
+    }
+  }
+
+  fn handle_direct_request(&mut self, peer: PeerId, request: DirectRequest) -> DirectResponse {
+    let result = match request {
+      DirectRequest::Message { envelope } => self.receive_direct(peer, &envelope),
+      DirectRequest::RoomKey {
+        room,
+        nonce,
+        sealed,
+      } => self.accept_room_key(peer, &room, &nonce, &sealed),
+      DirectRequest::RoomLeft { room } => self
+        .remove_member(&room, &peer)
+        .map(|_| DirectResponse::Accepted),
+    };
+    result.unwrap_or_else(|e| {
+      warn!("Rejected direct request from {peer}: {e}");
+      DirectResponse::Rejected {
+        reason: e.to_string(),
+      }
+    })
+  }
+
+  /// Receives a direct message held by a mailbox for us.
+  ///
+  /// The mailbox names the sender itself, so only sealed messages are accepted: opening them
+  /// with the key shared with `sender` is what proves who wrote them.
+  fn receive_mail(&mut self, sender: &str, data: &[u8]) -> Result<DirectResponse> {
+    let sender = sender
+      .parse::<PeerId>()
+      .map_err(|_| anyhow!("{sender} is not a valid peer id"))?;
+    if !Envelope::decode(data)?.is_encrypted() {
+      bail!("mailboxes only hand out encrypted messages, {sender} sent one in the clear");
+    }
+    self.receive_direct(sender, data)
+  }
+
+  fn receive_direct(&mut self, peer: PeerId, data: &[u8]) -> Result<DirectResponse> {
+    let envelope = Envelope::decode(data)?;
+    let (envelope, encrypted) = match envelope.is_encrypted() {
+      true => {
+        let key = self.direct_key(&peer)?;
+        match envelope.open(&key) {
+          Ok(inner) => (inner, true),
+          Err(e) => {
+            warn!(
+              "[dm] Could not decrypt message {} from {peer}: {e}",
+              envelope.id
+            );
+            return Err(e.into());
+          }
+        }
+      }
+      false => (envelope, false),
+    };
+    let mut message = ChatMessage::from_envelope(peer.to_string(), &envelope, encrypted);
+    message.nickname = self.verified_nickname(Some(peer), message.nickname);
+    match encrypted {
+      true => info!(
+        "[dm] {}{}: {}",
+        message.lock(),
+        message.author(),
+        message.content
+      ),
+      false => info!(
+        "[dm] [unencrypted] {}: {}",
+        message.author(),
+        message.content
+      ),
+    }
+    Ok(DirectResponse::Delivered { id: envelope.id })
+  }
+
+  fn accept_room_key(
+    &mut self,
+    owner: PeerId,
+    room: &str,
+    nonce: &[u8],
+    sealed: &[u8],
+  ) -> Result<DirectResponse> {
+    let direct_key = self.direct_key(&owner)?;
+    let plaintext = crypto::open(&direct_key, room.as_bytes(), nonce, sealed)
+      .map_err(|e| anyhow!("could not decrypt key of {room}: {e}"))?;
+    let grant: RoomKeyGrant = serde_cbor::from_slice(&plaintext)?;
+    let key: SymmetricKey = grant
+      .key
+      .as_slice()
+      .try_into()
+      .map_err(|_| anyhow!("room key must be 32 bytes"))?;
+    if self.rooms.contains_key(room) && self.private_rooms.get(room).is_none() {
+      bail!("{owner} sent a key for {room}, which we joined as a public room");
+    }
+    match self
+      .private_rooms
+      .accept_key(room, owner, grant.epoch, key)?
+    {
+      Grant::Rotated => info!(
+        "Received key {} of private room {room} from {owner}",
+        grant.epoch
+      ),
+      Grant::Invited => {
+        println!("{owner} invited you to the private room {room}, /accept {room} {owner} to join")
+      }
+    }
+    Ok(DirectResponse::Accepted)
+  }
+
+  /// Joins the private `room` that `owner` invited us to.
+  async fn accept_invite(&mut self, room: &str, owner: &PeerId) -> Result<()> {
+    if self.rooms.contains_key(room) {
+      bail!("already in {room}, leave it before accepting a private room of that name");
+    }
+    self.private_rooms.accept_invite(room, owner)?;
+    self.join_room(room).await
+  }
+
+  /// Checks a gossipsub message before it's delivered, and forwarded if accepted.
+  ///
+  /// Messages of rooms we're not in are ignored, the rest goes through the validator, which also
+  /// ignores messages we already have.
+  fn validate(&mut self, message: &gossipsub::Message) -> Result<Validated, Invalid> {
+    let topic = message.topic.as_str();
+    let room = presence::room_of(topic).unwrap_or(topic);
+    if !self.rooms.contains_key(room) {
+      return Err(Invalid::Ignore(format!("not in {room}")));
+    }
+    let seen = &self.seen;
+    self
+      .validator
+      .validate(message, self.presence.offline_after(), |id| {
+        seen.contains(id)
+      })
+  }
+
+  /// Decrypts a validated envelope for private rooms and records it in its room.
+  async fn receive(&mut self, room: &str, source: Option<PeerId>, envelope: Envelope) {
+    let sender = source
+      .map(|peer_id| peer_id.to_string())
+      .unwrap_or_else(|| "unknown".to_owned());
+    let (envelope, encrypted) = match self.open_room_envelope(room, envelope) {
+      Ok(opened) => opened,
+      Err(e) => {
+        warn!("[{room}] Could not decrypt message from {sender}: {e}");
+        return;
+      }
+    };
+    if encrypted && (envelope.sender.is_some() || envelope.signature.is_some()) {
+      match envelope.verify(room) {
+        Ok(author) if Some(author) == source => {}
+        Ok(author) => {
+          warn!("[{room}] Message of {author} published by {sender}");
+          return;
+        }
+        Err(e) => {
+          warn!("[{room}] Message from {sender}: {e}");
+          return;
+        }
+      }
+    }
+    if !self.seen.insert(envelope.id) || self.has_message(room, &envelope.id) {
+      debug!(
+        "[{room}] Already have message {} from {sender}",
+        envelope.id
+      );
+      return;
+    }
+    if !encrypted && self.private_rooms.get(room).is_some() {
+      warn!("[{room}] Unencrypted message from {sender} in a private room");
+    }
+    let mut message = ChatMessage::from_envelope(sender, &envelope, encrypted);
+    message.nickname = self.verified_nickname(source, message.nickname);
+    if let Err(e) = self
+      .chat
+      .execute(room, ChatRoomCommand::ReceiveMessage(message))
+      .await
+    {
+      error!("{e}");
+    }
+  }
+
+  /// Opens `envelope` with the key of `room` it was sealed with, telling whether it was encrypted.
+  fn open_room_envelope(&self, room: &str, envelope: Envelope) -> Result<(Envelope, bool)> {
+    let epoch = match envelope
+      .encryption
+      .as_ref()
+      .map(|encryption| &encryption.key)
+    {
+      None => return Ok((envelope, false)),
+      Some(KeyRef::Room { epoch }) => *epoch,
+      Some(KeyRef::Direct) => bail!("direct message key used in a room"),
+    };
+    let key = self
+      .private_rooms
+      .get(room)
+      .ok_or_else(|| anyhow!("no key for {room}, ask its owner for an invite"))?
+      .key(epoch)
+      .ok_or_else(|| anyhow!("no key for epoch {epoch}"))?;
+    Ok((envelope.open(key)?, true))
+  }
+
+  /// Whether `room` already has the message `id`, in history or in its in-memory view.
+  fn has_message(&self, room: &str, id: &Uuid) -> bool {
+    let in_view = self.chat.room(room).map_or(false, |view| {
+      view.messages.iter().any(|posted| &posted.message.id == id)
+    });
+    in_view
+      || self
+        .history
+        .as_ref()
+        .map_or(false, |history| history.contains(room, id).unwrap_or(false))
+  }
+
+  /// Latest message of `room` this node has, where a sync picks up from.
+  fn last_seen(&self, room: &str) -> Option<DateTime<Utc>> {
+    let last = match &self.history {
+      Some(history) => history.last(room, 1).ok()?.pop(),
+      None => self.chat.room(room)?.messages.pop(),
+    };
+    last.map(|posted| posted.message.sent_at)
+  }
+
+  /// Asks `peer` for the messages of `room` posted since the last one this node has.
+  fn request_sync(&mut self, peer: PeerId, room: &str) {
+    let request = SyncRequest {
+      room: room.to_owned(),
+      since: self.last_seen(room),
+    };
+    debug!("Syncing {room} from {peer} since {:?}", request.since);
+    let request_id = self.swarm.behaviour_mut().sync.send_request(&peer, request);
+    self
+      .pending_sync
+      .insert(request_id, (peer, room.to_owned()));
+  }
+
+  /// Messages a peer asked for, sealed with the current room key for private rooms.
+  fn synced_messages(&self, request: &SyncRequest) -> Result<Vec<SyncedMessage>> {
+    let room = &request.room;
+    if !self.rooms.contains_key(room) {
+      bail!("not in {room}");
+    }
+    let mut messages = match (&self.history, request.since) {
+      (Some(history), Some(since)) => history.since(room, since)?,
+      (Some(history), None) => history.last(room, SYNC_MAX_MESSAGES)?,
+      (None, since) => self
+        .chat
+        .room(room)
+        .map(|view| view.messages)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|posted| since.map_or(true, |since| posted.message.sent_at >= since))
+        .collect(),
+    };
+    // Messages from peers that don't sign them can't be checked by the requester.
+    messages.retain(|posted| posted.message.signature.is_some());
+    if messages.len() > SYNC_MAX_MESSAGES {
+      let overflow = messages.len() - SYNC_MAX_MESSAGES;
+      messages.drain(..overflow);
+    }
+
+    let private = self
+      .private_rooms
+      .get(room)
+      .map(|private| private.current());
+    Ok(
+      messages
+        .into_iter()
+        .map(|posted| {
+          let envelope = posted.message.to_envelope();
+          let envelope = match private {
+            Some((epoch, key)) => envelope.seal(key, KeyRef::Room { epoch }),
+            None => envelope,
+          };
+          SyncedMessage {
+            envelope: ByteBuf::from(envelope.encode()),
+          }
+        })
+        .collect(),
+    )
+  }
+
+  async fn handle_sync_event(&mut self, event: request_response::Event<SyncRequest, SyncResponse>) {
+    match event {
+      request_response::Event::Message {
+        peer,
+        message: request_response::Message::Request {
+          request, channel, ..
+        },
+      } => {
+        let response = match self.synced_messages(&request) {
+          Ok(messages) => {
+            debug!(
+              "Sending {} messages of {} to {peer}",
+              messages.len(),
+              request.room
+            );
+            SyncResponse::Messages(messages)
+          }
+          Err(e) => SyncResponse::Rejected {
+            reason: e.to_string(),
+          },
+        };
+        if self
+          .swarm
+          .behaviour_mut()
+          .sync
+          .send_response(channel, response)
+          .is_err()
+        {
+          debug!(
+            "Connection to {peer} closed before the sync of {} was answered",
+            request.room
+          );
+        }
+      }
+      request_response::Event::Message {
+        peer,
+        message:
+          request_response::Message::Response {
+            request_id,
+            response,
+          },
+      } => {
+        let room = match self.pending_sync.remove(&request_id) {
+          Some((_, room)) => room,
+          None => return,
+        };
+        match response {
+          SyncResponse::Messages(messages) => self.merge_synced(peer, &room, messages).await,
+          SyncResponse::Rejected { reason } => debug!("{peer} declined to sync {room}: {reason}"),
+        }
+      }
+      request_response::Event::OutboundFailure {
+        peer,
+        request_id,
+        error,
+      } => {
+        if let Some((_, room)) = self.pending_sync.remove(&request_id) {
+          debug!("Sync of {room} from {peer} failed: {error:?}");
+        }
+      }
+      request_response::Event::InboundFailure { peer, error, .. } => {
+        debug!("Inbound sync request from {peer} failed: {error:?}");
+      }
+      request_response::Event::ResponseSent { .. } => {}
+    }
+  }
+
+  /// Records the synced messages of `room` this node doesn't have yet.
+  ///
+  /// Each one goes through the size and timestamp checks of the validator, and must be signed by
+  /// its author for `room`.
+  async fn merge_synced(&mut self, peer: PeerId, room: &str, messages: Vec<SyncedMessage>) {
+    if !self.rooms.contains_key(room) {
+      return;
+    }
+    let mut merged = 0;
+    for synced in messages {
+      let envelope = match self.validator.validate_synced(&synced.envelope) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+          warn!("[{room}] Dropped a message synced from {peer}, {e}");
+          continue;
+        }
+      };
+      let opened = self
+        .open_room_envelope(room, envelope)
+        .and_then(|(envelope, encrypted)| {
+          let sender = envelope.verify(room)?;
+          Ok((envelope, encrypted, sender))
+        });
+      let (envelope, encrypted, sender) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+          warn!("[{room}] Dropped a message synced from {peer}: {e}");
+          continue;
+        }
+      };
+      if !self.seen.insert(envelope.id) || self.has_message(room, &envelope.id) {
+        continue;
+      }
+      let mut message = ChatMessage::from_envelope(sender.to_string(), &envelope, encrypted);
+      message.nickname = self.verified_nickname(Some(sender), message.nickname);
+      if let Err(e) = self
+        .chat
+        .execute(room, ChatRoomCommand::ReceiveMessage(message))
+        .await
+      {
+        error!("{e}");
+        continue;
+      }
+      merged += 1;
+    }
+    if merged > 0 {
+      info!("[{room}] Caught up on {merged} messages from {peer}");
+    }
+  }
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until_some(deadline: Option<tokio::time::Instant>) {
+  match deadline {
+    Some(deadline) => tokio::time::sleep_until(deadline).await,
+    None => std::future::pending().await,
+  }
+}
+
+/// Prints stored messages of `room`, oldest first.
+fn print_history(room: &str, messages: &[PostedMessage]) {
+  for PostedMessage { message, local } in messages {
+    let author = match local {
+      true => "you",
+      false => message.author(),
+    };
+    println!(
+      "[{room}] {} {}{author}: {}",
+      message.sent_at.format("%d/%m/%Y %H:%M:%S"),
+      message.lock(),
+      message.content
+    );
   }
 }
 
@@ -212,6 +1951,9 @@ impl TPeer for Peer {
 pub struct PeerBuilder {
   local_key: Option<Keypair>,
   local_peer_id: Option<PeerId>,
+  config: NetworkConfig,
+  history: Option<PathBuf>,
+  dht_store: Option<PathBuf>,
 }
 
 impl PeerBuilder {
@@ -226,6 +1968,29 @@ impl PeerBuilder {
     self.local_peer_id = Some(PeerId::from(&self.local_key.as_ref().unwrap().public()));
     self
   }
+
+  pub fn local_key_from_file(mut self, path: &Path) -> Result<Self> {
+    self.local_key = Some(keyfile::load_or_create(path)?);
+    self.local_peer_id = Some(PeerId::from(&self.local_key.as_ref().unwrap().public()));
+    Ok(self)
+  }
+
+  pub fn config(mut self, config: NetworkConfig) -> Self {
+    self.config = config;
+    self
+  }
+
+  /// Stores sent and received messages under `path`.
+  pub fn history(mut self, path: Option<PathBuf>) -> Self {
+    self.history = path;
+    self
+  }
+
+  /// Keeps DHT records and the routing table under `path`, in a directory named after the peer id.
+  pub fn dht_store(mut self, path: Option<PathBuf>) -> Self {
+    self.dht_store = path;
+    self
+  }
 }
 
 #[async_trait]
@@ -240,80 +2005,141 @@ impl TBuilder for PeerBuilder {
 
     info!("Local peer id: {local_peer_id}");
 
-    let (relay_transport, client) = Client::new_transport_and_behaviour(local_peer_id);
-
-    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
-      .into_authentic(&local_key)
-      .expect("Signing libp2p-noise static DH keypair failed.");
+    let (relay_transport, client) = relay::client::new(local_peer_id);
 
+    // Listening for browser clients is opt-in, by adding a `/ws` listen address.
+    let ws_enabled = self.config.listen_addrs().iter().any(is_websocket);
     let transport = OrTransport::new(
       relay_transport,
-      block_on(DnsConfig::system(TokioTcpTransport::new(
-        GenTcpConfig::default().nodelay(true).port_reuse(true),
-      )))?,
+      OrTransport::new(
+        websocket(ws_enabled)?,
+        dns::tokio::Transport::system(tcp::tokio::Transport::new(
+          tcp::Config::default().nodelay(true),
+        ))?,
+      ),
     )
     .upgrade(upgrade::Version::V1)
-    .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-    .multiplex(libp2p::yamux::YamuxConfig::default())
+    .authenticate(noise::Config::new(local_key)?)
+    .multiplex(yamux::Config::default())
     .boxed();
-
-    // Create a Gossipsub topic
-    let topic = gossipsub::IdentTopic::new("chat");
+    let transport = with_quic(local_key, transport);
 
     // Set mDNS
-    let mdns = TokioMdns::new(Default::default()).await?;
+    let mdns = mdns::tokio::Behaviour::new(Default::default(), local_peer_id)?;
 
     // Set a custom gossipsub
-    let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
-      .heartbeat_interval(std::time::Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
-      .idle_timeout(Duration::from_secs(30))
+    let gossipsub_settings = self.config.gossipsub();
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+      .heartbeat_interval(gossipsub_settings.heartbeat_interval()) // This is set to aid debugging by not cluttering the log space
       .validation_mode(ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
+      .message_id_fn(message_id)
+      .validate_messages() // Messages are only forwarded once `Peer::validate` accepts them
       .do_px()
       .build()
       .expect("Valid config");
 
     info!("reach here?");
 
-    // Build a gossipsub network behaviour
-    let mut gossipsub: gossipsub::Gossipsub = gossipsub::Gossipsub::new(
+    // Build a gossipsub network behaviour, rooms are subscribed to once running
+    let gossipsub = gossipsub::Behaviour::new(
       MessageAuthenticity::Signed(local_key.clone()),
       gossipsub_config,
     )
     .expect("Correct configuration");
 
-    // Subscribes to our topic
-    gossipsub.subscribe(&topic).unwrap();
-
-    let mut config = KademliaConfig::default();
+    let kademlia_settings = self.config.kademlia();
+    let mut config = kad::Config::new(kad::PROTOCOL_NAME);
     config
-      .set_query_timeout(Duration::from_secs(10))
-      .set_connection_idle_timeout(Duration::from_secs(10))
-      .set_record_ttl(Some(Duration::from_secs(120)))
+      .set_query_timeout(kademlia_settings.query_timeout())
+      .set_record_ttl(Some(kademlia_settings.record_ttl()))
       .set_publication_interval(None)
       .set_replication_interval(None)
-      .set_provider_record_ttl(Some(Duration::from_secs(120)))
-      .set_provider_publication_interval(None);
-    let store = MemoryStore::new(local_peer_id);
-    let kademlia = Kademlia::with_config(local_peer_id, store, config);
+      .set_provider_record_ttl(Some(kademlia_settings.provider_record_ttl()))
+      // Rooms we're in are announced again before the other nodes forget them.
+      .set_provider_publication_interval(Some(kademlia_settings.provider_record_ttl() / 2))
+      .set_record_filtering(kad::StoreInserts::FilterBoth);
+    let store = match &self.dht_store {
+      Some(dir) => DhtStore::open(&dir.join(local_peer_id.to_string()), local_peer_id)?,
+      None => DhtStore::memory(local_peer_id),
+    };
+    let mut kademlia = kad::Behaviour::with_config(local_peer_id, store, config);
+    restore_routes(&mut kademlia)?;
 
+    let autonat_settings = self.config.autonat();
     let behaviour = PeerBehaviour {
       client,
-      ping: Ping::new(PingConfig::default().with_keep_alive(true)),
-      identify: Identify::new(IdentifyConfig::new(
-        "/TODO/0.0.1".to_string(),
+      ping: ping::Behaviour::default(),
+      identify: identify::Behaviour::new(identify::Config::new(
+        self.config.identify().protocol_version().to_owned(),
         local_key.public(),
       )),
-      dcutr: dcutr::behaviour::Behaviour::new(),
+      dcutr: dcutr::Behaviour::new(local_peer_id),
+      autonat: autonat::Behaviour::new(
+        local_peer_id,
+        autonat::Config {
+          boot_delay: autonat_settings.boot_delay(),
+          refresh_interval: autonat_settings.refresh_interval(),
+          only_global_ips: autonat_settings.only_global_ips(),
+          ..Default::default()
+        },
+      ),
       gossipsub,
       mdns,
       kademlia,
+      direct: request_response::Behaviour::<DirectMessageCodec>::new(
+        iter::once((DirectMessageProtocol, ProtocolSupport::Full)),
+        Default::default(),
+      ),
+      sync: request_response::Behaviour::<SyncCodec>::new(
+        iter::once((SyncProtocol, ProtocolSupport::Full)),
+        Default::default(),
+      ),
+      mailbox: request_response::Behaviour::<MailboxCodec>::new(
+        iter::once((MailboxProtocol, ProtocolSupport::Outbound)),
+        Default::default(),
+      ),
     };
 
-    let swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
-      .executor(Box::new(|fut| {
-        tokio::spawn(fut);
-      }))
-      .build();
-    Ok(Box::new(Peer { swarm, topic }))
+    let history = self
+      .history
+      .as_deref()
+      .map(HistoryStore::open)
+      .transpose()?;
+
+    let swarm = Swarm::new(
+      transport,
+      behaviour,
+      local_peer_id,
+      swarm::Config::with_tokio_executor().with_idle_connection_timeout(idle_timeout(&self.config)),
+    );
+    Ok(Box::new(Peer {
+      swarm,
+      local_key: local_key.clone(),
+      rooms: BTreeMap::new(),
+      current_room: None,
+      nickname: None,
+      listen_addrs: self.config.listen_addrs().to_vec(),
+      external_addrs: self.config.external_addrs().to_vec(),
+      relays: Vec::new(),
+      relay_listeners: HashMap::new(),
+      failed_relays: HashSet::new(),
+      relay_reservations: self.config.relay().reservations(),
+      pending_direct: HashMap::new(),
+      pending_sync: HashMap::new(),
+      undelivered: HashMap::new(),
+      mailboxes: Vec::new(),
+      direct_keys: HashMap::new(),
+      private_rooms: PrivateRooms::default(),
+      chat: ChatService::new(history.clone()),
+      history,
+      startup: self.config.startup().clone(),
+      dht: Dht::new(kademlia_settings),
+      nicknames: NicknameRegistry::new(kademlia_settings.nickname_ttl()),
+      discoveries: HashMap::new(),
+      presence: Presence::new(self.config.presence()),
+      seen: SeenMessages::new(SEEN_MESSAGES_MAX),
+      validator: Validator::new(gossipsub_settings),
+      handshakes: HashMap::new(),
+    }))
   }
 }
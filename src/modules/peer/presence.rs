@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use libp2p::gossipsub::IdentTopic;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::time::Instant;
+
+use crate::config::PresenceSettings;
+use crate::constants::PRESENCE_TOPIC_PREFIX;
+use crate::crypto;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Status {
+  Online,
+  Away,
+  Offline,
+}
+
+impl Display for Status {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Status::Online => write!(f, "online"),
+      Status::Away => write!(f, "away"),
+      Status::Offline => write!(f, "offline"),
+    }
+  }
+}
+
+/// Status of `peer_id` in `room`, signed by its identity key and published on the presence topic
+/// of the room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Heartbeat {
+  pub room: String,
+  pub peer_id: String,
+  pub status: Status,
+  pub nickname: Option<String>,
+  pub sent_at: DateTime<Utc>,
+  pub signature: ByteBuf,
+}
+
+/// The heartbeat without its signature, which is what gets signed.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+  room: &'a str,
+  peer_id: &'a str,
+  status: Status,
+  nickname: Option<&'a str>,
+  sent_at: &'a DateTime<Utc>,
+}
+
+impl Heartbeat {
+  pub fn new(
+    keypair: &Keypair,
+    room: &str,
+    status: Status,
+    nickname: Option<String>,
+  ) -> Result<Self> {
+    let mut heartbeat = Self {
+      room: room.to_owned(),
+      peer_id: PeerId::from(keypair.public()).to_string(),
+      status,
+      nickname,
+      sent_at: Utc::now(),
+      signature: ByteBuf::new(),
+    };
+    heartbeat.signature = ByteBuf::from(keypair.sign(&heartbeat.signed_bytes())?);
+    Ok(heartbeat)
+  }
+
+  fn signed_bytes(&self) -> Vec<u8> {
+    let fields = SignedFields {
+      room: &self.room,
+      peer_id: &self.peer_id,
+      status: self.status,
+      nickname: self.nickname.as_deref(),
+      sent_at: &self.sent_at,
+    };
+    serde_cbor::to_vec(&fields).expect("heartbeat is always serializable")
+  }
+
+  /// Checks the signature and that it was sent within `max_age`, returning the peer that sent it.
+  pub fn verify(&self, max_age: Duration) -> Result<PeerId> {
+    let max_age = chrono::Duration::from_std(max_age)?;
+    let now = Utc::now();
+    if self.sent_at < now - max_age || self.sent_at > now + max_age {
+      bail!("heartbeat sent at {} is out of date", self.sent_at);
+    }
+    let peer_id = self
+      .peer_id
+      .parse::<PeerId>()
+      .map_err(|_| anyhow!("{} is not a valid peer id", self.peer_id))?;
+    if !crypto::public_key(&peer_id)?.verify(&self.signed_bytes(), &self.signature) {
+      bail!("heartbeat of {peer_id} has an invalid signature");
+    }
+    Ok(peer_id)
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    serde_cbor::to_vec(self).expect("heartbeat is always serializable")
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self> {
+    Ok(serde_cbor::from_slice(data)?)
+  }
+}
+
+/// Topic the heartbeats of `room` are published on.
+pub fn topic(room: &str) -> IdentTopic {
+  IdentTopic::new(format!("{PRESENCE_TOPIC_PREFIX}{room}"))
+}
+
+/// Room whose heartbeats are published on `topic`, if it's a presence topic.
+pub fn room_of(topic: &str) -> Option<&str> {
+  topic.strip_prefix(PRESENCE_TOPIC_PREFIX)
+}
+
+/// A room member as last heard of.
+struct Member {
+  status: Status,
+  nickname: Option<String>,
+  heard_at: Instant,
+  /// Cleared when the connection to the member closes, until it's heard of again.
+  reachable: bool,
+}
+
+/// Who is online in the rooms we're in, from their heartbeats and our connections to them.
+pub struct Presence {
+  interval: Duration,
+  away_after: Duration,
+  offline_after: Duration,
+  next_heartbeat: Instant,
+  last_input: Instant,
+  rooms: HashMap<String, HashMap<PeerId, Member>>,
+}
+
+impl Presence {
+  pub fn new(settings: &PresenceSettings) -> Self {
+    Self {
+      interval: settings.heartbeat_interval(),
+      away_after: settings.away_after(),
+      offline_after: settings.offline_after(),
+      next_heartbeat: Instant::now() + settings.heartbeat_interval(),
+      last_input: Instant::now(),
+      rooms: HashMap::new(),
+    }
+  }
+
+  pub fn offline_after(&self) -> Duration {
+    self.offline_after
+  }
+
+  pub fn next_heartbeat(&self) -> Instant {
+    self.next_heartbeat
+  }
+
+  pub fn heartbeat_sent(&mut self) {
+    self.next_heartbeat = Instant::now() + self.interval;
+  }
+
+  /// Our status, away once the console has been idle for a while.
+  pub fn own_status(&self) -> Status {
+    match self.last_input.elapsed() >= self.away_after {
+      true => Status::Away,
+      false => Status::Online,
+    }
+  }
+
+  /// Records console input, telling whether we were away and should say we're back.
+  pub fn input(&mut self) -> bool {
+    let was_away = self.own_status() == Status::Away;
+    self.last_input = Instant::now();
+    was_away
+  }
+
+  /// Records a verified heartbeat, returning the status of the member if it changed.
+  pub fn heard(
+    &mut self,
+    room: &str,
+    peer: PeerId,
+    status: Status,
+    nickname: Option<String>,
+  ) -> Option<Status> {
+    let offline_after = self.offline_after;
+    let members = self.rooms.entry(room.to_owned()).or_default();
+    let previous = members
+      .get(&peer)
+      .map(|member| member.status(offline_after));
+    members.insert(
+      peer,
+      Member {
+        status,
+        nickname,
+        heard_at: Instant::now(),
+        reachable: true,
+      },
+    );
+    match previous == Some(status) {
+      true => None,
+      false => Some(status),
+    }
+  }
+
+  /// A connection to `peer` was established, so it's reachable again.
+  pub fn connected(&mut self, peer: &PeerId) {
+    for member in self
+      .rooms
+      .values_mut()
+      .filter_map(|members| members.get_mut(peer))
+    {
+      member.reachable = true;
+      member.heard_at = Instant::now();
+    }
+  }
+
+  /// The last connection to `peer` closed, or mDNS lost it, so it's offline until heard of again.
+  pub fn disconnected(&mut self, peer: &PeerId) {
+    for member in self
+      .rooms
+      .values_mut()
+      .filter_map(|members| members.get_mut(peer))
+    {
+      member.reachable = false;
+    }
+  }
+
+  pub fn leave(&mut self, room: &str) {
+    self.rooms.remove(room);
+  }
+
+  /// Members of `room` heard of, online ones first.
+  pub fn members(&self, room: &str) -> Vec<(PeerId, Status, Option<&str>)> {
+    let mut members: Vec<_> = self
+      .rooms
+      .get(room)
+      .into_iter()
+      .flatten()
+      .map(|(peer, member)| {
+        (
+          *peer,
+          member.status(self.offline_after),
+          member.nickname.as_deref(),
+        )
+      })
+      .collect();
+    members.sort_by_key(|(peer, status, _)| (*status, *peer));
+    members
+  }
+}
+
+impl Member {
+  fn status(&self, offline_after: Duration) -> Status {
+    match self.reachable && self.heard_at.elapsed() < offline_after {
+      true => self.status,
+      false => Status::Offline,
+    }
+  }
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashSet, VecDeque};
+
+use uuid::Uuid;
+
+/// Ids of the latest messages received, so a message delivered twice is only shown once.
+pub struct SeenMessages {
+  capacity: usize,
+  ids: HashSet<Uuid>,
+  /// Oldest first, the first to be forgotten once full.
+  order: VecDeque<Uuid>,
+}
+
+impl SeenMessages {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      ids: HashSet::with_capacity(capacity),
+      order: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  pub fn contains(&self, id: &Uuid) -> bool {
+    self.ids.contains(id)
+  }
+
+  /// Records `id`, telling whether it wasn't seen before.
+  pub fn insert(&mut self, id: Uuid) -> bool {
+    if !self.ids.insert(id) {
+      return false;
+    }
+    if self.order.len() == self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.ids.remove(&oldest);
+      }
+    }
+    self.order.push_back(id);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reports_duplicates() {
+    let mut seen = SeenMessages::new(2);
+    let id = Uuid::new_v4();
+    assert!(seen.insert(id));
+    assert!(!seen.insert(id));
+    assert!(seen.contains(&id));
+  }
+
+  #[test]
+  fn forgets_the_oldest_once_full() {
+    let mut seen = SeenMessages::new(2);
+    let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+    for id in &ids {
+      assert!(seen.insert(*id));
+    }
+    assert!(!seen.contains(&ids[0]));
+    assert!(seen.contains(&ids[1]));
+    assert!(seen.contains(&ids[2]));
+    assert_eq!(seen.order.len(), 2);
+    assert_eq!(seen.ids.len(), 2);
+
+    // A duplicate doesn't count as the newest, so it's still the next one forgotten.
+    assert!(!seen.insert(ids[1]));
+    assert!(seen.insert(ids[0]));
+    assert!(!seen.contains(&ids[1]));
+  }
+}
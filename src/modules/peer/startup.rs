@@ -0,0 +1,46 @@
+use std::fmt::{self, Display};
+
+/// Phases a peer goes through before it runs, each bounded by a timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupPhase {
+  /// Waiting for the listeners to report their addresses.
+  Listen,
+  /// Dialing a bootnode.
+  Dial,
+  /// Exchanging Identify with a connected bootnode, learning our observed address.
+  Identify,
+}
+
+impl Display for StartupPhase {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StartupPhase::Listen => write!(f, "listen"),
+      StartupPhase::Dial => write!(f, "dial"),
+      StartupPhase::Identify => write!(f, "identify"),
+    }
+  }
+}
+
+/// Progress of the handshake with one bootnode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Handshake {
+  pub connected: bool,
+  /// We told the bootnode its observed address.
+  pub identify_sent: bool,
+  /// The bootnode told us our observed address.
+  pub identify_received: bool,
+}
+
+impl Handshake {
+  /// Phase the handshake is in, the one that failed when it times out.
+  pub fn phase(&self) -> StartupPhase {
+    match self.connected {
+      false => StartupPhase::Dial,
+      true => StartupPhase::Identify,
+    }
+  }
+
+  pub fn is_done(&self) -> bool {
+    self.connected && self.identify_sent && self.identify_received
+  }
+}
@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use libp2p::kad::store::{self, MemoryStore, RecordStore};
+use libp2p::kad::{ProviderRecord, Record, RecordKey as Key};
+use libp2p::{Multiaddr, PeerId};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+const RECORD_TREE: &str = "records";
+const PROVIDER_TREE: &str = "providers";
+const ROUTING_TREE: &str = "routing";
+
+/// Kademlia record store that mirrors records, provider records and the routing table to disk.
+///
+/// Lookups are served from memory. Without a directory it behaves like a plain `MemoryStore`.
+pub struct DhtStore {
+  memory: MemoryStore,
+  db: Option<sled::Db>,
+}
+
+/// A record as written to disk, with its expiry as wall-clock time so it survives a restart.
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+  value: ByteBuf,
+  publisher: Option<ByteBuf>,
+  expires: Option<SystemTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+  key: ByteBuf,
+  provider: ByteBuf,
+  addresses: Vec<Multiaddr>,
+  expires: Option<SystemTime>,
+}
+
+impl DhtStore {
+  pub fn memory(local_peer_id: PeerId) -> Self {
+    Self {
+      memory: MemoryStore::new(local_peer_id),
+      db: None,
+    }
+  }
+
+  /// Opens the store under `path`, reloading the records that haven't expired.
+  pub fn open(path: &Path, local_peer_id: PeerId) -> Result<Self> {
+    let db =
+      sled::open(path).with_context(|| format!("failed to open DHT store {}", path.display()))?;
+    let mut memory = MemoryStore::new(local_peer_id);
+    let now = SystemTime::now();
+
+    let records = db.open_tree(RECORD_TREE)?;
+    let mut loaded = 0;
+    for entry in records.iter() {
+      let (key, value) = entry?;
+      let stored: StoredRecord = serde_cbor::from_slice(&value)?;
+      if stored.expires.map_or(false, |expires| expires <= now) {
+        records.remove(key)?;
+        continue;
+      }
+      let mut record = Record::new(Key::from(key.to_vec()), stored.value.into_vec());
+      record.publisher = stored
+        .publisher
+        .and_then(|publisher| PeerId::from_bytes(&publisher).ok());
+      record.expires = stored.expires.map(instant);
+      memory.put(record)?;
+      loaded += 1;
+    }
+
+    let providers = db.open_tree(PROVIDER_TREE)?;
+    for entry in providers.iter() {
+      let (key, value) = entry?;
+      let stored: StoredProvider = serde_cbor::from_slice(&value)?;
+      if stored.expires.map_or(false, |expires| expires <= now) {
+        providers.remove(key)?;
+        continue;
+      }
+      let provider = match PeerId::from_bytes(&stored.provider) {
+        Ok(provider) => provider,
+        Err(_) => continue,
+      };
+      // Our own rooms are announced again as we rejoin them.
+      if provider == local_peer_id {
+        providers.remove(key)?;
+        continue;
+      }
+      memory.add_provider(ProviderRecord {
+        key: Key::from(stored.key.into_vec()),
+        provider,
+        expires: stored.expires.map(instant),
+        addresses: stored.addresses,
+      })?;
+    }
+
+    info!("Reloaded {loaded} DHT records from {}", path.display());
+    Ok(Self {
+      memory,
+      db: Some(db),
+    })
+  }
+
+  /// Routing table entries saved by a previous run.
+  pub fn routes(&self) -> Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+    let db = match &self.db {
+      Some(db) => db,
+      None => return Ok(Vec::new()),
+    };
+    let mut routes = Vec::new();
+    for entry in db.open_tree(ROUTING_TREE)?.iter() {
+      let (peer, addresses) = entry?;
+      if let Ok(peer) = PeerId::from_bytes(&peer) {
+        routes.push((peer, serde_cbor::from_slice(&addresses)?));
+      }
+    }
+    Ok(routes)
+  }
+
+  /// Saves the addresses of a peer in the routing table.
+  pub fn save_route(&self, peer: &PeerId, addresses: Vec<Multiaddr>) {
+    self.write(ROUTING_TREE, |tree| {
+      tree.insert(peer.to_bytes(), serde_cbor::to_vec(&addresses)?)?;
+      Ok(())
+    });
+  }
+
+  /// Forgets a peer evicted from the routing table.
+  pub fn remove_route(&self, peer: &PeerId) {
+    self.write(ROUTING_TREE, |tree| {
+      tree.remove(peer.to_bytes())?;
+      Ok(())
+    });
+  }
+
+  /// Runs `write` on `tree`, logging failures: the in-memory copy stays authoritative.
+  fn write(&self, tree: &str, write: impl FnOnce(&sled::Tree) -> Result<()>) {
+    if let Some(db) = &self.db {
+      let result = db
+        .open_tree(tree)
+        .map_err(anyhow::Error::from)
+        .and_then(|tree| write(&tree));
+      if let Err(e) = result {
+        error!("Failed to write the DHT store: {e:?}");
+      }
+    }
+  }
+}
+
+impl RecordStore for DhtStore {
+  type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+  type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+  fn get(&self, k: &Key) -> Option<Cow<'_, Record>> {
+    self.memory.get(k)
+  }
+
+  fn put(&mut self, r: Record) -> store::Result<()> {
+    let stored = StoredRecord {
+      value: ByteBuf::from(r.value.clone()),
+      publisher: r
+        .publisher
+        .map(|publisher| ByteBuf::from(publisher.to_bytes())),
+      expires: r.expires.map(system_time),
+    };
+    let key = r.key.to_vec();
+    self.memory.put(r)?;
+    self.write(RECORD_TREE, |tree| {
+      tree.insert(key, serde_cbor::to_vec(&stored)?)?;
+      Ok(())
+    });
+    Ok(())
+  }
+
+  fn remove(&mut self, k: &Key) {
+    self.memory.remove(k);
+    self.write(RECORD_TREE, |tree| {
+      tree.remove(k.to_vec())?;
+      Ok(())
+    });
+  }
+
+  fn records(&self) -> Self::RecordsIter<'_> {
+    self.memory.records()
+  }
+
+  fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+    let id = provider_id(&record.key, &record.provider);
+    let stored = StoredProvider {
+      key: ByteBuf::from(record.key.to_vec()),
+      provider: ByteBuf::from(record.provider.to_bytes()),
+      addresses: record.addresses.clone(),
+      expires: record.expires.map(system_time),
+    };
+    self.memory.add_provider(record)?;
+    self.write(PROVIDER_TREE, |tree| {
+      tree.insert(id, serde_cbor::to_vec(&stored)?)?;
+      Ok(())
+    });
+    Ok(())
+  }
+
+  fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+    self.memory.providers(key)
+  }
+
+  fn provided(&self) -> Self::ProvidedIter<'_> {
+    self.memory.provided()
+  }
+
+  fn remove_provider(&mut self, k: &Key, p: &PeerId) {
+    self.memory.remove_provider(k, p);
+    self.write(PROVIDER_TREE, |tree| {
+      tree.remove(provider_id(k, p))?;
+      Ok(())
+    });
+  }
+}
+
+/// Provider records are keyed by record key then provider, the key length first so two pairs
+/// can't collide.
+fn provider_id(key: &Key, provider: &PeerId) -> Vec<u8> {
+  let key = key.to_vec();
+  let mut id = (key.len() as u32).to_be_bytes().to_vec();
+  id.extend_from_slice(&key);
+  id.extend_from_slice(&provider.to_bytes());
+  id
+}
+
+fn system_time(expires: Instant) -> SystemTime {
+  let now = Instant::now();
+  SystemTime::now() + expires.saturating_duration_since(now)
+}
+
+fn instant(expires: SystemTime) -> Instant {
+  let remaining = expires
+    .duration_since(SystemTime::now())
+    .unwrap_or(Duration::ZERO);
+  Instant::now() + remaining
+}
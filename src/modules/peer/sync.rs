@@ -0,0 +1,92 @@
+use std::io;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use libp2p::futures::{AsyncRead, AsyncWrite};
+use libp2p::request_response::Codec;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::constants::{SYNC_MAX_SIZE, SYNC_PROTOCOL};
+
+use super::direct::{read_cbor, write_cbor};
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl AsRef<str> for SyncProtocol {
+  fn as_ref(&self) -> &str {
+    SYNC_PROTOCOL
+  }
+}
+
+/// Asks a room member for the messages it has seen in `room`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+  pub room: String,
+  /// Last message the requester has, everything is requested when unset.
+  pub since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncResponse {
+  /// Messages oldest first. Those of private rooms are sealed with the current room key.
+  Messages(Vec<SyncedMessage>),
+  Rejected {
+    reason: String,
+  },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedMessage {
+  /// An encoded `Envelope`, signed by its author so the peer answering can't make it up.
+  pub envelope: ByteBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl Codec for SyncCodec {
+  type Protocol = SyncProtocol;
+  type Request = SyncRequest;
+  type Response = SyncResponse;
+
+  async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, SYNC_MAX_SIZE).await
+  }
+
+  async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    read_cbor(io, SYNC_MAX_SIZE).await
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    request: Self::Request,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &request).await
+  }
+
+  async fn write_response<T>(
+    &mut self,
+    _: &Self::Protocol,
+    io: &mut T,
+    response: Self::Response,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_cbor(io, &response).await
+  }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use libp2p::gossipsub::{Message, MessageAcceptance, TopicHash};
+use libp2p::PeerId;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::config::GossipsubSettings;
+use crate::envelope::Envelope;
+
+use super::presence::{self, Heartbeat};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// A gossipsub message that passed validation, decoded.
+pub enum Validated {
+  Envelope(Envelope),
+  Heartbeat(PeerId, Heartbeat),
+}
+
+/// Why a message is neither delivered nor forwarded.
+#[derive(Debug)]
+pub enum Invalid {
+  /// Bad whoever forwards it, so the peer that forwarded it is penalized.
+  Reject(String),
+  /// Possibly fine but unwanted, like a message over the rate limit or from a room we left.
+  Ignore(String),
+}
+
+impl Invalid {
+  pub fn acceptance(&self) -> MessageAcceptance {
+    match self {
+      Invalid::Reject(_) => MessageAcceptance::Reject,
+      Invalid::Ignore(_) => MessageAcceptance::Ignore,
+    }
+  }
+}
+
+impl Display for Invalid {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Invalid::Reject(reason) => write!(f, "rejected, {reason}"),
+      Invalid::Ignore(reason) => write!(f, "ignored, {reason}"),
+    }
+  }
+}
+
+/// Checks gossipsub messages before they're delivered and forwarded to the mesh.
+pub struct Validator {
+  max_size: usize,
+  max_per_minute: u32,
+  max_skew: chrono::Duration,
+  max_age: chrono::Duration,
+  /// Messages counted per sender and topic, with the start of their current window.
+  counts: HashMap<(PeerId, TopicHash), (Instant, u32)>,
+}
+
+impl Validator {
+  pub fn new(settings: &GossipsubSettings) -> Self {
+    Self {
+      max_size: settings.max_message_size(),
+      max_per_minute: settings.max_messages_per_minute(),
+      max_skew: chrono::Duration::from_std(settings.max_clock_skew())
+        .expect("clock skew is validated"),
+      max_age: chrono::Duration::from_std(settings.max_message_age())
+        .expect("message age is validated"),
+      counts: HashMap::new(),
+    }
+  }
+
+  /// Checks a message of a room topic or of its presence topic, whose heartbeats are kept for
+  /// `heartbeat_age`.
+  ///
+  /// Envelopes `seen` already are ignored before counting them against the rate limit, so
+  /// copies of a message don't use up the allowance of its sender.
+  pub fn validate(
+    &mut self,
+    message: &Message,
+    heartbeat_age: Duration,
+    seen: impl Fn(&Uuid) -> bool,
+  ) -> Result<Validated, Invalid> {
+    let source = message
+      .source
+      .ok_or_else(|| Invalid::Reject("no source".to_owned()))?;
+    self.check_size(&message.data)?;
+
+    match presence::room_of(message.topic.as_str()) {
+      Some(room) => {
+        let heartbeat = Heartbeat::decode(&message.data)
+          .map_err(|e| Invalid::Reject(format!("malformed heartbeat: {e}")))?;
+        self.count(source, &message.topic)?;
+        if heartbeat.room != room {
+          return Err(Invalid::Reject(format!(
+            "heartbeat for {} on the presence topic of {room}",
+            heartbeat.room
+          )));
+        }
+        let max_age =
+          chrono::Duration::from_std(heartbeat_age).map_err(|e| Invalid::Ignore(e.to_string()))?;
+        self.check_timestamp(heartbeat.sent_at, Some(max_age))?;
+        let peer = heartbeat
+          .verify(heartbeat_age)
+          .map_err(|e| Invalid::Reject(e.to_string()))?;
+        if peer != source {
+          return Err(Invalid::Reject(format!(
+            "heartbeat of {peer} published by {source}"
+          )));
+        }
+        Ok(Validated::Heartbeat(peer, heartbeat))
+      }
+      None => {
+        let envelope =
+          Envelope::decode(&message.data).map_err(|e| Invalid::Reject(e.to_string()))?;
+        if seen(&envelope.id) {
+          return Err(Invalid::Ignore(format!("already have {}", envelope.id)));
+        }
+        self.count(source, &message.topic)?;
+        self.check_timestamp(envelope.timestamp, Some(self.max_age))?;
+        // Unsigned ones are from older peers and taken as sent by the source, sealed ones are
+        // signed inside and checked once opened.
+        if envelope.sender.is_some() || envelope.signature.is_some() {
+          let sender = envelope
+            .verify(message.topic.as_str())
+            .map_err(|e| Invalid::Reject(e.to_string()))?;
+          if sender != source {
+            return Err(Invalid::Reject(format!(
+              "message of {sender} published by {source}"
+            )));
+          }
+        }
+        Ok(Validated::Envelope(envelope))
+      }
+    }
+  }
+
+  /// Checks an envelope synced from a room member, which may be old but not sent in the future.
+  pub fn validate_synced(&self, data: &[u8]) -> Result<Envelope, Invalid> {
+    self.check_size(data)?;
+    let envelope = Envelope::decode(data).map_err(|e| Invalid::Reject(e.to_string()))?;
+    self.check_timestamp(envelope.timestamp, None)?;
+    Ok(envelope)
+  }
+
+  fn check_size(&self, data: &[u8]) -> Result<(), Invalid> {
+    if data.len() > self.max_size {
+      return Err(Invalid::Reject(format!(
+        "{} bytes is over the limit of {}",
+        data.len(),
+        self.max_size
+      )));
+    }
+    Ok(())
+  }
+
+  /// Counts a message of `source` on `topic`, failing once it sent too many this minute.
+  fn count(&mut self, source: PeerId, topic: &TopicHash) -> Result<(), Invalid> {
+    let now = Instant::now();
+    let (started, count) = self
+      .counts
+      .entry((source, topic.clone()))
+      .or_insert((now, 0));
+    if now.duration_since(*started) >= RATE_WINDOW {
+      *started = now;
+      *count = 0;
+    }
+    *count += 1;
+    if *count > self.max_per_minute {
+      return Err(Invalid::Ignore(format!(
+        "{source} is over {} messages a minute",
+        self.max_per_minute
+      )));
+    }
+    if *count == 1 {
+      // A new window for one sender is a good time to forget the silent ones.
+      self
+        .counts
+        .retain(|_, (started, _)| now.duration_since(*started) < RATE_WINDOW);
+    }
+    Ok(())
+  }
+
+  /// Checks `timestamp` isn't in the future, beyond the clock skew allowed, nor over `max_age`.
+  fn check_timestamp(
+    &self,
+    timestamp: DateTime<Utc>,
+    max_age: Option<chrono::Duration>,
+  ) -> Result<(), Invalid> {
+    let now = Utc::now();
+    if timestamp > now + self.max_skew {
+      return Err(Invalid::Reject(format!(
+        "sent in the future, at {timestamp}"
+      )));
+    }
+    if max_age.map_or(false, |max_age| timestamp < now - max_age) {
+      return Err(Invalid::Ignore(format!(
+        "sent too long ago, at {timestamp}"
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use libp2p::gossipsub::IdentTopic;
+  use libp2p::identity::Keypair;
+
+  use super::presence::Status;
+  use super::*;
+
+  const HEARTBEAT_AGE: Duration = Duration::from_secs(90);
+
+  fn validator() -> Validator {
+    Validator::new(&GossipsubSettings::default())
+  }
+
+  fn message(source: &Keypair, topic: IdentTopic, data: Vec<u8>) -> Message {
+    Message {
+      source: Some(PeerId::from(source.public())),
+      data,
+      sequence_number: None,
+      topic: topic.hash(),
+    }
+  }
+
+  fn envelope_message(source: &Keypair, envelope: Envelope) -> Message {
+    message(source, IdentTopic::new("chat"), envelope.encode())
+  }
+
+  fn text() -> Envelope {
+    Envelope::text("hello".to_owned(), None, None)
+  }
+
+  fn unseen(_: &Uuid) -> bool {
+    false
+  }
+
+  fn is_reject(result: Result<Validated, Invalid>) -> bool {
+    matches!(result, Err(Invalid::Reject(_)))
+  }
+
+  fn is_ignore(result: Result<Validated, Invalid>) -> bool {
+    matches!(result, Err(Invalid::Ignore(_)))
+  }
+
+  #[test]
+  fn accepts_signed_and_unsigned_envelopes() {
+    let keypair = Keypair::generate_ed25519();
+    let mut validator = validator();
+    let signed = text().sign(&keypair, "chat").unwrap();
+    for envelope in [text(), signed] {
+      let result = validator.validate(&envelope_message(&keypair, envelope), HEARTBEAT_AGE, unseen);
+      assert!(matches!(result, Ok(Validated::Envelope(_))));
+    }
+  }
+
+  #[test]
+  fn rejects_anonymous_oversized_and_malformed_messages() {
+    let keypair = Keypair::generate_ed25519();
+    let mut validator = validator();
+
+    let mut anonymous = envelope_message(&keypair, text());
+    anonymous.source = None;
+    assert!(is_reject(validator.validate(
+      &anonymous,
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    let oversized = Envelope::text("a".repeat(validator.max_size), None, None);
+    assert!(is_reject(validator.validate(
+      &envelope_message(&keypair, oversized),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    let malformed = message(&keypair, IdentTopic::new("chat"), vec![0xa1, 0xff]);
+    assert!(is_reject(validator.validate(
+      &malformed,
+      HEARTBEAT_AGE,
+      unseen
+    )));
+  }
+
+  #[test]
+  fn rejects_future_messages_and_ignores_old_ones() {
+    let keypair = Keypair::generate_ed25519();
+    let mut validator = validator();
+
+    let mut future = text();
+    future.timestamp = Utc::now() + validator.max_skew * 2;
+    assert!(is_reject(validator.validate(
+      &envelope_message(&keypair, future),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    let mut old = text();
+    old.timestamp = Utc::now() - validator.max_age * 2;
+    assert!(is_ignore(validator.validate(
+      &envelope_message(&keypair, old),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+  }
+
+  #[test]
+  fn rejects_messages_signed_by_another_peer_or_for_another_room() {
+    let (author, publisher) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let mut validator = validator();
+
+    let relayed = text().sign(&author, "chat").unwrap();
+    assert!(is_reject(validator.validate(
+      &envelope_message(&publisher, relayed),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    let elsewhere = text().sign(&author, "other").unwrap();
+    assert!(is_reject(validator.validate(
+      &envelope_message(&author, elsewhere),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+  }
+
+  #[test]
+  fn ignores_senders_over_the_rate_limit() {
+    let keypair = Keypair::generate_ed25519();
+    let mut validator = validator();
+    for _ in 0..validator.max_per_minute {
+      assert!(validator
+        .validate(&envelope_message(&keypair, text()), HEARTBEAT_AGE, unseen)
+        .is_ok());
+    }
+    assert!(is_ignore(validator.validate(
+      &envelope_message(&keypair, text()),
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    // Other senders have their own count.
+    let other = Keypair::generate_ed25519();
+    assert!(validator
+      .validate(&envelope_message(&other, text()), HEARTBEAT_AGE, unseen)
+      .is_ok());
+  }
+
+  #[test]
+  fn messages_seen_already_are_not_counted() {
+    let keypair = Keypair::generate_ed25519();
+    let mut validator = validator();
+    let message = envelope_message(&keypair, text());
+    for _ in 0..validator.max_per_minute * 2 {
+      assert!(is_ignore(validator.validate(
+        &message,
+        HEARTBEAT_AGE,
+        |_| true
+      )));
+    }
+    assert!(validator.validate(&message, HEARTBEAT_AGE, unseen).is_ok());
+  }
+
+  #[test]
+  fn checks_heartbeats_against_their_topic_and_source() {
+    let (keypair, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let mut validator = validator();
+    let heartbeat = Heartbeat::new(&keypair, "chat", Status::Online, None).unwrap();
+
+    let valid = message(&keypair, presence::topic("chat"), heartbeat.encode());
+    assert!(matches!(
+      validator.validate(&valid, HEARTBEAT_AGE, unseen),
+      Ok(Validated::Heartbeat(peer, _)) if peer == PeerId::from(keypair.public())
+    ));
+
+    let wrong_room = message(&keypair, presence::topic("other"), heartbeat.encode());
+    assert!(is_reject(validator.validate(
+      &wrong_room,
+      HEARTBEAT_AGE,
+      unseen
+    )));
+
+    let replayed = message(&other, presence::topic("chat"), heartbeat.encode());
+    assert!(is_reject(validator.validate(
+      &replayed,
+      HEARTBEAT_AGE,
+      unseen
+    )));
+  }
+
+  #[test]
+  fn synced_envelopes_may_be_old_but_not_from_the_future() {
+    let validator = validator();
+
+    let mut old = text();
+    old.timestamp = Utc::now() - validator.max_age * 2;
+    assert!(validator.validate_synced(&old.encode()).is_ok());
+
+    let mut future = text();
+    future.timestamp = Utc::now() + validator.max_skew * 2;
+    assert!(matches!(
+      validator.validate_synced(&future.encode()),
+      Err(Invalid::Reject(_))
+    ));
+  }
+}
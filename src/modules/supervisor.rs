@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use bastion::prelude::*;
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+
+use crate::config::{BootNode, SupervisorSettings};
+use crate::constants::PEER_MONITOR;
+use crate::peer::PeerCommand;
+use crate::traits::peer::TBuilder;
+
+/// Lifecycle reports a peer actor sends to the monitor of its supervisor.
+#[derive(Debug, Clone)]
+pub enum PeerLifecycle {
+  Started { name: String },
+  Stopped { name: String },
+  Failed { name: String, error: String },
+}
+
+/// Supervises the peer actors of this process and the monitor collecting their lifecycle reports.
+pub struct PeerSupervisor {
+  supervisor: SupervisorRef,
+}
+
+impl PeerSupervisor {
+  pub fn new(settings: &SupervisorSettings) -> Result<Self> {
+    let restart_policy = match settings.max_restarts() {
+      None => RestartPolicy::Always,
+      Some(0) => RestartPolicy::Never,
+      Some(tries) => RestartPolicy::Tries(tries),
+    };
+    let backoff = match settings.backoff().is_zero() {
+      true => ActorRestartStrategy::Immediate,
+      false => ActorRestartStrategy::ExponentialBackOff {
+        timeout: settings.backoff(),
+        multiplier: settings.backoff_multiplier(),
+      },
+    };
+    let restart_strategy = RestartStrategy::new(restart_policy, backoff);
+
+    let supervisor = Bastion::supervisor(|sp| {
+      sp.with_strategy(SupervisionStrategy::OneForOne)
+        .with_restart_strategy(restart_strategy)
+    })
+    .map_err(|_| anyhow!("failed to create the peer supervisor"))?;
+
+    supervisor
+      .children(|children| {
+        children
+          .with_name(PEER_MONITOR)
+          .with_distributor(Distributor::named(PEER_MONITOR))
+          .with_exec(monitor)
+      })
+      .map_err(|_| anyhow!("failed to start the peer monitor"))?;
+
+    Ok(Self { supervisor })
+  }
+
+  /// Runs the peer built by `builder` as a supervised child named `name`.
+  ///
+  /// Other actors reach it through `Distributor::named(name)` with a `PeerCommand`.
+  pub fn spawn(
+    &self,
+    name: String,
+    builder: Box<dyn TBuilder>,
+    boot_nodes: Vec<BootNode>,
+  ) -> Result<ChildrenRef> {
+    let builder: Arc<dyn TBuilder> = Arc::from(builder);
+    let boot_nodes = Arc::new(boot_nodes);
+    let restarted = name.clone();
+
+    self
+      .supervisor
+      .children(|children| {
+        children
+          .with_name(name.clone())
+          .with_distributor(Distributor::named(&name))
+          .with_callbacks(
+            Callbacks::new().with_after_restart(move || warn!("{restarted} restarted")),
+          )
+          .with_exec(move |ctx: BastionContext| {
+            let name = name.clone();
+            let builder = builder.clone();
+            let boot_nodes = boot_nodes.clone();
+            async move { run_peer(ctx, name, builder, boot_nodes).await }
+          })
+      })
+      .map_err(|_| anyhow!("failed to spawn peer actor"))
+  }
+}
+
+async fn run_peer(
+  ctx: BastionContext,
+  name: String,
+  builder: Arc<dyn TBuilder>,
+  boot_nodes: Arc<Vec<BootNode>>,
+) -> Result<(), ()> {
+  let mut peer = match builder.build().await {
+    Ok(peer) => peer,
+    Err(e) => {
+      report(PeerLifecycle::Failed {
+        name,
+        error: format!("build failed: {e:?}"),
+      });
+      return Err(());
+    }
+  };
+
+  let (tx, rx) = mpsc::unbounded_channel();
+  report(PeerLifecycle::Started { name: name.clone() });
+
+  let result = tokio::select! {
+    result = peer.run(&boot_nodes, rx) => result.map_err(|e| {
+      report(PeerLifecycle::Failed { name: name.clone(), error: format!("{e:?}") });
+    }),
+    result = forward(&ctx, &name, tx) => result,
+  };
+
+  if result.is_ok() {
+    report(PeerLifecycle::Stopped { name });
+  }
+  result
+}
+
+/// Forwards actor messages to the peer until the actor is asked to stop.
+async fn forward(
+  ctx: &BastionContext,
+  name: &str,
+  tx: mpsc::UnboundedSender<PeerCommand>,
+) -> Result<(), ()> {
+  loop {
+    msg! { ctx.recv().await?,
+      command: PeerCommand => {
+        let _ = tx.send(command);
+      };
+      msg: _ => {
+        warn!("{name} ignored unexpected message {msg:?}");
+      };
+    }
+  }
+}
+
+async fn monitor(ctx: BastionContext) -> Result<(), ()> {
+  loop {
+    msg! { ctx.recv().await?,
+      event: PeerLifecycle => {
+        match event {
+          PeerLifecycle::Started { name } => info!("{name} started"),
+          PeerLifecycle::Stopped { name } => info!("{name} stopped"),
+          PeerLifecycle::Failed { name, error } => error!("{name} failed: {error}"),
+        }
+      };
+      msg: _ => {
+        warn!("{PEER_MONITOR} ignored unexpected message {msg:?}");
+      };
+    }
+  }
+}
+
+fn report(event: PeerLifecycle) {
+  if let Err(e) = Distributor::named(PEER_MONITOR).tell_one(event) {
+    warn!("failed to report peer lifecycle: {e:?}");
+  }
+}
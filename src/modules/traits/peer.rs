@@ -1,13 +1,21 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::config::BootNode;
+use crate::peer::PeerCommand;
 
 #[async_trait]
 pub trait TPeer: Send {
-  async fn run(&mut self, boot_nodes: &[&str]) -> Result<()>;
+  async fn run(
+    &mut self,
+    boot_nodes: &[BootNode],
+    commands: UnboundedReceiver<PeerCommand>,
+  ) -> Result<()>;
 }
 
 #[async_trait]
-pub trait TBuilder {
+pub trait TBuilder: Send + Sync {
   fn boxed(self) -> Box<dyn TBuilder>;
   async fn build(&self) -> Result<Box<dyn TPeer>>;
 }